@@ -0,0 +1,954 @@
+//! The information handed from the loader to the kernel.
+//!
+//! Every type here is `#[repr(C)]` (or a `#[repr(u32)]` enum) and only
+//! contains integers, raw pointers and such enums so that the loader and
+//! the kernel agree on the layout across the sysv64 call boundary. Field
+//! offsets are checked at compile time below; changing the layout requires
+//! rebuilding both sides.
+
+use core::mem::offset_of;
+use core::{ptr, slice};
+
+/// How the color channels of a framebuffer pixel are laid out.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// Byte 0 is red, 1 is green, 2 is blue and 3 is reserved.
+    Rgb = 0,
+    /// Byte 0 is blue, 1 is green, 2 is red and 3 is reserved.
+    Bgr = 1,
+    /// The channels are described by [`FrameBufferInfo::masks`].
+    Bitmask = 2,
+    /// There are no pixels the kernel can write directly, as with a
+    /// `BltOnly` GOP mode.
+    Unknown = 3,
+}
+
+impl PixelFormat {
+    /// Bytes each pixel takes in the framebuffer: 4 for every format GOP
+    /// can describe, 0 for [`PixelFormat::Unknown`].
+    pub const fn bytes_per_pixel(self) -> usize {
+        match self {
+            Self::Rgb | Self::Bgr | Self::Bitmask => 4,
+            Self::Unknown => 0,
+        }
+    }
+
+    /// Convert an `EFI_GRAPHICS_PIXEL_FORMAT`. `PixelBltOnly` and values
+    /// past the ones the UEFI spec defines become [`PixelFormat::Unknown`].
+    pub const fn from_uefi(format: u32) -> Self {
+        match format {
+            0 => Self::Rgb,
+            1 => Self::Bgr,
+            2 => Self::Bitmask,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// The bits of a 32-bit pixel that hold each channel, for
+/// [`PixelFormat::Bitmask`].
+///
+/// | offset | field      |
+/// |--------|------------|
+/// | 0      | `red`      |
+/// | 4      | `green`    |
+/// | 8      | `blue`     |
+/// | 12     | `reserved` |
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixelMasks {
+    pub red: u32,
+    pub green: u32,
+    pub blue: u32,
+    pub reserved: u32,
+}
+
+/// Linear framebuffer provided by the firmware.
+///
+/// Without one, as when the firmware has no GOP, `base` is null, every
+/// size is zero and `pixel_format` is [`PixelFormat::Unknown`]; see
+/// [`FrameBufferInfo::empty`].
+///
+/// The first 24 bytes are laid out like MikanOS's `FrameBufferConfig`,
+/// whose `kPixelRGBResv8BitPerColor` and `kPixelBGRResv8BitPerColor` are
+/// [`PixelFormat::Rgb`] and [`PixelFormat::Bgr`], so a kernel written
+/// against that struct can read it from here as is.
+///
+/// | offset | field                   |
+/// |--------|-------------------------|
+/// | 0      | `base`                  |
+/// | 8      | `pixels_per_scan_line`  |
+/// | 12     | `horizontal_resolution` |
+/// | 16     | `vertical_resolution`   |
+/// | 20     | `pixel_format`          |
+/// | 24     | `size`                  |
+/// | 32     | `masks`                 |
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FrameBufferInfo {
+    /// Start of the framebuffer, or null if there is none. It is a
+    /// physical address the kernel may write `size` bytes to once the
+    /// loader has jumped to it; before that the firmware owns it.
+    pub base: *mut u8,
+    /// Pixels per scan line, which may be larger than
+    /// `horizontal_resolution` when the firmware pads lines. Pixel `(x, y)`
+    /// is at index `y * pixels_per_scan_line + x`; indexing by the width
+    /// skews the image.
+    pub pixels_per_scan_line: u32,
+    /// Visible width in pixels.
+    pub horizontal_resolution: u32,
+    /// Visible height in pixels.
+    pub vertical_resolution: u32,
+    /// Layout of each 32-bit pixel.
+    pub pixel_format: PixelFormat,
+    /// Size of the framebuffer in bytes, at least
+    /// [`FrameBufferInfo::visible_len`].
+    pub size: usize,
+    /// Channel masks for [`PixelFormat::Bitmask`]; zero otherwise.
+    pub masks: PixelMasks,
+}
+
+impl FrameBufferInfo {
+    /// Bytes from `base` through the end of the last scan line:
+    /// `pixels_per_scan_line * vertical_resolution` pixels of
+    /// [`PixelFormat::bytes_per_pixel`] bytes each.
+    pub const fn visible_len(&self) -> usize {
+        self.pixels_per_scan_line as usize
+            * self.vertical_resolution as usize
+            * self.pixel_format.bytes_per_pixel()
+    }
+
+    /// The marker for "no framebuffer".
+    pub const fn empty() -> Self {
+        Self {
+            base: ptr::null_mut(),
+            pixels_per_scan_line: 0,
+            horizontal_resolution: 0,
+            vertical_resolution: 0,
+            pixel_format: PixelFormat::Unknown,
+            size: 0,
+            masks: PixelMasks {
+                red: 0,
+                green: 0,
+                blue: 0,
+                reserved: 0,
+            },
+        }
+    }
+}
+
+/// The UEFI memory map as it was when the loader exited boot services.
+///
+/// Descriptors are `descriptor_size` bytes apart, which may be more than
+/// the size of an `EFI_MEMORY_DESCRIPTOR` as the kernel knows it: the UEFI
+/// spec lets firmware append fields. Stepping through the buffer by
+/// anything else reads garbage without any error, so use
+/// [`MemoryMapInfo::descriptor`] or the same stride.
+///
+/// | offset | field              |
+/// |--------|--------------------|
+/// | 0      | `buffer`           |
+/// | 8      | `size`             |
+/// | 16     | `descriptor_size`  |
+/// | 24     | `descriptor_count` |
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryMapInfo {
+    /// Start of the raw `EFI_MEMORY_DESCRIPTOR` array.
+    pub buffer: *const u8,
+    /// Size of the whole map in bytes.
+    pub size: usize,
+    /// Distance between the starts of two consecutive descriptors, in bytes.
+    pub descriptor_size: usize,
+    /// Number of descriptors in the map.
+    pub descriptor_count: usize,
+}
+
+impl MemoryMapInfo {
+    /// Pointer to the start of descriptor `index`, or `None` if the map has
+    /// no such descriptor.
+    pub fn descriptor(&self, index: usize) -> Option<*const u8> {
+        if index >= self.descriptor_count {
+            return None;
+        }
+        Some(self.buffer.wrapping_add(index * self.descriptor_size))
+    }
+}
+
+/// One entry of the memory map in a layout owned by this crate.
+///
+/// | offset | field        |
+/// |--------|--------------|
+/// | 0      | `ty`         |
+/// | 8      | `phys_start` |
+/// | 16     | `page_count` |
+/// | 24     | `attribute`  |
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryDescriptor {
+    /// The UEFI `EFI_MEMORY_TYPE` of the region.
+    pub ty: u32,
+    /// Physical address of the first byte of the region.
+    pub phys_start: u64,
+    /// Size of the region in 4 KiB pages.
+    pub page_count: u64,
+    /// The UEFI `EFI_MEMORY_ATTRIBUTE` bits of the region.
+    pub attribute: u64,
+}
+
+/// The final memory map as an array of [`MemoryDescriptor`]s, so the
+/// kernel can walk it without knowing UEFI's descriptor layout.
+///
+/// | offset | field     |
+/// |--------|-----------|
+/// | 0      | `entries` |
+/// | 8      | `count`   |
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryDescriptors {
+    pub entries: *const MemoryDescriptor,
+    pub count: usize,
+}
+
+impl MemoryDescriptors {
+    /// View the descriptors as a slice.
+    ///
+    /// # Safety
+    ///
+    /// `entries` must point to `count` valid descriptors, as set up by the
+    /// loader.
+    pub unsafe fn as_slice(&self) -> &[MemoryDescriptor] {
+        if self.entries.is_null() {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.entries, self.count) }
+    }
+}
+
+/// An entry of the UEFI configuration table: the GUID of a table the
+/// firmware publishes and its address.
+///
+/// | offset | field     |
+/// |--------|-----------|
+/// | 0      | `guid`    |
+/// | 16     | `address` |
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ConfigTable {
+    /// The GUID in the byte order UEFI stores it in, with the first three
+    /// fields little-endian.
+    pub guid: [u8; 16],
+    pub address: *const u8,
+}
+
+/// A copy of the whole UEFI configuration table, for the tables the loader
+/// doesn't look up itself, such as a device tree.
+///
+/// | offset | field     |
+/// |--------|-----------|
+/// | 0      | `entries` |
+/// | 8      | `count`   |
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ConfigTables {
+    pub entries: *const ConfigTable,
+    pub count: usize,
+}
+
+impl ConfigTables {
+    /// No entries.
+    pub const fn empty() -> Self {
+        Self {
+            entries: ptr::null(),
+            count: 0,
+        }
+    }
+
+    /// View the entries as a slice.
+    ///
+    /// # Safety
+    ///
+    /// `entries` must point to `count` valid entries, as set up by the
+    /// loader.
+    pub unsafe fn as_slice(&self) -> &[ConfigTable] {
+        if self.entries.is_null() {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.entries, self.count) }
+    }
+}
+
+/// A block of memory the loader filled and left allocated for the kernel.
+///
+/// | offset | field  |
+/// |--------|--------|
+/// | 0      | `base` |
+/// | 8      | `size` |
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryRegion {
+    /// Start of the region, or null if there is none.
+    pub base: *const u8,
+    /// Size of the region in bytes.
+    pub size: usize,
+}
+
+impl MemoryRegion {
+    /// A region that doesn't exist.
+    pub const fn empty() -> Self {
+        Self {
+            base: ptr::null(),
+            size: 0,
+        }
+    }
+
+    /// View the region as a byte slice.
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to `size` readable bytes, as set up by the loader.
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        if self.base.is_null() {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.base, self.size) }
+    }
+}
+
+/// Longest kernel command line the loader passes on, in bytes, not
+/// counting the NUL it appends.
+pub const MAX_CMDLINE_LEN: usize = 4095;
+
+/// Most ELF modules the loader places in memory besides the kernel.
+pub const MAX_MODULES: usize = 8;
+
+/// An ELF module the loader placed in memory for the kernel to start.
+///
+/// | offset | field   |
+/// |--------|---------|
+/// | 0      | `base`  |
+/// | 8      | `size`  |
+/// | 16     | `entry` |
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ModuleInfo {
+    /// Lowest address of the loaded image.
+    pub base: u64,
+    /// Bytes from `base` to the end of the highest segment.
+    pub size: u64,
+    /// Entry point, with any relocation applied.
+    pub entry: u64,
+}
+
+impl ModuleInfo {
+    /// An unused slot.
+    pub const fn empty() -> Self {
+        Self {
+            base: 0,
+            size: 0,
+            entry: 0,
+        }
+    }
+}
+
+/// The wall-clock time the loader read from the firmware, in the fields of
+/// an `EFI_TIME`. All zero if the firmware couldn't tell.
+///
+/// | offset | field        |
+/// |--------|--------------|
+/// | 0      | `year`       |
+/// | 2      | `month`      |
+/// | 3      | `day`        |
+/// | 4      | `hour`       |
+/// | 5      | `minute`     |
+/// | 6      | `second`     |
+/// | 8      | `nanosecond` |
+/// | 12     | `time_zone`  |
+/// | 14     | `daylight`   |
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootTime {
+    pub year: u16,
+    /// 1 to 12.
+    pub month: u8,
+    /// 1 to 31.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+    /// Offset from UTC in minutes, or [`BootTime::UNSPECIFIED_TIME_ZONE`]
+    /// if the time is local time.
+    pub time_zone: i16,
+    /// The UEFI daylight saving bits.
+    pub daylight: u8,
+}
+
+impl BootTime {
+    /// `time_zone` value for a time that isn't tied to a time zone.
+    pub const UNSPECIFIED_TIME_ZONE: i16 = 0x07ff;
+
+    /// No time known.
+    pub const fn empty() -> Self {
+        Self {
+            year: 0,
+            month: 0,
+            day: 0,
+            hour: 0,
+            minute: 0,
+            second: 0,
+            nanosecond: 0,
+            time_zone: 0,
+            daylight: 0,
+        }
+    }
+
+    /// Whether the loader got a time from the firmware.
+    pub fn is_valid(&self) -> bool {
+        self.month != 0
+    }
+}
+
+/// A PSF1 or PSF2 bitmap font loaded by the loader.
+///
+/// Glyph `i` is the `bytes_per_glyph` bytes starting at
+/// `base + header_size + i * bytes_per_glyph`. Each row of a glyph takes
+/// `glyph_width.div_ceil(8)` bytes, most significant bit first.
+///
+/// | offset | field             |
+/// |--------|-------------------|
+/// | 0      | `base`            |
+/// | 8      | `size`            |
+/// | 16     | `header_size`     |
+/// | 20     | `glyph_count`     |
+/// | 24     | `glyph_width`     |
+/// | 28     | `glyph_height`    |
+/// | 32     | `bytes_per_glyph` |
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct FontInfo {
+    /// Start of the whole font file, or null if no font was loaded.
+    pub base: *const u8,
+    /// Size of the font file in bytes.
+    pub size: usize,
+    /// Offset of the first glyph from `base`.
+    pub header_size: u32,
+    pub glyph_count: u32,
+    /// Glyph width in pixels.
+    pub glyph_width: u32,
+    /// Glyph height in pixels.
+    pub glyph_height: u32,
+    pub bytes_per_glyph: u32,
+}
+
+impl FontInfo {
+    /// No font.
+    pub const fn empty() -> Self {
+        Self {
+            base: ptr::null(),
+            size: 0,
+            header_size: 0,
+            glyph_count: 0,
+            glyph_width: 0,
+            glyph_height: 0,
+            bytes_per_glyph: 0,
+        }
+    }
+
+    /// The glyph to draw for `byte`: its own if the font has one, else
+    /// the one for `replacement`, or glyph 0 in a font too small to have
+    /// that either.
+    ///
+    /// Meaningless if `glyph_count` is zero.
+    pub fn glyph_index(&self, byte: u8, replacement: u8) -> u32 {
+        [byte, replacement]
+            .into_iter()
+            .map(u32::from)
+            .find(|&index| index < self.glyph_count)
+            .unwrap_or(0)
+    }
+}
+
+/// The loader's log, kept in a ring buffer for the kernel to show or save.
+///
+/// The log is plain UTF-8 lines, each ending in `\n`, the same ones the
+/// loader writes to COM1. Once more than `capacity` bytes were written, the
+/// oldest are overwritten, so the first line left may be cut off, possibly
+/// in the middle of a character. [`BootLog::contents`] returns what is left
+/// in order.
+///
+/// | offset | field      |
+/// |--------|------------|
+/// | 0      | `base`     |
+/// | 8      | `capacity` |
+/// | 16     | `written`  |
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BootLog {
+    /// Start of the buffer, or null if the loader has no log to pass on.
+    pub base: *mut u8,
+    /// Size of the buffer in bytes.
+    pub capacity: usize,
+    /// Bytes written since the loader started, including the ones that
+    /// have been overwritten. The next byte goes to `written % capacity`.
+    pub written: u64,
+}
+
+impl BootLog {
+    /// No log.
+    pub const fn empty() -> Self {
+        Self {
+            base: ptr::null_mut(),
+            capacity: 0,
+            written: 0,
+        }
+    }
+
+    /// Append `bytes`, overwriting the oldest ones once the buffer is full.
+    /// Does nothing if there is no buffer.
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to `capacity` writable bytes.
+    pub unsafe fn append(&mut self, bytes: &[u8]) {
+        if self.base.is_null() || self.capacity == 0 {
+            return;
+        }
+        let buffer = unsafe { slice::from_raw_parts_mut(self.base, self.capacity) };
+        // Only the last `capacity` bytes would survive anyway.
+        let skipped = bytes.len().saturating_sub(self.capacity);
+        self.written += skipped as u64;
+        let mut rest = &bytes[skipped..];
+        while !rest.is_empty() {
+            let start = (self.written % self.capacity as u64) as usize;
+            let len = usize::min(rest.len(), self.capacity - start);
+            buffer[start..start + len].copy_from_slice(&rest[..len]);
+            self.written += len as u64;
+            rest = &rest[len..];
+        }
+    }
+
+    /// What is left of the log, oldest first, as the two parts before and
+    /// after the point where the buffer wraps around. The second part is
+    /// empty until it does.
+    ///
+    /// # Safety
+    ///
+    /// `base` must point to `capacity` readable bytes, as set up by the
+    /// loader.
+    pub unsafe fn contents(&self) -> (&[u8], &[u8]) {
+        if self.base.is_null() || self.capacity == 0 {
+            return (&[], &[]);
+        }
+        let buffer = unsafe { slice::from_raw_parts(self.base, self.capacity) };
+        if self.written <= self.capacity as u64 {
+            return (&buffer[..self.written as usize], &[]);
+        }
+        let (newer, older) = buffer.split_at((self.written % self.capacity as u64) as usize);
+        (older, newer)
+    }
+}
+
+/// A GNU build ID, the descriptor of the kernel's `NT_GNU_BUILD_ID` note.
+///
+/// | offset | field   |
+/// |--------|---------|
+/// | 0      | `len`   |
+/// | 4      | `bytes` |
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BuildId {
+    /// Bytes of `bytes` that hold the ID, 0 if the kernel has none.
+    pub len: u32,
+    /// The ID, cut off after [`BuildId::MAX_LEN`] bytes.
+    pub bytes: [u8; BuildId::MAX_LEN],
+}
+
+impl BuildId {
+    /// The longest ID that is kept whole. SHA-1 IDs, the linkers' default,
+    /// take 20 bytes.
+    pub const MAX_LEN: usize = 32;
+
+    /// No build ID.
+    pub const fn empty() -> Self {
+        Self {
+            len: 0,
+            bytes: [0; Self::MAX_LEN],
+        }
+    }
+
+    /// The first [`BuildId::MAX_LEN`] bytes of `id`.
+    pub fn new(id: &[u8]) -> Self {
+        let mut build_id = Self::empty();
+        let len = usize::min(id.len(), Self::MAX_LEN);
+        build_id.bytes[..len].copy_from_slice(&id[..len]);
+        build_id.len = len as u32;
+        build_id
+    }
+
+    /// The ID, empty if there is none.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..usize::min(self.len as usize, Self::MAX_LEN)]
+    }
+}
+
+/// The version of the loader that started the kernel, from its Cargo
+/// manifest.
+///
+/// | offset | field   |
+/// |--------|---------|
+/// | 0      | `major` |
+/// | 2      | `minor` |
+/// | 4      | `patch` |
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoaderVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl LoaderVersion {
+    /// No version known, as in a `BootInfo` that no loader filled in.
+    pub const fn empty() -> Self {
+        Self {
+            major: 0,
+            minor: 0,
+            patch: 0,
+        }
+    }
+}
+
+/// Boot information passed to the kernel entry point.
+///
+/// | offset | field                |
+/// |--------|----------------------|
+/// | 0      | `framebuffer`        |
+/// | 48     | `memory_map`         |
+/// | 80     | `rsdp`               |
+/// | 88     | `initrd`             |
+/// | 104    | `memory_descriptors` |
+/// | 120    | `phys_mem_offset`    |
+/// | 128    | `font`               |
+/// | 168    | `runtime_services`   |
+/// | 176    | `smbios`             |
+/// | 184    | `modules`            |
+/// | 376    | `module_count`       |
+/// | 384    | `boot_time`          |
+/// | 400    | `app`                |
+/// | 424    | `cmdline`            |
+/// | 440    | `tsc_frequency`      |
+/// | 448    | `rsdp_revision`      |
+/// | 456    | `back_buffer`        |
+/// | 472    | `archive`            |
+/// | 488    | `usable_ram_bytes`   |
+/// | 496    | `kernel_stack`       |
+/// | 512    | `largest_free`       |
+/// | 528    | `boot_log`           |
+/// | 552    | `cpu_count`          |
+/// | 556    | `build_id`           |
+/// | 592    | `loader_version`     |
+/// | 600    | `config_tables`      |
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BootInfo {
+    pub framebuffer: FrameBufferInfo,
+    pub memory_map: MemoryMapInfo,
+    /// The ACPI RSDP (2.0 if available, otherwise 1.0), or null if the
+    /// firmware has none. [`BootInfo::rsdp_revision`] tells which.
+    pub rsdp: *const u8,
+    /// The initial ramdisk, or an empty region if none was loaded.
+    pub initrd: MemoryRegion,
+    /// The final memory map, converted to [`MemoryDescriptor`]s. With the
+    /// loader's `merge_memmap` set, adjacent descriptors of the same type
+    /// and attributes are merged; `memory_map` is always left as is.
+    pub memory_descriptors: MemoryDescriptors,
+    /// Virtual address at which physical address 0 is mapped. Physical
+    /// address `p` is accessible at `phys_mem_offset + p`.
+    pub phys_mem_offset: u64,
+    /// The console font, or [`FontInfo::empty`] if none was found.
+    pub font: FontInfo,
+    /// The UEFI `EFI_RUNTIME_SERVICES` table, or null if the firmware has
+    /// none.
+    ///
+    /// The loader keeps every runtime services region identity mapped, so
+    /// the table and the functions it points to are callable as long as the
+    /// kernel runs on the loader's page tables. A kernel that remaps them
+    /// must call `SetVirtualAddressMap` itself first.
+    pub runtime_services: *const u8,
+    /// The SMBIOS entry point (3.0 if available, otherwise 2.x), or null if
+    /// the firmware has none.
+    pub smbios: *const u8,
+    /// The modules from the loader config, in order. Only the first
+    /// `module_count` entries are used.
+    pub modules: [ModuleInfo; MAX_MODULES],
+    pub module_count: usize,
+    /// The time when the loader ran, or [`BootTime::empty`] if the firmware
+    /// doesn't support reading it.
+    pub boot_time: BootTime,
+    /// The init app, loaded apart from the kernel and the modules, or
+    /// [`ModuleInfo::empty`] if the boot volume has none.
+    pub app: ModuleInfo,
+    /// The `cmdline=` string from the loader config as UTF-8, or an empty
+    /// region if there is none. At most [`MAX_CMDLINE_LEN`] bytes, followed
+    /// by a NUL that `size` doesn't count.
+    pub cmdline: MemoryRegion,
+    /// TSC ticks per second as the loader measured them against the UEFI
+    /// timer, or 0 if it didn't. Only trustworthy on CPUs with an invariant
+    /// TSC.
+    pub tsc_frequency: u64,
+    /// The `Revision` field of [`BootInfo::rsdp`]: 0 for ACPI 1.0, whose
+    /// RSDP only has the 32-bit RSDT address, and 2 or more for ACPI 2.0+,
+    /// which adds the XSDT address. 0 if there is no RSDP.
+    pub rsdp_revision: u8,
+    /// Zeroed memory as large as the framebuffer for drawing off-screen, or
+    /// an empty region unless the loader's `double_buffer` is set. The
+    /// loader only allocates it; copying it to the screen is up to the
+    /// kernel. Pixels are laid out like the framebuffer's.
+    pub back_buffer: MemoryRegion,
+    /// The `\boot.cpio` archive the kernel was loaded from, decompressed
+    /// if it was gzipped, or an empty region if the kernel came from its
+    /// own file. The kernel can find the other members in it.
+    pub archive: MemoryRegion,
+    /// Bytes of RAM free for the kernel in the final memory map: conventional
+    /// memory plus the boot services code and data the firmware left
+    /// behind. What the loader allocated for the kernel is not included.
+    pub usable_ram_bytes: u64,
+    /// The stack the kernel starts on, `kernel_stack_kib` from the loader
+    /// config rounded up to pages. `rsp` starts at or just below its end.
+    pub kernel_stack: MemoryRegion,
+    /// The largest run of contiguous conventional memory in the final
+    /// memory map, for bootstrapping a heap, or an empty region if there is
+    /// none. `base` is a physical address.
+    pub largest_free: MemoryRegion,
+    /// Everything the loader logged up to the jump to the kernel, or
+    /// [`BootLog::empty`] if it couldn't allocate the buffer.
+    pub boot_log: BootLog,
+    /// Enabled processors in the ACPI MADT, counting local APIC and x2APIC
+    /// entries, including the one the kernel starts on. 1 if the firmware
+    /// has no ACPI tables or no usable MADT.
+    pub cpu_count: u32,
+    /// The kernel's GNU build ID, for matching it to its debug symbols.
+    pub build_id: BuildId,
+    /// The version of the loader, for kernels that depend on fields only
+    /// newer loaders fill in.
+    pub loader_version: LoaderVersion,
+    /// The UEFI configuration table as it was before the loader exited
+    /// boot services, or [`ConfigTables::empty`] if it couldn't be copied.
+    /// The tables it points to are where the firmware left them.
+    pub config_tables: ConfigTables,
+}
+
+impl BootInfo {
+    /// A `BootInfo` with every pointer null, every size zero and a
+    /// `cpu_count` of 1.
+    pub const fn empty() -> Self {
+        Self {
+            framebuffer: FrameBufferInfo::empty(),
+            memory_map: MemoryMapInfo {
+                buffer: ptr::null(),
+                size: 0,
+                descriptor_size: 0,
+                descriptor_count: 0,
+            },
+            rsdp: ptr::null(),
+            initrd: MemoryRegion::empty(),
+            memory_descriptors: MemoryDescriptors {
+                entries: ptr::null(),
+                count: 0,
+            },
+            phys_mem_offset: 0,
+            font: FontInfo::empty(),
+            runtime_services: ptr::null(),
+            smbios: ptr::null(),
+            modules: [ModuleInfo::empty(); MAX_MODULES],
+            module_count: 0,
+            boot_time: BootTime::empty(),
+            app: ModuleInfo::empty(),
+            cmdline: MemoryRegion::empty(),
+            tsc_frequency: 0,
+            rsdp_revision: 0,
+            back_buffer: MemoryRegion::empty(),
+            archive: MemoryRegion::empty(),
+            usable_ram_bytes: 0,
+            kernel_stack: MemoryRegion::empty(),
+            largest_free: MemoryRegion::empty(),
+            boot_log: BootLog::empty(),
+            cpu_count: 1,
+            build_id: BuildId::empty(),
+            loader_version: LoaderVersion::empty(),
+            config_tables: ConfigTables::empty(),
+        }
+    }
+}
+
+const _: () = {
+    assert!(offset_of!(FrameBufferInfo, base) == 0);
+    assert!(offset_of!(FrameBufferInfo, pixels_per_scan_line) == 8);
+    assert!(offset_of!(FrameBufferInfo, horizontal_resolution) == 12);
+    assert!(offset_of!(FrameBufferInfo, vertical_resolution) == 16);
+    assert!(offset_of!(FrameBufferInfo, pixel_format) == 20);
+    assert!(offset_of!(FrameBufferInfo, size) == 24);
+    assert!(offset_of!(FrameBufferInfo, masks) == 32);
+    assert!(offset_of!(PixelMasks, red) == 0);
+    assert!(offset_of!(PixelMasks, green) == 4);
+    assert!(offset_of!(PixelMasks, blue) == 8);
+    assert!(offset_of!(PixelMasks, reserved) == 12);
+    assert!(offset_of!(MemoryMapInfo, buffer) == 0);
+    assert!(offset_of!(MemoryMapInfo, size) == 8);
+    assert!(offset_of!(MemoryMapInfo, descriptor_size) == 16);
+    assert!(offset_of!(MemoryMapInfo, descriptor_count) == 24);
+    assert!(offset_of!(MemoryDescriptor, ty) == 0);
+    assert!(offset_of!(MemoryDescriptor, phys_start) == 8);
+    assert!(offset_of!(MemoryDescriptor, page_count) == 16);
+    assert!(offset_of!(MemoryDescriptor, attribute) == 24);
+    assert!(offset_of!(MemoryDescriptors, entries) == 0);
+    assert!(offset_of!(MemoryDescriptors, count) == 8);
+    assert!(offset_of!(MemoryRegion, base) == 0);
+    assert!(offset_of!(MemoryRegion, size) == 8);
+    assert!(offset_of!(BootInfo, framebuffer) == 0);
+    assert!(offset_of!(BootInfo, memory_map) == 48);
+    assert!(offset_of!(BootInfo, rsdp) == 80);
+    assert!(offset_of!(BootInfo, initrd) == 88);
+    assert!(offset_of!(BootInfo, memory_descriptors) == 104);
+    assert!(offset_of!(FontInfo, base) == 0);
+    assert!(offset_of!(FontInfo, size) == 8);
+    assert!(offset_of!(FontInfo, header_size) == 16);
+    assert!(offset_of!(FontInfo, glyph_count) == 20);
+    assert!(offset_of!(FontInfo, glyph_width) == 24);
+    assert!(offset_of!(FontInfo, glyph_height) == 28);
+    assert!(offset_of!(FontInfo, bytes_per_glyph) == 32);
+    assert!(offset_of!(BootInfo, phys_mem_offset) == 120);
+    assert!(offset_of!(BootInfo, font) == 128);
+    assert!(offset_of!(BootInfo, runtime_services) == 168);
+    assert!(offset_of!(BootInfo, smbios) == 176);
+    assert!(offset_of!(ModuleInfo, base) == 0);
+    assert!(offset_of!(ModuleInfo, size) == 8);
+    assert!(offset_of!(ModuleInfo, entry) == 16);
+    assert!(offset_of!(BootInfo, modules) == 184);
+    assert!(offset_of!(BootInfo, module_count) == 376);
+    assert!(offset_of!(BootTime, year) == 0);
+    assert!(offset_of!(BootTime, month) == 2);
+    assert!(offset_of!(BootTime, day) == 3);
+    assert!(offset_of!(BootTime, hour) == 4);
+    assert!(offset_of!(BootTime, minute) == 5);
+    assert!(offset_of!(BootTime, second) == 6);
+    assert!(offset_of!(BootTime, nanosecond) == 8);
+    assert!(offset_of!(BootTime, time_zone) == 12);
+    assert!(offset_of!(BootTime, daylight) == 14);
+    assert!(offset_of!(BootInfo, boot_time) == 384);
+    assert!(offset_of!(BootInfo, app) == 400);
+    assert!(offset_of!(BootInfo, cmdline) == 424);
+    assert!(offset_of!(BootInfo, tsc_frequency) == 440);
+    assert!(offset_of!(BootInfo, rsdp_revision) == 448);
+    assert!(offset_of!(BootInfo, back_buffer) == 456);
+    assert!(offset_of!(BootInfo, archive) == 472);
+    assert!(offset_of!(BootInfo, usable_ram_bytes) == 488);
+    assert!(offset_of!(BootInfo, kernel_stack) == 496);
+    assert!(offset_of!(BootInfo, largest_free) == 512);
+    assert!(offset_of!(BootLog, base) == 0);
+    assert!(offset_of!(BootLog, capacity) == 8);
+    assert!(offset_of!(BootLog, written) == 16);
+    assert!(offset_of!(BootInfo, boot_log) == 528);
+    assert!(offset_of!(BootInfo, cpu_count) == 552);
+    assert!(offset_of!(BuildId, bytes) == 4);
+    assert!(offset_of!(BootInfo, build_id) == 556);
+    assert!(offset_of!(LoaderVersion, major) == 0);
+    assert!(offset_of!(LoaderVersion, minor) == 2);
+    assert!(offset_of!(LoaderVersion, patch) == 4);
+    assert!(offset_of!(BootInfo, loader_version) == 592);
+    assert!(offset_of!(ConfigTable, guid) == 0);
+    assert!(offset_of!(ConfigTable, address) == 16);
+    assert!(offset_of!(ConfigTables, entries) == 0);
+    assert!(offset_of!(ConfigTables, count) == 8);
+    assert!(offset_of!(BootInfo, config_tables) == 600);
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_id_keeps_the_first_bytes() {
+        assert_eq!(BuildId::new(&[1, 2, 3]).as_bytes(), [1, 2, 3]);
+        let long = [0xab; BuildId::MAX_LEN + 4];
+        let id = BuildId::new(&long);
+        assert_eq!(id.len as usize, BuildId::MAX_LEN);
+        assert_eq!(id.as_bytes(), &long[..BuildId::MAX_LEN]);
+        assert!(BuildId::empty().as_bytes().is_empty());
+    }
+
+    #[test]
+    fn converts_uefi_pixel_formats() {
+        // PixelRedGreenBlueReserved8BitPerColor, PixelBlueGreenRedReserved8BitPerColor,
+        // PixelBitMask, PixelBltOnly and PixelFormatMax.
+        assert_eq!(PixelFormat::from_uefi(0), PixelFormat::Rgb);
+        assert_eq!(PixelFormat::from_uefi(1), PixelFormat::Bgr);
+        assert_eq!(PixelFormat::from_uefi(2), PixelFormat::Bitmask);
+        assert_eq!(PixelFormat::from_uefi(3), PixelFormat::Unknown);
+        assert_eq!(PixelFormat::from_uefi(4), PixelFormat::Unknown);
+    }
+
+    #[test]
+    fn small_font_falls_back_to_glyph_zero() {
+        let font = |glyph_count| FontInfo {
+            glyph_count,
+            ..FontInfo::empty()
+        };
+        assert_eq!(font(256).glyph_index(b'a', b'?'), b'a' as u32);
+        assert_eq!(font(128).glyph_index(0xe9, b'?'), b'?' as u32);
+        // A 16-glyph font has no `?` to fall back to.
+        assert_eq!(font(16).glyph_index(5, b'?'), 5);
+        assert_eq!(font(16).glyph_index(b'a', b'?'), 0);
+    }
+
+    #[test]
+    fn visible_len_counts_padding() {
+        let info = FrameBufferInfo {
+            horizontal_resolution: 800,
+            vertical_resolution: 600,
+            pixels_per_scan_line: 832,
+            pixel_format: PixelFormat::Bgr,
+            ..FrameBufferInfo::empty()
+        };
+        assert_eq!(info.visible_len(), 832 * 600 * 4);
+        assert_eq!(FrameBufferInfo::empty().visible_len(), 0);
+    }
+
+    #[test]
+    fn boot_log_wraps_around() {
+        let mut buffer = [0u8; 8];
+        let mut log = BootLog {
+            base: buffer.as_mut_ptr(),
+            capacity: buffer.len(),
+            written: 0,
+        };
+        unsafe {
+            log.append(b"abc\n");
+            assert_eq!(log.contents(), (&b"abc\n"[..], &b""[..]));
+            log.append(b"defg\n");
+            assert_eq!(log.written, 9);
+            assert_eq!(log.contents(), (&b"bc\ndefg"[..], &b"\n"[..]));
+            log.append(b"0123456789\n");
+            assert_eq!(log.written, 20);
+            assert_eq!(log.contents(), (&b"3456"[..], &b"789\n"[..]));
+        }
+        let empty = BootLog::empty();
+        assert_eq!(unsafe { empty.contents() }, (&b""[..], &b""[..]));
+    }
+
+    #[test]
+    fn descriptors_use_the_map_stride() {
+        let buffer = [0u8; 3 * 48];
+        let map = MemoryMapInfo {
+            buffer: buffer.as_ptr(),
+            size: buffer.len(),
+            descriptor_size: 48,
+            descriptor_count: 3,
+        };
+        assert_eq!(map.descriptor(0), Some(buffer.as_ptr()));
+        assert_eq!(map.descriptor(2), Some(buffer[96..].as_ptr()));
+        assert_eq!(map.descriptor(3), None);
+    }
+}
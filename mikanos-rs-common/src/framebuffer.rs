@@ -0,0 +1,197 @@
+//! Pixel math for the framebuffer a [`FrameBufferInfo`] describes, shared by
+//! the loader and the kernel so that both index it the same way.
+
+use core::ptr;
+
+use crate::boot_info::{FrameBufferInfo, PixelFormat, PixelMasks};
+
+/// The fields of a GOP mode's `EFI_GRAPHICS_OUTPUT_MODE_INFORMATION` that
+/// describe its framebuffer, named as in the UEFI spec.
+#[derive(Debug, Clone, Copy)]
+pub struct ModeInfo {
+    pub horizontal_resolution: u32,
+    pub vertical_resolution: u32,
+    /// An `EFI_GRAPHICS_PIXEL_FORMAT`.
+    pub pixel_format: u32,
+    /// The channel masks, only meaningful for `PixelBitMask`.
+    pub pixel_information: PixelMasks,
+    pub pixels_per_scan_line: u32,
+}
+
+/// The framebuffer of `size` bytes at `base` in the mode `mode`, or `None`
+/// if the mode has no pixels the kernel can write, like `PixelBltOnly`.
+pub fn framebuffer_info(base: *mut u8, size: usize, mode: &ModeInfo) -> Option<FrameBufferInfo> {
+    let pixel_format = PixelFormat::from_uefi(mode.pixel_format);
+    let masks = match pixel_format {
+        PixelFormat::Bitmask => mode.pixel_information,
+        PixelFormat::Unknown => return None,
+        _ => FrameBufferInfo::empty().masks,
+    };
+    Some(FrameBufferInfo {
+        base,
+        pixels_per_scan_line: mode.pixels_per_scan_line,
+        horizontal_resolution: mode.horizontal_resolution,
+        vertical_resolution: mode.vertical_resolution,
+        pixel_format,
+        size,
+        masks,
+    })
+}
+
+/// Place an 8-bit channel value into the bits selected by `mask`.
+///
+/// Masks narrower than 8 bits keep the channel's high bits.
+fn encode_channel(value: u8, mask: u32) -> u32 {
+    if mask == 0 {
+        return 0;
+    }
+    let shift = mask.trailing_zeros();
+    let width = (mask >> shift).trailing_ones();
+    let value = if width < 8 {
+        value as u32 >> (8 - width)
+    } else {
+        value as u32
+    };
+    (value << shift) & mask
+}
+
+/// The bytes of the `0xRRGGBB` `color` in `fb`'s pixel format, in memory
+/// order, or `None` for [`PixelFormat::Unknown`].
+pub fn encode_pixel(fb: &FrameBufferInfo, color: u32) -> Option<[u8; 4]> {
+    let [_, r, g, b] = color.to_be_bytes();
+    match fb.pixel_format {
+        PixelFormat::Rgb => Some([r, g, b, 0]),
+        PixelFormat::Bgr => Some([b, g, r, 0]),
+        PixelFormat::Bitmask => {
+            let masks = fb.masks;
+            let pixel = encode_channel(r, masks.red)
+                | encode_channel(g, masks.green)
+                | encode_channel(b, masks.blue);
+            Some(pixel.to_le_bytes())
+        }
+        PixelFormat::Unknown => None,
+    }
+}
+
+/// Offset in bytes of pixel (`x`, `y`) from `fb.base`, going by
+/// `pixels_per_scan_line` rather than the width. `None` outside the visible
+/// area.
+pub fn pixel_offset(fb: &FrameBufferInfo, x: u32, y: u32) -> Option<usize> {
+    if x >= fb.horizontal_resolution || y >= fb.vertical_resolution {
+        return None;
+    }
+    let index = fb.pixels_per_scan_line as usize * y as usize + x as usize;
+    Some(index * fb.pixel_format.bytes_per_pixel())
+}
+
+/// Write the `0xRRGGBB` `color` at (`x`, `y`). Coordinates outside the
+/// visible area and unknown pixel formats are ignored.
+///
+/// # Safety
+///
+/// `fb.base` must point to [`FrameBufferInfo::visible_len`] writable bytes.
+pub unsafe fn put_pixel(fb: &FrameBufferInfo, x: u32, y: u32, color: u32) {
+    let (Some(offset), Some(bytes)) = (pixel_offset(fb, x, y), encode_pixel(fb, color)) else {
+        return;
+    };
+    for (i, byte) in bytes.into_iter().enumerate() {
+        unsafe { ptr::write_volatile(fb.base.add(offset + i), byte) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 3x2 framebuffer over `buffer` whose scan lines are padded to 4
+    /// pixels.
+    fn padded(buffer: &mut [u8; 32], pixel_format: PixelFormat) -> FrameBufferInfo {
+        FrameBufferInfo {
+            base: buffer.as_mut_ptr(),
+            size: buffer.len(),
+            horizontal_resolution: 3,
+            vertical_resolution: 2,
+            pixels_per_scan_line: 4,
+            pixel_format,
+            ..FrameBufferInfo::empty()
+        }
+    }
+
+    #[test]
+    fn populates_fields_from_mode_info() {
+        let masks = PixelMasks {
+            red: 0xf800,
+            green: 0x07e0,
+            blue: 0x001f,
+            reserved: 0,
+        };
+        let mut mode = ModeInfo {
+            horizontal_resolution: 1024,
+            vertical_resolution: 768,
+            // PixelBlueGreenRedReserved8BitPerColor
+            pixel_format: 1,
+            pixel_information: masks,
+            pixels_per_scan_line: 1056,
+        };
+        let base = 0x8000_0000 as *mut u8;
+        let fb = framebuffer_info(base, 1056 * 768 * 4, &mode).unwrap();
+        assert_eq!(fb.base, base);
+        assert_eq!(fb.size, 1056 * 768 * 4);
+        assert_eq!(fb.horizontal_resolution, 1024);
+        assert_eq!(fb.vertical_resolution, 768);
+        assert_eq!(fb.pixels_per_scan_line, 1056);
+        assert_eq!(fb.pixel_format, PixelFormat::Bgr);
+        assert_eq!(fb.masks.red, 0);
+
+        mode.pixel_format = 2;
+        let fb = framebuffer_info(base, 0, &mode).unwrap();
+        assert_eq!(fb.pixel_format, PixelFormat::Bitmask);
+        assert_eq!(fb.masks.green, 0x07e0);
+
+        // PixelBltOnly
+        mode.pixel_format = 3;
+        assert!(framebuffer_info(base, 0, &mode).is_none());
+    }
+
+    #[test]
+    fn offsets_follow_the_stride() {
+        let mut buffer = [0; 32];
+        let fb = padded(&mut buffer, PixelFormat::Bgr);
+        assert_eq!(pixel_offset(&fb, 0, 0), Some(0));
+        assert_eq!(pixel_offset(&fb, 2, 0), Some(8));
+        assert_eq!(pixel_offset(&fb, 0, 1), Some(16));
+        assert_eq!(pixel_offset(&fb, 2, 1), Some(24));
+        assert_eq!(pixel_offset(&fb, 3, 0), None);
+        assert_eq!(pixel_offset(&fb, 0, 2), None);
+    }
+
+    #[test]
+    fn puts_pixels_inside_the_visible_area() {
+        let mut buffer = [0; 32];
+        let fb = padded(&mut buffer, PixelFormat::Bgr);
+        unsafe {
+            put_pixel(&fb, 2, 1, 0x112233);
+            put_pixel(&fb, 3, 1, 0xffffff);
+            put_pixel(&fb, 0, 2, 0xffffff);
+        }
+        let mut expected = [0; 32];
+        expected[24..28].copy_from_slice(&[0x33, 0x22, 0x11, 0]);
+        assert_eq!(buffer, expected);
+    }
+
+    #[test]
+    fn encodes_bitmask_pixels() {
+        let fb = FrameBufferInfo {
+            pixel_format: PixelFormat::Bitmask,
+            masks: PixelMasks {
+                red: 0xf800,
+                green: 0x07e0,
+                blue: 0x001f,
+                reserved: 0,
+            },
+            ..FrameBufferInfo::empty()
+        };
+        assert_eq!(encode_pixel(&fb, 0xff8000), Some(0xfc00u32.to_le_bytes()));
+        assert_eq!(encode_pixel(&FrameBufferInfo::empty(), 0xffffff), None);
+    }
+}
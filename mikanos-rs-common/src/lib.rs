@@ -0,0 +1,9 @@
+//! Definitions shared between mikanos-rs-loader and mikanos-rs-kernel.
+
+#![no_std]
+
+pub mod boot_info;
+pub mod framebuffer;
+pub mod memory_attribute;
+pub mod memory_type;
+pub mod serial;
@@ -0,0 +1,77 @@
+//! Names for the `EFI_MEMORY_*` attribute bits of a memory descriptor.
+
+use core::fmt;
+
+/// Uncacheable.
+pub const UC: u64 = 0x1;
+/// Write-combining.
+pub const WC: u64 = 0x2;
+/// Write-through.
+pub const WT: u64 = 0x4;
+/// Write-back.
+pub const WB: u64 = 0x8;
+/// Read-protected.
+pub const RP: u64 = 0x2000;
+/// Execute-protected.
+pub const XP: u64 = 0x4000;
+/// Read-only.
+pub const RO: u64 = 0x20000;
+/// Needs a virtual mapping for runtime services.
+pub const RUNTIME: u64 = 0x8000_0000_0000_0000;
+
+const NAMES: [(u64, &str); 8] = [
+    (UC, "UC"),
+    (WC, "WC"),
+    (WT, "WT"),
+    (WB, "WB"),
+    (RP, "RP"),
+    (XP, "XP"),
+    (RO, "RO"),
+    (RUNTIME, "RUNTIME"),
+];
+
+/// Displays the named bits of an attribute value joined by `|`, e.g.
+/// `WB|XP|RUNTIME`. Bits without a name are left out.
+#[derive(Debug, Clone, Copy)]
+pub struct AttributeNames(pub u64);
+
+impl fmt::Display for AttributeNames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (bit, name) in NAMES {
+            if self.0 & bit == 0 {
+                continue;
+            }
+            if !first {
+                f.write_str("|")?;
+            }
+            f.write_str(name)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn names_set_bits_in_order() {
+        assert_eq!(AttributeNames(XP | WB | UC).to_string(), "UC|WB|XP");
+    }
+
+    #[test]
+    fn includes_runtime() {
+        assert_eq!(AttributeNames(RUNTIME | WB).to_string(), "WB|RUNTIME");
+    }
+
+    #[test]
+    fn ignores_unnamed_bits() {
+        assert_eq!(AttributeNames(0x10 | 0x1000).to_string(), "");
+    }
+}
@@ -0,0 +1,30 @@
+//! Memory types the loader gives its allocations for the kernel, so that
+//! the kernel can find them in the memory map.
+//!
+//! They are in the range `0x8000_0000..=0xffff_ffff` that the UEFI spec
+//! leaves to the OS loader. Everything else the loader hands over, such as
+//! modules, the initrd and the memory map itself, is `EfiLoaderData`, apart
+//! from the executable pages of modules, which are `EfiLoaderCode`.
+
+/// The pages of the kernel's ELF image that aren't [`KERNEL_CODE`].
+pub const KERNEL_IMAGE: u32 = 0x8000_0000;
+/// The stack the kernel starts on.
+pub const KERNEL_STACK: u32 = 0x8000_0001;
+/// The page holding `BootInfo`.
+pub const BOOT_INFO: u32 = 0x8000_0002;
+/// The buffer behind `BootInfo::boot_log`.
+pub const BOOT_LOG: u32 = 0x8000_0003;
+/// The pages of the kernel's ELF image that hold executable segments.
+pub const KERNEL_CODE: u32 = 0x8000_0004;
+
+/// The name of `ty` if it is one of the types above.
+pub fn name(ty: u32) -> Option<&'static str> {
+    match ty {
+        KERNEL_IMAGE => Some("KERNEL_IMAGE"),
+        KERNEL_STACK => Some("KERNEL_STACK"),
+        BOOT_INFO => Some("BOOT_INFO"),
+        BOOT_LOG => Some("BOOT_LOG"),
+        KERNEL_CODE => Some("KERNEL_CODE"),
+        _ => None,
+    }
+}
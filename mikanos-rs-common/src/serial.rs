@@ -0,0 +1,154 @@
+//! A minimal COM1 driver for early output from the loader and the kernel.
+//!
+//! The port has to be set up with [`init`] once; output before that is
+//! dropped rather than sent to an unprogrammed UART. [`serial_print!`] and
+//! [`serial_println!`] write to it directly, without going through the
+//! `log` crate.
+//!
+//! [`serial_print!`]: crate::serial_print
+//! [`serial_println!`]: crate::serial_println
+
+use core::arch::asm;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// I/O port base of the first PC serial port.
+pub const COM1: u16 = 0x3f8;
+
+// Register offsets from the port base.
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+/// Divisor latch access bit in the line control register.
+const LCR_DLAB: u8 = 0x80;
+/// 8 data bits, no parity, one stop bit.
+const LCR_8N1: u8 = 0x03;
+/// Transmit holding register empty bit in the line status register.
+const LSR_THRE: u8 = 0x20;
+/// DTR, RTS and OUT2.
+const MCR_NORMAL: u8 = 0x0b;
+/// RTS, OUT1, OUT2 and loopback, for the self-test.
+const MCR_LOOPBACK: u8 = 0x1e;
+/// Byte sent through the loopback during the self-test.
+const TEST_BYTE: u8 = 0xae;
+
+/// Whether [`init`] has programmed COM1.
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// A 16550-compatible UART driven through port I/O.
+///
+/// It doesn't depend on boot services, so it keeps working after
+/// exit_boot_services.
+#[derive(Debug, Clone, Copy)]
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    /// COM1 at [`COM1`].
+    pub const fn com1() -> Self {
+        Self { base: COM1 }
+    }
+
+    fn write_byte(&self, byte: u8) {
+        unsafe {
+            while inb(self.base + LINE_STATUS) & LSR_THRE == 0 {
+                core::hint::spin_loop();
+            }
+            outb(self.base + DATA, byte);
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    /// Send `s`, turning `\n` into `\r\n`. Does nothing before [`init`].
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if !INITIALIZED.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Program COM1 for 115200 baud, 8N1, with FIFOs enabled and interrupts
+/// off.
+///
+/// The UART is checked in loopback mode first. Returns `false`, leaving
+/// output disabled, if the test byte doesn't come back, which is what
+/// happens on machines without a serial port.
+pub fn init() -> bool {
+    let base = COM1;
+    let working = unsafe {
+        outb(base + INTERRUPT_ENABLE, 0x00);
+        outb(base + LINE_CONTROL, LCR_DLAB);
+        // Divisor 1 selects 115200 baud.
+        outb(base + DATA, 0x01);
+        outb(base + INTERRUPT_ENABLE, 0x00);
+        outb(base + LINE_CONTROL, LCR_8N1);
+        outb(base + FIFO_CONTROL, 0xc7);
+        outb(base + MODEM_CONTROL, MCR_LOOPBACK);
+        outb(base + DATA, TEST_BYTE);
+        let working = inb(base + DATA) == TEST_BYTE;
+        outb(base + MODEM_CONTROL, MCR_NORMAL);
+        working
+    };
+    INITIALIZED.store(working, Ordering::Release);
+    working
+}
+
+/// Print a banner naming `component`, to show the port works.
+pub fn print_banner(component: &str) {
+    crate::serial_println!(
+        "{}: serial console on COM1 ({:#x}), 115200 8N1",
+        component,
+        COM1
+    );
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    let _ = fmt::Write::write_fmt(&mut SerialPort::com1(), args);
+}
+
+/// Print to COM1, like `print!`.
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => {
+        $crate::serial::_print(format_args!($($arg)*))
+    };
+}
+
+/// Print a line to COM1, like `println!`.
+#[macro_export]
+macro_rules! serial_println {
+    () => {
+        $crate::serial_print!("\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::serial::_print(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+unsafe fn outb(port: u16, value: u8) {
+    unsafe {
+        asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+    }
+}
+
+unsafe fn inb(port: u16) -> u8 {
+    let value: u8;
+    unsafe {
+        asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+    }
+    value
+}
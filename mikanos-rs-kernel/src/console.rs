@@ -0,0 +1,213 @@
+//! A text console drawn on the framebuffer with the loader's PSF font.
+//!
+//! There is no scrolling: once the last line is full, output wraps around
+//! to the top line, which is cleared first. [`print!`] and [`println!`]
+//! write to the console set up with [`init`] and do nothing before that.
+//!
+//! [`print!`]: crate::print
+//! [`println!`]: crate::println
+
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::slice;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use mikanos_rs_common::boot_info::FontInfo;
+
+use crate::graphics::FrameBuffer;
+
+/// Drawn for characters the font doesn't have.
+const REPLACEMENT: u8 = b'?';
+
+/// A PSF font whose glyphs the kernel can read.
+struct Font {
+    info: FontInfo,
+    bytes_per_row: usize,
+}
+
+impl Font {
+    /// Check `info`, whose `base` must already be a virtual address.
+    /// Returns `None` if there is no font, it has no glyphs or they don't
+    /// fit in it.
+    fn new(info: FontInfo) -> Option<Self> {
+        let bytes_per_row = info.glyph_width.div_ceil(8) as usize;
+        let glyphs_end =
+            info.header_size as usize + info.glyph_count as usize * info.bytes_per_glyph as usize;
+        let valid = !info.base.is_null()
+            && info.glyph_count > 0
+            && info.glyph_width > 0
+            && info.glyph_height > 0
+            && info.bytes_per_glyph as usize >= bytes_per_row * info.glyph_height as usize
+            && glyphs_end <= info.size;
+        valid.then_some(Self {
+            info,
+            bytes_per_row,
+        })
+    }
+
+    /// The bitmap of `byte`, or of [`REPLACEMENT`] if the font has no glyph
+    /// for it, or of glyph 0 if it doesn't have that either.
+    fn glyph(&self, byte: u8) -> &[u8] {
+        let index = self.info.glyph_index(byte, REPLACEMENT);
+        let size = self.info.bytes_per_glyph as usize;
+        let offset = self.info.header_size as usize + index as usize * size;
+        unsafe { slice::from_raw_parts(self.info.base.add(offset), size) }
+    }
+}
+
+/// Text output on a framebuffer, one glyph cell at a time.
+pub struct Console {
+    framebuffer: FrameBuffer,
+    font: Font,
+    /// `0xRRGGBB` colors of the text and of the rest of each cell.
+    foreground: u32,
+    background: u32,
+    columns: u32,
+    rows: u32,
+    column: u32,
+    row: u32,
+}
+
+impl Console {
+    /// A console covering `framebuffer`, starting in the top left corner.
+    /// Returns `None` if the font is unusable or too large for the screen.
+    pub fn new(
+        framebuffer: FrameBuffer,
+        font: FontInfo,
+        foreground: u32,
+        background: u32,
+    ) -> Option<Self> {
+        let font = Font::new(font)?;
+        let columns = framebuffer.width() / font.info.glyph_width;
+        let rows = framebuffer.height() / font.info.glyph_height;
+        if columns == 0 || rows == 0 {
+            return None;
+        }
+        Some(Self {
+            framebuffer,
+            font,
+            foreground,
+            background,
+            columns,
+            rows,
+            column: 0,
+            row: 0,
+        })
+    }
+
+    /// Draw `byte` in the cell at `column`, `row`.
+    fn draw_glyph(&mut self, byte: u8, column: u32, row: u32) {
+        let (width, height) = (self.font.info.glyph_width, self.font.info.glyph_height);
+        let (left, top) = (column * width, row * height);
+        let glyph = self.font.glyph(byte);
+        for y in 0..height {
+            let start = y as usize * self.font.bytes_per_row;
+            for x in 0..width {
+                let bits = glyph[start + x as usize / 8];
+                let color = if bits & (0x80 >> (x % 8)) != 0 {
+                    self.foreground
+                } else {
+                    self.background
+                };
+                self.framebuffer.write_pixel(left + x, top + y, color);
+            }
+        }
+    }
+
+    /// Move to the start of the next line, wrapping around to the top and
+    /// clearing the line there.
+    fn newline(&mut self) {
+        self.column = 0;
+        self.row = (self.row + 1) % self.rows;
+        for column in 0..self.columns {
+            self.draw_glyph(b' ', column, self.row);
+        }
+    }
+
+    /// Draw `byte` at the cursor and advance it. `\n` starts a new line and
+    /// `\r` returns to the start of the current one.
+    fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.newline(),
+            b'\r' => self.column = 0,
+            _ => {
+                if self.column == self.columns {
+                    self.newline();
+                }
+                self.draw_glyph(byte, self.column, self.row);
+                self.column += 1;
+            }
+        }
+    }
+}
+
+impl fmt::Write for Console {
+    /// Write `s`, drawing every character outside of ASCII as
+    /// [`REPLACEMENT`].
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.write_byte(if c.is_ascii() { c as u8 } else { REPLACEMENT });
+        }
+        Ok(())
+    }
+}
+
+/// The console behind [`print!`](crate::print), guarded by a flag that
+/// makes output that would nest, say from a panic while printing, get
+/// dropped instead of corrupting the cursor.
+struct Global {
+    busy: AtomicBool,
+    console: UnsafeCell<Option<Console>>,
+}
+
+// The kernel runs on one CPU, and `busy` keeps accesses from overlapping.
+unsafe impl Sync for Global {}
+
+static CONSOLE: Global = Global {
+    busy: AtomicBool::new(false),
+    console: UnsafeCell::new(None),
+};
+
+impl Global {
+    /// Run `f` on the console unless it is already in use.
+    fn with(&self, f: impl FnOnce(&mut Option<Console>)) {
+        if self.busy.swap(true, Ordering::Acquire) {
+            return;
+        }
+        f(unsafe { &mut *self.console.get() });
+        self.busy.store(false, Ordering::Release);
+    }
+}
+
+/// Make `console` the target of [`print!`](crate::print).
+pub fn init(console: Console) {
+    CONSOLE.with(|slot| *slot = Some(console));
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    CONSOLE.with(|slot| {
+        if let Some(console) = slot {
+            let _ = fmt::Write::write_fmt(console, args);
+        }
+    });
+}
+
+/// Print to the framebuffer console, like `print!`.
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => {
+        $crate::console::_print(format_args!($($arg)*))
+    };
+}
+
+/// Print a line to the framebuffer console, like `println!`.
+#[macro_export]
+macro_rules! println {
+    () => {
+        $crate::print!("\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::console::_print(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
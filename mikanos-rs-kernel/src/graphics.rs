@@ -0,0 +1,83 @@
+use core::{ptr, slice};
+
+use mikanos_rs_common::boot_info::{FrameBufferInfo, PixelFormat};
+use mikanos_rs_common::framebuffer;
+
+/// A linear framebuffer with 32-bit pixels.
+pub struct FrameBuffer {
+    info: FrameBufferInfo,
+}
+
+impl FrameBuffer {
+    /// Wrap the framebuffer the loader handed over.
+    ///
+    /// Returns `None` if the base is null, as when the firmware has no GOP,
+    /// if either dimension is zero, if the pixel format is unknown, or if
+    /// the buffer is too small to hold `height` scan lines of
+    /// `pixels_per_scan_line` pixels.
+    pub fn new(info: FrameBufferInfo) -> Option<Self> {
+        let valid = !info.base.is_null()
+            && info.pixel_format != PixelFormat::Unknown
+            && info.horizontal_resolution > 0
+            && info.vertical_resolution > 0
+            && info.horizontal_resolution <= info.pixels_per_scan_line
+            && info.size >= info.visible_len();
+        valid.then_some(Self { info })
+    }
+
+    /// The visible scan lines as bytes, [`FrameBufferInfo::visible_len`]
+    /// of them, padding included.
+    ///
+    /// Only valid after the handoff: `info.base` must be mapped, which
+    /// [`FrameBuffer::new`]'s caller takes care of, and the memory belongs
+    /// to the kernel once the loader has exited boot services.
+    pub fn as_mut_bytes(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.info.base, self.info.visible_len()) }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.info.horizontal_resolution
+    }
+
+    pub fn height(&self) -> u32 {
+        self.info.vertical_resolution
+    }
+
+    /// Write one `0xRRGGBB` pixel. Out-of-range coordinates are ignored.
+    pub fn write_pixel(&mut self, x: u32, y: u32, color: u32) {
+        let (Some(offset), Some(bytes)) = (
+            framebuffer::pixel_offset(&self.info, x, y),
+            framebuffer::encode_pixel(&self.info, color),
+        ) else {
+            return;
+        };
+        let pixel = &mut self.as_mut_bytes()[offset..offset + bytes.len()];
+        for (dest, byte) in pixel.iter_mut().zip(bytes) {
+            unsafe { ptr::write_volatile(dest, byte) };
+        }
+    }
+
+    /// Fill the visible area with a gradient from blue on the left to red
+    /// on the right.
+    ///
+    /// A wrong stride shows up as slanted bands and a wrong pixel format as
+    /// swapped ends.
+    pub fn draw_gradient(&mut self) {
+        let last = u32::max(self.width(), 2) - 1;
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                let red = 0xff * x / last;
+                self.write_pixel(x, y, red << 16 | (0xff - red));
+            }
+        }
+    }
+
+    /// Fill the visible area with `color`.
+    pub fn fill(&mut self, color: u32) {
+        for y in 0..self.height() {
+            for x in 0..self.width() {
+                self.write_pixel(x, y, color);
+            }
+        }
+    }
+}
@@ -1,14 +1,160 @@
 #![no_std]
 #![no_main]
 
+mod console;
+mod graphics;
+
+use core::arch::asm;
 use core::panic::PanicInfo;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use console::Console;
+use graphics::FrameBuffer;
+use mikanos_rs_common::boot_info::{BootInfo, FontInfo, MemoryRegion};
+use mikanos_rs_common::{serial, serial_println};
+
+/// Screen color after a panic.
+const PANIC_COLOR: u32 = 0xcc0000;
+
+/// Screen color while the kernel runs, and the console's background.
+const BACKGROUND_COLOR: u32 = 0x1e90ff;
+
+/// Color of console text.
+const TEXT_COLOR: u32 = 0xffffff;
+
+/// The loader's boot info, for the panic handler. Null until
+/// `kernel_main` stores it.
+static BOOT_INFO: AtomicPtr<BootInfo> = AtomicPtr::new(ptr::null_mut());
+
+/// Idle the CPU forever.
+fn hlt_loop() -> ! {
+    loop {
+        unsafe { asm!("hlt", options(nomem, nostack)) };
+    }
+}
 
+/// Report the panic on COM1 (if [`serial::init`] found one) and by turning
+/// the screen red, then halt with interrupts disabled.
+///
+/// Nothing here allocates, so it is safe to reach from anywhere, including
+/// a future allocator.
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    loop {}
+fn panic(info: &PanicInfo) -> ! {
+    match info.location() {
+        Some(location) => serial_println!(
+            "[PANIC]: {}:{}: {}",
+            location.file(),
+            location.line(),
+            info.message()
+        ),
+        None => serial_println!("[PANIC]: {}", info.message()),
+    }
+    let boot_info = BOOT_INFO.load(Ordering::Acquire);
+    if !boot_info.is_null()
+        && let Some(mut framebuffer) = framebuffer(unsafe { &*boot_info })
+    {
+        framebuffer.fill(PANIC_COLOR);
+    }
+    loop {
+        unsafe { asm!("cli", "hlt", options(nomem, nostack)) };
+    }
+}
+
+/// The virtual address of physical address `phys` in the mapping the
+/// loader set up.
+fn phys_to_virt(boot_info: &BootInfo, phys: u64) -> *mut u8 {
+    (boot_info.phys_mem_offset + phys) as *mut u8
+}
+
+/// The framebuffer the loader handed over, or `None` if there is none or
+/// it is unusable.
+fn framebuffer(boot_info: &BootInfo) -> Option<FrameBuffer> {
+    // GOP reports the framebuffer's physical address, and null means there
+    // is none.
+    let mut info = boot_info.framebuffer;
+    if !info.base.is_null() {
+        info.base = phys_to_virt(boot_info, info.base as u64);
+    }
+    FrameBuffer::new(info)
+}
+
+/// The font the loader handed over, with `base` mapped like
+/// [`framebuffer`] does.
+fn font(boot_info: &BootInfo) -> FontInfo {
+    let mut font = boot_info.font;
+    if !font.base.is_null() {
+        font.base = phys_to_virt(boot_info, font.base as u64);
+    }
+    font
+}
+
+/// The command line the loader handed over, with `base` mapped like
+/// [`framebuffer`] does.
+fn cmdline(boot_info: &BootInfo) -> MemoryRegion {
+    let mut cmdline = boot_info.cmdline;
+    if !cmdline.base.is_null() {
+        cmdline.base = phys_to_virt(boot_info, cmdline.base as u64);
+    }
+    cmdline
 }
 
+/// Kernel entry point, called by the loader after exiting boot services.
+///
+/// The loader calls this with the sysv64 ABI, so `boot_info` arrives in
+/// `rdi`. It lives in memory that stays reserved after exit_boot_services,
+/// so it is valid for the lifetime of the kernel.
 #[unsafe(no_mangle)]
-pub extern "C" fn kernel_main() {
-    loop {}
+pub extern "sysv64" fn kernel_main(boot_info: &BootInfo) -> ! {
+    BOOT_INFO.store(ptr::from_ref(boot_info).cast_mut(), Ordering::Release);
+    if serial::init() {
+        serial::print_banner("mikanos-rs-kernel");
+    }
+    // Read from `boot_info`, so this only comes out right if the loader
+    // passed it where the kernel looks.
+    let info = boot_info.framebuffer;
+    serial_println!(
+        "Framebuffer: {}x{}",
+        info.horizontal_resolution,
+        info.vertical_resolution
+    );
+    let time = boot_info.boot_time;
+    if time.is_valid() {
+        serial_println!(
+            "Booted at {:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            time.year,
+            time.month,
+            time.day,
+            time.hour,
+            time.minute,
+            time.second
+        );
+    }
+    // The loader only hands over valid UTF-8, but don't trust it blindly.
+    let cmdline = cmdline(boot_info);
+    if let Ok(cmdline) = core::str::from_utf8(unsafe { cmdline.as_slice() })
+        && !cmdline.is_empty()
+    {
+        serial_println!("Command line: {}", cmdline);
+    }
+    match framebuffer(boot_info) {
+        Some(mut framebuffer) if cfg!(feature = "gradient-demo") => framebuffer.draw_gradient(),
+        Some(mut framebuffer) => {
+            framebuffer.fill(BACKGROUND_COLOR);
+            match Console::new(framebuffer, font(boot_info), TEXT_COLOR, BACKGROUND_COLOR) {
+                Some(console) => console::init(console),
+                None => serial_println!("No usable font: {:?}", boot_info.font),
+            }
+        }
+        None => serial_println!("No usable framebuffer: {:?}", boot_info.framebuffer),
+    }
+    println!("mikanos-rs-kernel");
+    println!(
+        "Framebuffer: {}x{} (stride {}), {:?}",
+        info.horizontal_resolution,
+        info.vertical_resolution,
+        info.pixels_per_scan_line,
+        info.pixel_format
+    );
+    hlt_loop()
 }
@@ -0,0 +1,171 @@
+//! Just enough of the ACPI tables to count the processors: the RSDT and
+//! XSDT that point to the other tables, and the MADT that lists the
+//! interrupt controllers.
+//!
+//! Every table starts with the 36-byte system description header, whose
+//! `Length` at offset 4 covers the whole table and whose bytes sum to zero.
+
+/// Signature of the MADT.
+pub const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+
+/// Size of the header every table starts with.
+pub const HEADER_SIZE: usize = 36;
+
+/// Where the MADT's interrupt controller structures start, after the
+/// header, the local APIC address and the flags.
+const MADT_ENTRIES_OFFSET: usize = HEADER_SIZE + 8;
+
+/// Interrupt controller structure types for a processor's local APIC and
+/// local x2APIC.
+const LOCAL_APIC: u8 = 0;
+const LOCAL_X2APIC: u8 = 9;
+
+/// The `Enabled` bit of a local APIC's or x2APIC's flags.
+const ENABLED: u32 = 1;
+
+/// The `Length` of the table whose header starts `header`, or `None` if
+/// `header` is too short to hold one.
+pub fn table_len(header: &[u8]) -> Option<usize> {
+    let bytes = header.get(4..8)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+}
+
+/// Whether `table` is a whole table with the given signature and a valid
+/// checksum.
+pub fn is_valid_table(table: &[u8], signature: &[u8; 4]) -> bool {
+    table.len() >= HEADER_SIZE
+        && table[..4] == *signature
+        && table_len(table) == Some(table.len())
+        && table.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// The table addresses in the RSDT or XSDT `root`, which are
+/// `entry_size` bytes each: 4 in the RSDT and 8 in the XSDT.
+pub fn root_entries(root: &[u8], entry_size: usize) -> impl Iterator<Item = u64> + '_ {
+    root.get(HEADER_SIZE..)
+        .unwrap_or_default()
+        .chunks_exact(entry_size)
+        .map(|entry| {
+            let mut bytes = [0; 8];
+            bytes[..entry.len()].copy_from_slice(entry);
+            u64::from_le_bytes(bytes)
+        })
+}
+
+/// The number of enabled processors the MADT in `madt` lists, counting
+/// both local APIC and local x2APIC structures.
+///
+/// Returns `None` if `madt` isn't a valid MADT or one of its structures
+/// runs past its end.
+pub fn count_enabled_cpus(madt: &[u8]) -> Option<u32> {
+    if !is_valid_table(madt, MADT_SIGNATURE) {
+        return None;
+    }
+    let mut count = 0;
+    let mut rest = madt.get(MADT_ENTRIES_OFFSET..)?;
+    while !rest.is_empty() {
+        let (ty, len) = (*rest.first()?, *rest.get(1)? as usize);
+        if len < 2 {
+            return None;
+        }
+        let entry = rest.get(..len)?;
+        let flags_offset = match ty {
+            LOCAL_APIC => Some(4),
+            LOCAL_X2APIC => Some(8),
+            _ => None,
+        };
+        if let Some(offset) = flags_offset {
+            let flags = u32::from_le_bytes(entry.get(offset..offset + 4)?.try_into().unwrap());
+            if flags & ENABLED != 0 {
+                count += 1;
+            }
+        }
+        rest = &rest[len..];
+    }
+    Some(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// A table with `signature` and `body` after the header, with the
+    /// length and checksum filled in.
+    fn table(signature: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut table = Vec::new();
+        table.extend_from_slice(signature);
+        table.extend_from_slice(&((HEADER_SIZE + body.len()) as u32).to_le_bytes());
+        table.resize(HEADER_SIZE, 0);
+        table.extend_from_slice(body);
+        let sum = table.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        table[9] = 0u8.wrapping_sub(sum);
+        table
+    }
+
+    fn local_apic(id: u8, flags: u32) -> Vec<u8> {
+        let mut entry = alloc::vec![LOCAL_APIC, 8, id, id];
+        entry.extend_from_slice(&flags.to_le_bytes());
+        entry
+    }
+
+    fn local_x2apic(id: u32, flags: u32) -> Vec<u8> {
+        let mut entry = alloc::vec![LOCAL_X2APIC, 16, 0, 0];
+        entry.extend_from_slice(&id.to_le_bytes());
+        entry.extend_from_slice(&flags.to_le_bytes());
+        entry.extend_from_slice(&id.to_le_bytes());
+        entry
+    }
+
+    fn madt(entries: &[Vec<u8>]) -> Vec<u8> {
+        // Local APIC address and flags.
+        let mut body = alloc::vec![0, 0, 0xe0, 0xfe, 1, 0, 0, 0];
+        for entry in entries {
+            body.extend_from_slice(entry);
+        }
+        table(MADT_SIGNATURE, &body)
+    }
+
+    #[test]
+    fn counts_enabled_processors() {
+        // An I/O APIC, which isn't a processor.
+        let io_apic = alloc::vec![1, 12, 0, 0, 0, 0, 0xc0, 0xfe, 0, 0, 0, 0];
+        let madt = madt(&[
+            local_apic(0, 1),
+            local_apic(1, 1),
+            local_apic(2, 0),
+            io_apic,
+            local_x2apic(300, 1),
+        ]);
+        assert_eq!(count_enabled_cpus(&madt), Some(3));
+        assert_eq!(count_enabled_cpus(&self::madt(&[])), Some(0));
+    }
+
+    #[test]
+    fn rejects_malformed_madt() {
+        let mut bad_checksum = madt(&[local_apic(0, 1)]);
+        bad_checksum[9] ^= 1;
+        assert_eq!(count_enabled_cpus(&bad_checksum), None);
+
+        let facp = table(b"FACP", &[0; 8]);
+        assert_eq!(count_enabled_cpus(&facp), None);
+
+        // The structure claims 16 bytes but the table ends after 8.
+        let mut truncated = local_apic(0, 1);
+        truncated[1] = 16;
+        assert_eq!(count_enabled_cpus(&madt(&[truncated])), None);
+    }
+
+    #[test]
+    fn lists_root_entries() {
+        let mut body = Vec::new();
+        body.extend_from_slice(&0x7fe1_4000u32.to_le_bytes());
+        body.extend_from_slice(&0x7fe1_5000u32.to_le_bytes());
+        let rsdt = table(b"RSDT", &body);
+        assert!(is_valid_table(&rsdt, b"RSDT"));
+        assert_eq!(table_len(&rsdt), Some(rsdt.len()));
+        let entries: Vec<u64> = root_entries(&rsdt, 4).collect();
+        assert_eq!(entries, [0x7fe1_4000, 0x7fe1_5000]);
+    }
+}
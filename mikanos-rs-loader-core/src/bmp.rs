@@ -0,0 +1,149 @@
+//! Uncompressed 24-bit BMP images.
+
+/// Size of the `BITMAPFILEHEADER`.
+const FILE_HEADER_SIZE: usize = 14;
+/// Size of a `BITMAPINFOHEADER`, the oldest info header with the fields
+/// read here. Newer headers are larger and start the same way.
+const INFO_HEADER_SIZE: usize = 40;
+
+/// A parsed BMP image borrowing its pixels from the file.
+#[derive(Debug)]
+pub struct Bitmap<'a> {
+    pub width: u32,
+    pub height: u32,
+    /// Start of the pixel array.
+    pixels: &'a [u8],
+    /// Bytes per row, including the padding to a multiple of four.
+    row_size: usize,
+    /// Whether the first row in the file is the bottom one, as usual.
+    bottom_up: bool,
+}
+
+impl Bitmap<'_> {
+    /// The `0xRRGGBB` color at (`x`, `y`), counted from the top left.
+    ///
+    /// Panics if the coordinates are outside the image.
+    pub fn pixel(&self, x: u32, y: u32) -> u32 {
+        assert!(x < self.width && y < self.height);
+        let row = if self.bottom_up {
+            self.height - 1 - y
+        } else {
+            y
+        };
+        let offset = row as usize * self.row_size + x as usize * 3;
+        let [b, g, r] = [
+            self.pixels[offset],
+            self.pixels[offset + 1],
+            self.pixels[offset + 2],
+        ];
+        u32::from_be_bytes([0, r, g, b])
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Parse `data` as an uncompressed 24-bit BMP file.
+pub fn parse(data: &[u8]) -> Result<Bitmap<'_>, &'static str> {
+    if data.len() < FILE_HEADER_SIZE + INFO_HEADER_SIZE || &data[..2] != b"BM" {
+        return Err("not a BMP file");
+    }
+    let pixel_offset = u32_at(data, 10) as usize;
+    if (u32_at(data, 14) as usize) < INFO_HEADER_SIZE {
+        return Err("unsupported BMP header");
+    }
+    let width = u32_at(data, 18) as i32;
+    let height = u32_at(data, 22) as i32;
+    if u16_at(data, 28) != 24 || u32_at(data, 30) != 0 {
+        return Err("not an uncompressed 24-bit BMP");
+    }
+    if width <= 0 || height == 0 {
+        return Err("empty BMP image");
+    }
+    let width = width as u32;
+    let row_size = (width as usize * 3).next_multiple_of(4);
+    let pixels = data.get(pixel_offset..).ok_or("pixel array past the end")?;
+    if pixels.len() < row_size * height.unsigned_abs() as usize {
+        return Err("pixel array is truncated");
+    }
+    Ok(Bitmap {
+        width,
+        height: height.unsigned_abs(),
+        pixels,
+        row_size,
+        // A negative height marks a top-down image.
+        bottom_up: height > 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// A 2x2 BMP with the given rows, top row first, stored bottom-up
+    /// unless `height` is negative.
+    fn tiny_bmp(height: i32, top: [u32; 2], bottom: [u32; 2]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(b"BM");
+        data.extend_from_slice(&70u32.to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&54u32.to_le_bytes());
+        data.extend_from_slice(&40u32.to_le_bytes());
+        data.extend_from_slice(&2i32.to_le_bytes());
+        data.extend_from_slice(&height.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&24u16.to_le_bytes());
+        data.extend_from_slice(&[0; 24]);
+        let rows = if height > 0 {
+            [bottom, top]
+        } else {
+            [top, bottom]
+        };
+        for row in rows {
+            for color in row {
+                let [_, r, g, b] = color.to_be_bytes();
+                data.extend_from_slice(&[b, g, r]);
+            }
+            // Pad the 6-byte row to 8 bytes.
+            data.extend_from_slice(&[0; 2]);
+        }
+        data
+    }
+
+    #[test]
+    fn reads_bottom_up_rows() {
+        let data = tiny_bmp(2, [0xff0000, 0x00ff00], [0x0000ff, 0xffffff]);
+        let bmp = parse(&data).unwrap();
+        assert_eq!((bmp.width, bmp.height), (2, 2));
+        assert_eq!(bmp.pixel(0, 0), 0xff0000);
+        assert_eq!(bmp.pixel(1, 0), 0x00ff00);
+        assert_eq!(bmp.pixel(0, 1), 0x0000ff);
+        assert_eq!(bmp.pixel(1, 1), 0xffffff);
+    }
+
+    #[test]
+    fn reads_top_down_rows() {
+        let data = tiny_bmp(-2, [0xff0000, 0x00ff00], [0x0000ff, 0xffffff]);
+        let bmp = parse(&data).unwrap();
+        assert_eq!(bmp.height, 2);
+        assert_eq!(bmp.pixel(1, 0), 0x00ff00);
+        assert_eq!(bmp.pixel(0, 1), 0x0000ff);
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let data = tiny_bmp(2, [0; 2], [0; 2]);
+        assert!(parse(&data[..60]).is_err());
+        assert!(parse(b"not a bitmap at all, but long enough to hold the headers.").is_err());
+        let mut paletted = data.clone();
+        paletted[28] = 8;
+        assert!(parse(&paletted).is_err());
+    }
+}
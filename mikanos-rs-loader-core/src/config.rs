@@ -0,0 +1,781 @@
+//! The loader configuration file format.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use log::{LevelFilter, warn};
+use mikanos_rs_common::boot_info::{MAX_CMDLINE_LEN, MAX_MODULES, PixelFormat};
+
+use crate::sha256;
+
+/// Kernel path used when the configuration doesn't name one, and tried
+/// when the configured kernel is missing.
+pub const DEFAULT_KERNEL_PATH: &str = "\\kernel.elf";
+
+/// Initrd path used when the configuration doesn't name one.
+const DEFAULT_INITRD_PATH: &str = "\\initrd";
+
+/// Init app path used when the configuration doesn't name one.
+const DEFAULT_APP_PATH: &str = "\\app.elf";
+
+/// Resolution used when the configuration doesn't ask for one.
+const DEFAULT_RESOLUTION: (usize, usize) = (1280, 720);
+
+/// Dark gray.
+const DEFAULT_BACKGROUND: u32 = 0x202020;
+
+/// Seconds counted down before booting the first entry.
+const DEFAULT_MENU_TIMEOUT: u64 = 5;
+
+/// Kernel stack size in KiB used when the configuration doesn't set one.
+const DEFAULT_KERNEL_STACK_KIB: usize = 64;
+
+/// Range of kernel stack sizes in KiB the configuration may ask for.
+const KERNEL_STACK_KIB: core::ops::RangeInclusive<usize> = 16..=16 * 1024;
+
+const PAGE_SIZE: usize = 0x1000;
+
+/// A malformed line in the configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConfigError {
+    /// 1-based number of the offending line.
+    pub line: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+/// How the memory map dump is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemMapFormat {
+    /// One human-readable line per descriptor, written to `\memmap`.
+    Csv,
+    /// A header of two little-endian `u64`s (entry count, descriptor size)
+    /// followed by the raw `EFI_MEMORY_DESCRIPTOR` records, written to
+    /// `\memmap.bin`.
+    Binary,
+    /// A JSON array with one object per descriptor, written to
+    /// `\memmap.json`. See [`MemoryMapJson`](crate::memory::MemoryMapJson).
+    Json,
+}
+
+/// What the loader does when booting fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    /// Stop the CPU, leaving the error on screen.
+    Halt,
+    /// Reset the machine after a short delay.
+    Reboot,
+}
+
+/// What the loader does if the kernel entry point returns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnKernelReturn {
+    /// Disable interrupts and stop the CPU.
+    Halt,
+    /// Reset the machine.
+    Reboot,
+    /// Wait a few seconds, then stop the CPU with interrupts left as the
+    /// kernel set them.
+    Stall,
+}
+
+/// The CPU mode the kernel entry point is called in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryMode {
+    /// 64-bit mode on the loader's page tables, the way [`EntryAbi`]
+    /// describes.
+    Long,
+    /// 32-bit protected mode with paging off, for kernels that set up long
+    /// mode themselves. Experimental, and only in loaders built with the
+    /// `protected-mode` feature.
+    Protected,
+}
+
+/// How the kernel entry point expects to receive `BootInfo`. All of them
+/// use the sysv64 calling convention.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryAbi {
+    /// A pointer to `BootInfo` in `rdi`.
+    Pointer,
+    /// A copy of `BootInfo` on the stack, as sysv64 passes large structs:
+    /// it starts at `rsp + 8` on entry.
+    Value,
+    /// A pointer to `BootInfo::framebuffer` in `rdi` and one to
+    /// `BootInfo::memory_map` in `rsi`, like the original MikanOS
+    /// `KernelMain`.
+    Split,
+}
+
+/// A kernel the boot menu offers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootEntry {
+    /// Name shown in the menu.
+    pub label: String,
+    /// Path of the kernel ELF file on the boot volume.
+    pub path: String,
+}
+
+/// Settings read from `\loader.conf`.
+///
+/// The file consists of `key = value` lines; whitespace around the key and
+/// the value is ignored. Empty lines and lines starting with `#` are
+/// skipped, and CRLF line endings are accepted. Keys that don't appear keep
+/// their defaults.
+#[derive(Debug)]
+pub struct LoaderConfig {
+    /// FAT volume label of the file system to load everything but this
+    /// config from, from a `volume=LABEL` line. The boot volume is used if
+    /// it is unset or no file system has the label.
+    pub volume: Option<String>,
+    /// Path of the kernel ELF file on the boot volume, used when there are
+    /// no `entries`.
+    pub kernel: String,
+    /// Kernels to choose from in the boot menu, from `entry=label,path`
+    /// lines.
+    pub entries: Vec<BootEntry>,
+    /// Seconds counted down before booting the first entry, from
+    /// `timeout=` (or the older `menu_timeout=`). Zero boots it right away
+    /// without offering the menu.
+    pub menu_timeout: u64,
+    /// ELF modules to load next to the kernel, from `module=path` lines.
+    /// At most [`MAX_MODULES`].
+    pub modules: Vec<String>,
+    /// Expected CRC-32 of the kernel file, from a `kernel_crc=0x...` line.
+    /// Without one the computed checksum is only logged.
+    pub kernel_crc: Option<u32>,
+    /// Expected SHA-256 of the kernel file, from a `kernel_sha256=` line
+    /// with 64 hex digits. Replaces the digest file for the `kernel=` path
+    /// only; kernels booted from a menu entry, the `MikanKernel` variable
+    /// or the archive are still checked against their digest files.
+    pub kernel_sha256: Option<[u8; 32]>,
+    /// Copy kernel segments straight from the file instead of reading it
+    /// into memory first. Compressed and relocatable kernels, and kernels
+    /// with a `kernel_crc` or a digest file, are always read whole.
+    pub stream_kernel: bool,
+    /// Refuse to boot a kernel without a `<kernel path>.sha256` digest file
+    /// next to it, from a `require_signature=true` line. Without it, a
+    /// missing digest file is only warned about. A digest file that exists
+    /// is always checked.
+    pub require_signature: bool,
+    /// Path of the optional initial ramdisk on the boot volume.
+    pub initrd: String,
+    /// Path of the optional init app on the boot volume, from an
+    /// `app=path` line. It is loaded like a module but handed to the kernel
+    /// separately.
+    pub app: String,
+    /// Command line handed to the kernel, from a `cmdline=...` line. At
+    /// most [`MAX_CMDLINE_LEN`] bytes; empty if there is none.
+    pub cmdline: String,
+    /// Preferred graphics mode as `(width, height)`, from a
+    /// `resolution=WIDTHxHEIGHT` line, or `None` to keep the mode the
+    /// firmware set, from `resolution=current`.
+    pub resolution: Option<(usize, usize)>,
+    /// Pixel format to report to the kernel instead of the one GOP claims,
+    /// from a `pixel_format=rgb|bgr` line. Works around firmware that
+    /// mislabels the channel order.
+    pub pixel_format: Option<PixelFormat>,
+    /// Dump the memory map to the boot volume, from a `save_memmap=true`
+    /// line. Off by default to spare slow or flash media a write on every
+    /// boot.
+    pub save_memmap: bool,
+    /// Format of the memory map dump.
+    pub memmap_format: MemMapFormat,
+    /// Merge adjacent descriptors of the same type and attributes in the
+    /// map handed to the kernel, from a `merge_memmap=true` line. Off by
+    /// default, so the kernel sees the map as the firmware reported it.
+    pub merge_memmap: bool,
+    /// Load the kernel and fill in `BootInfo`, then halt instead of exiting
+    /// boot services and jumping to the kernel.
+    pub dry_run: bool,
+    /// Log the available graphics modes before selecting one.
+    pub list_modes: bool,
+    /// Index of the GOP handle to draw on, from a `display=N` line. The
+    /// loader logs every handle's resolution at startup.
+    pub display: usize,
+    /// Draw a progress bar on the framebuffer while loading.
+    pub progress: bool,
+    /// Allocate a back buffer as large as the framebuffer for the kernel,
+    /// from a `double_buffer=true` line.
+    pub double_buffer: bool,
+    /// Dump the kernel's ELF header before parsing it, and log the loadable
+    /// segments of every ELF image before loading it.
+    pub verbose: bool,
+    /// Refuse relocatable images with relocation types the loader can't
+    /// apply. With `strict_relocations=false` they are loaded with a
+    /// warning, leaving those relocations unapplied.
+    pub strict_relocations: bool,
+    /// Map the kernel's `PT_GNU_RELRO` region read-only in the kernel's page
+    /// tables, from a `protect_relro=true` line.
+    pub protect_relro: bool,
+    /// Align the kernel to 2 MiB and map it with 2 MiB pages where it can
+    /// be, from a `huge_pages=true` line.
+    pub huge_pages: bool,
+    /// Check the kernel's mappings before jumping to it, from a
+    /// `verify=true` line.
+    pub verify: bool,
+    /// Log the final `BootInfo` right before jumping to the kernel, from a
+    /// `debug=true` line.
+    pub debug: bool,
+    /// Most verbose log level that is printed, from a `loglevel=` line,
+    /// also spelled `log_level=` or `log-level=`.
+    pub log_level: LevelFilter,
+    /// Write log messages to the UEFI console. They always go to COM1.
+    pub log_console: bool,
+    /// `0xRRGGBB` color the screen is cleared to before the kernel starts.
+    pub background: u32,
+    /// What to do if booting fails, from an `on_error=halt|reboot` line.
+    pub on_error: OnError,
+    /// What to do if the kernel returns, from an
+    /// `on_kernel_return=halt|reboot|stall` line.
+    pub on_kernel_return: OnKernelReturn,
+    /// The CPU mode the kernel starts in, from an
+    /// `entry_mode=long|protected` line.
+    pub entry_mode: EntryMode,
+    /// How the kernel takes `BootInfo`, from an `abi=ptr|value|split` line.
+    pub abi: EntryAbi,
+    /// Size of the stack the kernel starts on in KiB, from a
+    /// `kernel_stack_kib=` line. Between 16 KiB and 16 MiB.
+    pub kernel_stack_kib: usize,
+    /// Address a relocatable kernel is loaded at, from a `load_base=0x...`
+    /// line. Page-aligned; the loader also checks it against the kernel's
+    /// segment alignment, and refuses it for a fixed-address kernel.
+    pub load_base: Option<u64>,
+}
+
+impl Default for LoaderConfig {
+    fn default() -> Self {
+        Self {
+            volume: None,
+            kernel: DEFAULT_KERNEL_PATH.to_string(),
+            entries: Vec::new(),
+            menu_timeout: DEFAULT_MENU_TIMEOUT,
+            modules: Vec::new(),
+            kernel_crc: None,
+            kernel_sha256: None,
+            stream_kernel: false,
+            require_signature: false,
+            initrd: DEFAULT_INITRD_PATH.to_string(),
+            app: DEFAULT_APP_PATH.to_string(),
+            cmdline: String::new(),
+            resolution: Some(DEFAULT_RESOLUTION),
+            pixel_format: None,
+            save_memmap: false,
+            merge_memmap: false,
+            memmap_format: MemMapFormat::Csv,
+            dry_run: false,
+            list_modes: false,
+            display: 0,
+            progress: false,
+            double_buffer: false,
+            verbose: false,
+            strict_relocations: true,
+            protect_relro: false,
+            huge_pages: false,
+            verify: false,
+            debug: false,
+            log_level: LevelFilter::Info,
+            log_console: true,
+            background: DEFAULT_BACKGROUND,
+            on_error: OnError::Halt,
+            on_kernel_return: OnKernelReturn::Stall,
+            entry_mode: EntryMode::Long,
+            abi: EntryAbi::Pointer,
+            kernel_stack_kib: DEFAULT_KERNEL_STACK_KIB,
+            load_base: None,
+        }
+    }
+}
+
+impl LoaderConfig {
+    /// The kernel stack size in bytes, rounded up to whole pages.
+    pub fn kernel_stack_size(&self) -> usize {
+        (self.kernel_stack_kib * 1024).next_multiple_of(PAGE_SIZE)
+    }
+
+    /// Parse the configuration in `text`.
+    ///
+    /// Lines without `=` and values that don't fit their key are errors.
+    /// Unknown keys are only warned about, so that a config written for a
+    /// newer loader still boots.
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |reason| ConfigError {
+                line: index + 1,
+                reason,
+            };
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(error("expected `key = value`"));
+            };
+            let (key, value) = (key.trim(), value.trim());
+            match key {
+                "volume" => config.volume = Some(value.to_string()),
+                "kernel" => config.kernel = value.to_string(),
+                "entry" => {
+                    let (label, path) = value
+                        .split_once(',')
+                        .ok_or(error("boot entry has no path"))?;
+                    config.entries.push(BootEntry {
+                        label: label.trim().to_string(),
+                        path: path.trim().to_string(),
+                    });
+                }
+                "module" => {
+                    if config.modules.len() == MAX_MODULES {
+                        return Err(error("too many modules"));
+                    }
+                    config.modules.push(value.to_string());
+                }
+                "timeout" | "menu_timeout" => {
+                    config.menu_timeout =
+                        value.parse().map_err(|_| error("invalid menu timeout"))?
+                }
+                "kernel_crc" => {
+                    config.kernel_crc = Some(parse_hex(value).ok_or(error("invalid kernel CRC"))?)
+                }
+                "kernel_sha256" => {
+                    config.kernel_sha256 =
+                        Some(sha256::parse_digest(value).ok_or(error("invalid SHA-256 digest"))?)
+                }
+                "require_signature" => {
+                    config.require_signature =
+                        parse_bool(value).ok_or(error("expected a boolean"))?
+                }
+                "stream_kernel" => {
+                    config.stream_kernel = parse_bool(value).ok_or(error("expected a boolean"))?
+                }
+                "initrd" => config.initrd = value.to_string(),
+                "app" => config.app = value.to_string(),
+                "cmdline" => {
+                    if value.len() > MAX_CMDLINE_LEN {
+                        return Err(error("command line too long"));
+                    }
+                    config.cmdline = value.to_string();
+                }
+                "resolution" => {
+                    config.resolution = match value {
+                        "current" => None,
+                        _ => Some(
+                            parse_resolution(value)
+                                .ok_or(error("expected a resolution like `1920x1080`"))?,
+                        ),
+                    }
+                }
+                "pixel_format" => {
+                    config.pixel_format = Some(match value {
+                        "rgb" => PixelFormat::Rgb,
+                        "bgr" => PixelFormat::Bgr,
+                        _ => return Err(error("expected `rgb` or `bgr`")),
+                    })
+                }
+                "merge_memmap" => {
+                    config.merge_memmap = parse_bool(value).ok_or(error("expected a boolean"))?
+                }
+                "save_memmap" => {
+                    config.save_memmap = parse_bool(value).ok_or(error("expected a boolean"))?
+                }
+                "memmap_format" => {
+                    config.memmap_format = match value {
+                        "csv" => MemMapFormat::Csv,
+                        "binary" => MemMapFormat::Binary,
+                        "json" => MemMapFormat::Json,
+                        _ => return Err(error("unknown memory map format")),
+                    }
+                }
+                "dry_run" => {
+                    config.dry_run = parse_bool(value).ok_or(error("expected a boolean"))?
+                }
+                "list_modes" => {
+                    config.list_modes = parse_bool(value).ok_or(error("expected a boolean"))?
+                }
+                "display" => {
+                    config.display = value.parse().map_err(|_| error("invalid display index"))?
+                }
+                "progress" => {
+                    config.progress = parse_bool(value).ok_or(error("expected a boolean"))?
+                }
+                "double_buffer" => {
+                    config.double_buffer = parse_bool(value).ok_or(error("expected a boolean"))?
+                }
+                "verbose" => {
+                    config.verbose = parse_bool(value).ok_or(error("expected a boolean"))?
+                }
+                "strict_relocations" => {
+                    config.strict_relocations =
+                        parse_bool(value).ok_or(error("expected a boolean"))?
+                }
+                "protect_relro" => {
+                    config.protect_relro = parse_bool(value).ok_or(error("expected a boolean"))?
+                }
+                "huge_pages" => {
+                    config.huge_pages = parse_bool(value).ok_or(error("expected a boolean"))?
+                }
+                "debug" => config.debug = parse_bool(value).ok_or(error("expected a boolean"))?,
+                "verify" => config.verify = parse_bool(value).ok_or(error("expected a boolean"))?,
+                "log_console" => {
+                    config.log_console = parse_bool(value).ok_or(error("expected a boolean"))?
+                }
+                "background" => {
+                    config.background =
+                        parse_color(value).ok_or(error("invalid background color"))?
+                }
+                "on_error" => {
+                    config.on_error = match value {
+                        "halt" => OnError::Halt,
+                        "reboot" => OnError::Reboot,
+                        _ => return Err(error("expected `halt` or `reboot`")),
+                    }
+                }
+                "on_kernel_return" => {
+                    config.on_kernel_return = match value {
+                        "halt" => OnKernelReturn::Halt,
+                        "reboot" => OnKernelReturn::Reboot,
+                        "stall" => OnKernelReturn::Stall,
+                        _ => return Err(error("expected `halt`, `reboot` or `stall`")),
+                    }
+                }
+                "entry_mode" => {
+                    config.entry_mode = match value {
+                        "long" => EntryMode::Long,
+                        "protected" => EntryMode::Protected,
+                        _ => return Err(error("expected `long` or `protected`")),
+                    }
+                }
+                "abi" => {
+                    config.abi = match value {
+                        "ptr" => EntryAbi::Pointer,
+                        "value" => EntryAbi::Value,
+                        "split" => EntryAbi::Split,
+                        _ => return Err(error("expected `ptr`, `value` or `split`")),
+                    }
+                }
+                "kernel_stack_kib" => {
+                    config.kernel_stack_kib = value
+                        .parse()
+                        .ok()
+                        .filter(|kib| KERNEL_STACK_KIB.contains(kib))
+                        .ok_or(error("kernel stack size must be 16 to 16384 KiB"))?
+                }
+                "load_base" => {
+                    config.load_base = Some(
+                        parse_address(value)
+                            .filter(|base| base.is_multiple_of(PAGE_SIZE as u64))
+                            .ok_or(error("load base must be a page-aligned hex address"))?,
+                    )
+                }
+                "loglevel" | "log_level" | "log-level" => {
+                    config.log_level = value.parse().map_err(|_| error("unknown log level"))?
+                }
+                _ => warn!("Ignoring unknown config key on line {}: {}", index + 1, key),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Decode the value of the EFI variable that names the next kernel: a
+/// UCS-2 path in little-endian order, as `efivar` and the UEFI shell write
+/// strings, optionally NUL-terminated.
+///
+/// Returns `None` for an empty path, an odd number of bytes, unpaired
+/// surrogates and control characters.
+pub fn parse_kernel_var(value: &[u8]) -> Option<String> {
+    let (pairs, []) = value.as_chunks::<2>() else {
+        return None;
+    };
+    let units = pairs
+        .iter()
+        .map(|&pair| u16::from_le_bytes(pair))
+        .take_while(|&unit| unit != 0);
+    let path: String = char::decode_utf16(units).collect::<Result<_, _>>().ok()?;
+    if path.is_empty() || path.chars().any(char::is_control) {
+        return None;
+    }
+    Some(path)
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse a `0xRRGGBB` or `RRGGBB` color.
+fn parse_color(value: &str) -> Option<u32> {
+    let digits = value.strip_prefix("0x").unwrap_or(value);
+    if digits.len() != 6 {
+        return None;
+    }
+    u32::from_str_radix(digits, 16).ok()
+}
+
+/// Parse a 32-bit `0x`-prefixed or bare hexadecimal number.
+fn parse_hex(value: &str) -> Option<u32> {
+    let digits = value.strip_prefix("0x").unwrap_or(value);
+    u32::from_str_radix(digits, 16).ok()
+}
+
+/// Parse a 64-bit `0x`-prefixed or bare hexadecimal address.
+fn parse_address(value: &str) -> Option<u64> {
+    let digits = value.strip_prefix("0x").unwrap_or(value);
+    u64::from_str_radix(digits, 16).ok()
+}
+
+/// Parse a `WIDTHxHEIGHT` resolution such as `1024x768`.
+fn parse_resolution(value: &str) -> Option<(usize, usize)> {
+    let (width, height) = value.split_once('x')?;
+    match (width.parse().ok()?, height.parse().ok()?) {
+        (0, _) | (_, 0) => None,
+        resolution => Some(resolution),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "\
+# Boot the debug kernel in a small window.
+volume = MIKANOS
+kernel = \\EFI\\mikanos\\kernel-debug.elf\r
+resolution=1024x768
+
+  loglevel = debug
+dry_run = true
+save_memmap = true
+background = 0x000080
+on_error = reboot
+on_kernel_return = halt
+pixel_format = bgr
+display = 1
+abi = split
+entry_mode = protected
+";
+
+    #[test]
+    fn parses_sample() {
+        let config = LoaderConfig::parse(SAMPLE).unwrap();
+        assert_eq!(config.volume.as_deref(), Some("MIKANOS"));
+        assert_eq!(config.kernel, "\\EFI\\mikanos\\kernel-debug.elf");
+        assert_eq!(config.resolution, Some((1024, 768)));
+        assert_eq!(config.log_level, LevelFilter::Debug);
+        assert!(config.dry_run);
+        assert!(config.save_memmap);
+        assert_eq!(config.background, 0x000080);
+        assert_eq!(config.on_error, OnError::Reboot);
+        assert_eq!(config.on_kernel_return, OnKernelReturn::Halt);
+        assert_eq!(config.pixel_format, Some(PixelFormat::Bgr));
+        assert_eq!(config.display, 1);
+        assert_eq!(config.abi, EntryAbi::Split);
+        assert_eq!(config.entry_mode, EntryMode::Protected);
+    }
+
+    #[test]
+    fn missing_keys_keep_defaults() {
+        let config = LoaderConfig::parse("# nothing here\n\n").unwrap();
+        assert_eq!(config.volume, None);
+        assert!(!config.save_memmap);
+        assert!(!config.merge_memmap);
+        assert!(!config.double_buffer);
+        assert!(!config.require_signature);
+        assert_eq!(config.kernel_sha256, None);
+        assert!(config.strict_relocations);
+        assert!(!config.protect_relro);
+        assert!(!config.huge_pages);
+        assert!(!config.verify);
+        assert!(!config.debug);
+        assert_eq!(config.kernel, DEFAULT_KERNEL_PATH);
+        assert_eq!(config.app, DEFAULT_APP_PATH);
+        assert_eq!(config.cmdline, "");
+        assert_eq!(config.abi, EntryAbi::Pointer);
+        assert_eq!(config.entry_mode, EntryMode::Long);
+        assert_eq!(config.resolution, Some(DEFAULT_RESOLUTION));
+        assert_eq!(config.log_level, LevelFilter::Info);
+        assert!(!config.dry_run);
+        assert_eq!(config.background, DEFAULT_BACKGROUND);
+        assert_eq!(config.on_error, OnError::Halt);
+        assert_eq!(config.on_kernel_return, OnKernelReturn::Stall);
+        assert_eq!(config.pixel_format, None);
+        assert_eq!(config.display, 0);
+        assert_eq!(config.load_base, None);
+        assert_eq!(config.kernel_stack_size(), 64 * 1024);
+    }
+
+    #[test]
+    fn parses_load_base() {
+        let config = LoaderConfig::parse("load_base = 0xffff800000200000\n").unwrap();
+        assert_eq!(config.load_base, Some(0xffff_8000_0020_0000));
+        for text in ["load_base = 0x1234\n", "load_base = base\n"] {
+            assert_eq!(
+                LoaderConfig::parse(text).unwrap_err().reason,
+                "load base must be a page-aligned hex address"
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_kernel_variable() {
+        let ucs2 =
+            |text: &str| -> Vec<u8> { text.encode_utf16().flat_map(u16::to_le_bytes).collect() };
+        assert_eq!(
+            parse_kernel_var(&ucs2("\\kernel-next.elf")).as_deref(),
+            Some("\\kernel-next.elf")
+        );
+        assert_eq!(
+            parse_kernel_var(&ucs2("\\kernel.elf\0garbage")).as_deref(),
+            Some("\\kernel.elf")
+        );
+        assert_eq!(parse_kernel_var(&ucs2("\0")), None);
+        assert_eq!(parse_kernel_var(&ucs2("\\kernel\n")), None);
+        assert_eq!(parse_kernel_var(b"\\\0k"), None);
+        assert_eq!(parse_kernel_var(&[0x00, 0xd8, b'k', 0]), None);
+    }
+
+    #[test]
+    fn rounds_kernel_stack_to_pages() {
+        let config = LoaderConfig::parse("kernel_stack_kib = 18\n").unwrap();
+        assert_eq!(config.kernel_stack_kib, 18);
+        assert_eq!(config.kernel_stack_size(), 20 * 1024);
+        for text in ["kernel_stack_kib = 8\n", "kernel_stack_kib = 32768\n"] {
+            assert_eq!(
+                LoaderConfig::parse(text).unwrap_err().reason,
+                "kernel stack size must be 16 to 16384 KiB"
+            );
+        }
+    }
+
+    #[test]
+    fn parses_boot_entries() {
+        let config = LoaderConfig::parse("entry = Debug, \\debug.elf\n").unwrap();
+        assert_eq!(
+            config.entries,
+            [BootEntry {
+                label: "Debug".to_string(),
+                path: "\\debug.elf".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn accepts_both_timeout_keys() {
+        assert_eq!(LoaderConfig::parse("timeout=0\n").unwrap().menu_timeout, 0);
+        assert_eq!(
+            LoaderConfig::parse("menu_timeout=3\n")
+                .unwrap()
+                .menu_timeout,
+            3
+        );
+    }
+
+    #[test]
+    fn caps_modules() {
+        let mut text = String::new();
+        for i in 0..MAX_MODULES {
+            text += &alloc::format!("module=\\srv\\{}.elf\n", i);
+        }
+        let config = LoaderConfig::parse(&text).unwrap();
+        assert_eq!(config.modules.len(), MAX_MODULES);
+        assert_eq!(config.modules[1], "\\srv\\1.elf");
+        text += "module=\\srv\\extra.elf\n";
+        let err = LoaderConfig::parse(&text).unwrap_err();
+        assert_eq!(err.line, MAX_MODULES + 1);
+    }
+
+    #[test]
+    fn keeps_equals_signs_in_cmdline() {
+        let config = LoaderConfig::parse("cmdline = root=/dev/sda1 quiet\n").unwrap();
+        assert_eq!(config.cmdline, "root=/dev/sda1 quiet");
+        let long = alloc::format!("cmdline={}\n", "x".repeat(MAX_CMDLINE_LEN + 1));
+        assert!(LoaderConfig::parse(&long).is_err());
+    }
+
+    #[test]
+    fn reports_line_of_malformed_input() {
+        let err = LoaderConfig::parse("kernel=\\kernel.elf\n\nnot a setting\n").unwrap_err();
+        assert_eq!(
+            err,
+            ConfigError {
+                line: 3,
+                reason: "expected `key = value`",
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_resolutions() {
+        for value in [
+            "1920x",
+            "x1080",
+            "axb",
+            "1920x1080x32",
+            "0x600",
+            "1920X1080",
+        ] {
+            let err = LoaderConfig::parse(&alloc::format!("resolution={}\n", value)).unwrap_err();
+            assert_eq!(
+                err.reason, "expected a resolution like `1920x1080`",
+                "{}",
+                value
+            );
+        }
+        assert_eq!(
+            LoaderConfig::parse("resolution = 1920x1080\n")
+                .unwrap()
+                .resolution,
+            Some((1920, 1080))
+        );
+        assert_eq!(
+            LoaderConfig::parse("resolution=current\n")
+                .unwrap()
+                .resolution,
+            None
+        );
+    }
+
+    #[test]
+    fn parses_kernel_digest() {
+        let hex = "00112233445566778899aabbccddeeff00112233445566778899AABBCCDDEEFF";
+        let config = LoaderConfig::parse(&alloc::format!("kernel_sha256 = {}\n", hex)).unwrap();
+        let digest = config.kernel_sha256.unwrap();
+        assert_eq!(digest[..4], [0x00, 0x11, 0x22, 0x33]);
+        assert_eq!(digest[31], 0xff);
+        let err = LoaderConfig::parse("kernel_sha256=0011\n").unwrap_err();
+        assert_eq!(err.reason, "invalid SHA-256 digest");
+    }
+
+    #[test]
+    fn accepts_log_level_spellings() {
+        for key in ["loglevel", "log_level", "log-level"] {
+            let config = LoaderConfig::parse(&alloc::format!("{} = warn\n", key)).unwrap();
+            assert_eq!(config.log_level, LevelFilter::Warn, "{}", key);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert_eq!(
+            LoaderConfig::parse("resolution=1024\n").unwrap_err().line,
+            1
+        );
+        assert_eq!(LoaderConfig::parse("\ndry_run=yes\n").unwrap_err().line, 2);
+        assert_eq!(LoaderConfig::parse("background=red").unwrap_err().line, 1);
+        assert_eq!(
+            LoaderConfig::parse("pixel_format=bitmask")
+                .unwrap_err()
+                .line,
+            1
+        );
+    }
+}
@@ -0,0 +1,48 @@
+//! Lookups in the UEFI configuration table, apart from the firmware's
+//! types so that they can be tested with made-up tables.
+
+/// The address of the first table in `tables`, as `(guid, address)` pairs,
+/// that has one of `guids`, trying the GUIDs in order of preference.
+pub fn find_preferred<G: PartialEq, A>(
+    tables: impl Iterator<Item = (G, A)> + Clone,
+    guids: &[G],
+) -> Option<A> {
+    guids.iter().find_map(|guid| {
+        tables
+            .clone()
+            .find(|(entry, _)| entry == guid)
+            .map(|(_, addr)| addr)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACPI: u32 = 0xeb9d2d30;
+    const ACPI2: u32 = 0x8868e871;
+    const SMBIOS: u32 = 0xeb9d2d31;
+
+    fn find(tables: &[(u32, usize)]) -> Option<usize> {
+        find_preferred(tables.iter().copied(), &[ACPI2, ACPI])
+    }
+
+    #[test]
+    fn prefers_acpi_2() {
+        assert_eq!(
+            find(&[(SMBIOS, 0x1000), (ACPI, 0x2000), (ACPI2, 0x3000)]),
+            Some(0x3000)
+        );
+    }
+
+    #[test]
+    fn falls_back_to_acpi_1() {
+        assert_eq!(find(&[(SMBIOS, 0x1000), (ACPI, 0x2000)]), Some(0x2000));
+    }
+
+    #[test]
+    fn finds_nothing_without_a_match() {
+        assert_eq!(find(&[(SMBIOS, 0x1000)]), None);
+        assert_eq!(find(&[]), None);
+    }
+}
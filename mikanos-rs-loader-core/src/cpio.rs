@@ -0,0 +1,248 @@
+//! Archives in the cpio "newc" format, as written by `cpio -o -H newc`.
+//!
+//! Each member is a 110-byte ASCII header, the NUL-terminated name padded
+//! to a multiple of four bytes, and the contents padded the same way. The
+//! archive ends with a member named [`TRAILER`].
+
+use core::fmt;
+
+/// Size of a newc header.
+const HEADER_SIZE: usize = 110;
+
+/// Magic of a newc header, and of one with checksums.
+const MAGICS: [&[u8]; 2] = [b"070701", b"070702"];
+
+/// Name of the member that marks the end of the archive.
+pub const TRAILER: &str = "TRAILER!!!";
+
+/// Why an archive can't be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpioError {
+    /// A header at this offset doesn't start with a newc magic.
+    BadMagic(usize),
+    /// A header at this offset has a field that isn't hex, or a name that
+    /// isn't NUL-terminated UTF-8.
+    BadHeader(usize),
+    /// The member at this offset runs past the end of the archive.
+    Truncated(usize),
+    /// The archive ends without a trailer.
+    MissingTrailer,
+}
+
+impl fmt::Display for CpioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic(offset) => write!(f, "no cpio newc header at offset {:#x}", offset),
+            Self::BadHeader(offset) => write!(f, "malformed cpio header at offset {:#x}", offset),
+            Self::Truncated(offset) => {
+                write!(f, "cpio member at offset {:#x} is truncated", offset)
+            }
+            Self::MissingTrailer => write!(f, "cpio archive has no trailer"),
+        }
+    }
+}
+
+/// A member of an archive, borrowing from it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry<'a> {
+    /// The name as stored, which may start with `./`.
+    pub name: &'a str,
+    /// The file type and permission bits.
+    pub mode: u32,
+    pub data: &'a [u8],
+}
+
+/// Iterates over the members of an archive, stopping at the trailer or the
+/// first error.
+pub struct Entries<'a> {
+    data: &'a [u8],
+    offset: usize,
+    done: bool,
+}
+
+/// The members of the archive in `data`, in order, without the trailer.
+pub fn entries(data: &[u8]) -> Entries<'_> {
+    Entries {
+        data,
+        offset: 0,
+        done: false,
+    }
+}
+
+/// The contents of the member named `name`, ignoring a leading `./` or `/`
+/// on either side. Returns `Ok(None)` if there is no such member.
+pub fn find<'a>(data: &'a [u8], name: &str) -> Result<Option<&'a [u8]>, CpioError> {
+    let name = normalize(name);
+    for entry in entries(data) {
+        let entry = entry?;
+        if normalize(entry.name) == name {
+            return Ok(Some(entry.data));
+        }
+    }
+    Ok(None)
+}
+
+/// `name` without a leading `./` or `/`.
+fn normalize(name: &str) -> &str {
+    let name = name.strip_prefix("./").unwrap_or(name);
+    name.strip_prefix('/').unwrap_or(name)
+}
+
+/// The 8-digit hex field `index` of the header at the start of `header`.
+fn field(header: &[u8], index: usize) -> Option<u32> {
+    let start = MAGICS[0].len() + 8 * index;
+    let digits = core::str::from_utf8(&header[start..start + 8]).ok()?;
+    if !digits.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    u32::from_str_radix(digits, 16).ok()
+}
+
+impl<'a> Entries<'a> {
+    /// Parse the member at `self.offset`, or return `None` at the trailer.
+    fn parse(&mut self) -> Result<Option<Entry<'a>>, CpioError> {
+        let offset = self.offset;
+        if offset >= self.data.len() {
+            return Err(CpioError::MissingTrailer);
+        }
+        let header = self
+            .data
+            .get(offset..offset + HEADER_SIZE)
+            .ok_or(CpioError::Truncated(offset))?;
+        if !MAGICS.contains(&&header[..6]) {
+            return Err(CpioError::BadMagic(offset));
+        }
+        let bad_header = CpioError::BadHeader(offset);
+        let mode = field(header, 1).ok_or(bad_header)?;
+        let file_size = field(header, 6).ok_or(bad_header)? as usize;
+        let name_size = field(header, 11).ok_or(bad_header)? as usize;
+
+        let name_start = offset + HEADER_SIZE;
+        let name_bytes = self
+            .data
+            .get(name_start..name_start + name_size)
+            .ok_or(CpioError::Truncated(offset))?;
+        let name = match name_bytes.split_last() {
+            Some((0, name)) => core::str::from_utf8(name).map_err(|_| bad_header)?,
+            _ => return Err(bad_header),
+        };
+        let data_start = (name_start + name_size).next_multiple_of(4);
+        let data = self
+            .data
+            .get(data_start..data_start + file_size)
+            .ok_or(CpioError::Truncated(offset))?;
+        self.offset = (data_start + file_size).next_multiple_of(4);
+
+        if name == TRAILER {
+            return Ok(None);
+        }
+        Ok(Some(Entry { name, mode, data }))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<Entry<'a>, CpioError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.parse() {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// Append a member to `archive` the way `cpio -o -H newc` writes it.
+    fn push(archive: &mut Vec<u8>, name: &str, data: &[u8]) {
+        let header = format!(
+            "070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+            1,
+            0o100644,
+            0,
+            0,
+            1,
+            0,
+            data.len(),
+            0,
+            0,
+            0,
+            0,
+            name.len() + 1,
+            0
+        );
+        archive.extend_from_slice(header.as_bytes());
+        archive.extend_from_slice(name.as_bytes());
+        archive.push(0);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().next_multiple_of(4), 0);
+    }
+
+    fn sample() -> Vec<u8> {
+        let mut archive = Vec::new();
+        push(&mut archive, "./kernel.elf", b"\x7fELF kernel");
+        push(&mut archive, "font.psf", b"font");
+        push(&mut archive, TRAILER, b"");
+        archive
+    }
+
+    #[test]
+    fn lists_members() {
+        let archive = sample();
+        let members: Vec<Entry> = entries(&archive).map(Result::unwrap).collect();
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].name, "./kernel.elf");
+        assert_eq!(members[0].mode, 0o100644);
+        assert_eq!(members[0].data, b"\x7fELF kernel");
+        assert_eq!(members[1].name, "font.psf");
+        assert_eq!(members[1].data, b"font");
+    }
+
+    #[test]
+    fn finds_members_by_name() {
+        let archive = sample();
+        assert_eq!(
+            find(&archive, "kernel.elf"),
+            Ok(Some(&b"\x7fELF kernel"[..]))
+        );
+        assert_eq!(find(&archive, "/font.psf"), Ok(Some(&b"font"[..])));
+        assert_eq!(find(&archive, "initrd"), Ok(None));
+    }
+
+    #[test]
+    fn rejects_malformed_archives() {
+        let mut archive = Vec::new();
+        push(&mut archive, "kernel.elf", b"kernel");
+        assert_eq!(find(&archive, "initrd"), Err(CpioError::MissingTrailer));
+
+        let archive = sample();
+        assert_eq!(
+            find(&archive[..HEADER_SIZE + 4], "kernel.elf"),
+            Err(CpioError::Truncated(0))
+        );
+
+        let mut archive = sample();
+        archive[0] = b'1';
+        assert_eq!(find(&archive, "kernel.elf"), Err(CpioError::BadMagic(0)));
+
+        let mut archive = sample();
+        archive[6 + 8 * 6] = b'x';
+        assert_eq!(find(&archive, "kernel.elf"), Err(CpioError::BadHeader(0)));
+    }
+}
@@ -0,0 +1,1221 @@
+//! Working out how to load an ELF kernel, without touching memory.
+//!
+//! [`plan`] parses and checks a kernel image and describes what has to be
+//! copied where. The loader then carries the plan out with UEFI page
+//! allocations. [`plan_from_headers`] does the same for a fixed-address
+//! executable from just its headers.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+
+use goblin::container::Ctx;
+use goblin::elf::header::{EI_CLASS, ELFCLASS64, EM_X86_64, ET_DYN, ET_EXEC};
+use goblin::elf::program_header::{
+    PF_R, PF_W, PF_X, PT_GNU_RELRO, PT_LOAD, PT_NOTE, ProgramHeader,
+};
+use goblin::elf::reloc::{R_X86_64_NONE, R_X86_64_RELATIVE, r_to_str};
+use goblin::elf::{Elf, Header};
+
+/// Minimum alignment of the image, the x86-64 page size.
+const PAGE_SIZE: usize = 0x1000;
+
+/// Size of an ELF64 file header.
+pub const HEADER_SIZE: usize = 64;
+
+/// First address of the upper half of the canonical x86-64 address space.
+/// Firmware can't allocate pages here, since it identity maps memory.
+pub const HIGHER_HALF_START: u64 = 0xffff_8000_0000_0000;
+
+/// Why an ELF file can't be loaded.
+#[derive(Debug)]
+pub enum PlanError {
+    /// The file could not be parsed as an ELF file.
+    Parse(goblin::error::Error),
+    /// The file is an ELF file the loader can't run.
+    Unsupported(&'static str),
+    /// A relocatable image uses relocation types the loader can't apply,
+    /// listed once each in ascending order.
+    UnsupportedRelocations(Vec<u32>),
+    /// The entry point is not inside an executable loaded segment.
+    BadEntry(u64),
+    /// A load base was given for an image that can only be loaded at its
+    /// link address.
+    FixedLoadBase,
+    /// A load base is not a multiple of the image's segment alignment.
+    MisalignedLoadBase { base: u64, align: usize },
+}
+
+impl fmt::Display for PlanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Parse(err) => write!(f, "failed to parse kernel ELF: {}", err),
+            Self::Unsupported(reason) => write!(f, "unsupported kernel ELF: {}", reason),
+            Self::UnsupportedRelocations(types) => {
+                write!(f, "unsupported relocation types {}", RelocationTypes(types))
+            }
+            Self::BadEntry(entry) => {
+                write!(
+                    f,
+                    "entry point {:#x} is not in an executable segment",
+                    entry
+                )
+            }
+            Self::FixedLoadBase => {
+                write!(
+                    f,
+                    "a load base only applies to relocatable (ET_DYN) kernels"
+                )
+            }
+            Self::MisalignedLoadBase { base, align } => write!(
+                f,
+                "load base {:#x} is not aligned to the kernel's {:#x}-byte segments",
+                base, align
+            ),
+        }
+    }
+}
+
+impl From<goblin::error::Error> for PlanError {
+    fn from(err: goblin::error::Error) -> Self {
+        Self::Parse(err)
+    }
+}
+
+/// One PT_LOAD segment to copy into memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    /// Offset of the segment's contents in the file.
+    pub offset: usize,
+    /// Bytes to copy from the file.
+    pub file_size: usize,
+    /// Bytes the segment occupies in memory. Everything past `file_size` is
+    /// zero-filled.
+    pub mem_size: usize,
+    /// Link address of the segment.
+    pub vaddr: u64,
+    /// The segment's `PF_*` flags.
+    pub flags: u32,
+}
+
+impl Segment {
+    /// Copy the segment's contents from the ELF file `data` into `dest` and
+    /// zero the rest of it.
+    ///
+    /// `dest` is where the segment's `mem_size` bytes go, so every byte from
+    /// `vaddr + file_size` up to `vaddr + mem_size` is zeroed however many
+    /// pages that spans. Memory past `mem_size` in the same pages is left
+    /// alone, since another segment may share it.
+    ///
+    /// Panics if `dest` is not `mem_size` bytes long.
+    pub fn copy_to(&self, data: &[u8], dest: &mut [u8]) {
+        assert_eq!(dest.len(), self.mem_size);
+        let (contents, bss) = dest.split_at_mut(self.file_size);
+        contents.copy_from_slice(&data[self.offset..self.offset + self.file_size]);
+        bss.fill(0);
+    }
+}
+
+/// Displays `PF_*` flags like `readelf` does, as `R`, `W` and `X` with `-`
+/// for the ones that aren't set.
+pub struct SegmentFlags(pub u32);
+
+impl fmt::Display for SegmentFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flag = |bit, c| if self.0 & bit != 0 { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(PF_R, 'R'),
+            flag(PF_W, 'W'),
+            flag(PF_X, 'X')
+        )
+    }
+}
+
+/// Displays segments as an aligned table, one line per segment after a
+/// header line.
+pub struct SegmentTable<'a>(pub &'a [Segment]);
+
+impl fmt::Display for SegmentTable<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<18}  {:<10}  {:<10}  {:<10}  flags",
+            "vaddr", "offset", "filesz", "memsz"
+        )?;
+        for segment in self.0 {
+            write!(
+                f,
+                "\n{:#018x}  {:#010x}  {:#010x}  {:#010x}  {}",
+                segment.vaddr,
+                segment.offset,
+                segment.file_size,
+                segment.mem_size,
+                SegmentFlags(segment.flags),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Displays relocation types by name where goblin knows it, separated by
+/// commas.
+pub struct RelocationTypes<'a>(pub &'a [u32]);
+
+impl fmt::Display for RelocationTypes<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, &ty) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            // goblin is inconsistent about the `R_` prefix.
+            match r_to_str(ty, EM_X86_64) {
+                "R_UNKNOWN_X86_64" => write!(f, "{}", ty)?,
+                name => write!(f, "R_{}", name.trim_start_matches("R_"))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An `R_X86_64_RELATIVE` relocation: store `bias + addend` as a `u64` at
+/// `offset + bias`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relocation {
+    /// Link address of the word to patch.
+    pub offset: u64,
+    pub addend: i64,
+}
+
+/// Everything the loader needs to place an ELF image in memory.
+#[derive(Debug)]
+pub struct LoadPlan {
+    /// Whether the image is position independent (`ET_DYN`) and may be
+    /// loaded away from its link address.
+    pub relocatable: bool,
+    /// Link address of the entry point.
+    pub entry: u64,
+    /// Lowest link address of any segment.
+    pub start: usize,
+    /// End of the highest segment.
+    pub end: usize,
+    /// The largest segment alignment, at least a page. A relocatable image
+    /// must be shifted by a multiple of this.
+    pub align: usize,
+    pub segments: Vec<Segment>,
+    /// Relocations to apply once the image's load address is known. Always
+    /// empty for a fixed-address image.
+    pub relocations: Vec<Relocation>,
+    /// Relocation types the image uses that can't be applied, once each in
+    /// ascending order. See [`LoadPlan::check_relocations`].
+    pub unsupported_relocations: Vec<u32>,
+    /// Link addresses covered by the `PT_GNU_RELRO` header, if there is
+    /// one: data that is only written by relocations.
+    pub relro: Option<Range<usize>>,
+    /// File offsets of the contents of each `PT_NOTE` header that lies
+    /// inside the file, for [`crate::note`].
+    pub notes: Vec<Range<usize>>,
+}
+
+impl LoadPlan {
+    /// Fail if the image needs relocations that are left out of
+    /// `relocations`, unless `strict` is off.
+    ///
+    /// A statically linked PIE only has `R_X86_64_RELATIVE` relocations, so
+    /// anything else usually means the kernel was linked wrong, and loading
+    /// it anyway leaves those words unpatched.
+    pub fn check_relocations(&self, strict: bool) -> Result<(), PlanError> {
+        if strict && !self.unsupported_relocations.is_empty() {
+            return Err(PlanError::UnsupportedRelocations(
+                self.unsupported_relocations.clone(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The image rounded out for allocation: `start` aligned down to
+    /// `align` and `end` up to a page boundary.
+    ///
+    /// The lowest segment need not start on a page boundary, so this is
+    /// what has to be allocated to cover `start..end`. Each segment is
+    /// still copied to its exact `vaddr` inside it.
+    pub fn aligned_range(&self, align: usize) -> Range<usize> {
+        self.start & !(align - 1)..self.end.next_multiple_of(PAGE_SIZE)
+    }
+
+    /// Check that the image can be loaded with
+    /// [`aligned_range(self.align)`](Self::aligned_range) starting at
+    /// `base`: it has to be relocatable, and `base` a multiple of
+    /// [`LoadPlan::align`] so that every segment stays aligned.
+    pub fn check_load_base(&self, base: u64) -> Result<(), PlanError> {
+        if !self.relocatable {
+            return Err(PlanError::FixedLoadBase);
+        }
+        if !base.is_multiple_of(self.align as u64) {
+            return Err(PlanError::MisalignedLoadBase {
+                base,
+                align: self.align,
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether the image is linked in the upper half of the address space,
+    /// like a kernel at `0xffffffff80000000`. Such an image can't be
+    /// allocated at its link address and has to be mapped there instead.
+    pub fn is_higher_half(&self) -> bool {
+        self.start as u64 >= HIGHER_HALF_START
+    }
+
+    /// The whole pages of [`LoadPlan::relro`] that can be made read-only.
+    ///
+    /// Both ends are rounded down, like the dynamic linker does: the linker
+    /// pads the region so that its end lands on a page boundary, and the
+    /// page it starts in holds nothing that is written later. Returns
+    /// `None` if there is no RELRO region or it doesn't span a page.
+    pub fn relro_pages(&self) -> Option<Range<usize>> {
+        let relro = self.relro.as_ref()?;
+        let pages = relro.start & !(PAGE_SIZE - 1)..relro.end & !(PAGE_SIZE - 1);
+        (!pages.is_empty()).then_some(pages)
+    }
+}
+
+/// Check that `header` describes an x86-64 executable this loader can jump
+/// into.
+pub fn validate_header(header: &Header) -> Result<(), PlanError> {
+    if header.e_ident[EI_CLASS] != ELFCLASS64 {
+        return Err(PlanError::Unsupported("not a 64-bit ELF"));
+    }
+    if header.e_machine != EM_X86_64 {
+        return Err(PlanError::Unsupported("not an x86-64 ELF"));
+    }
+    if !matches!(header.e_type, ET_EXEC | ET_DYN) {
+        return Err(PlanError::Unsupported("not an executable ELF"));
+    }
+    Ok(())
+}
+
+/// The address range `[start, end)` spanned by the PT_LOAD segments in
+/// `program_headers`.
+///
+/// Returns `(usize::MAX, 0)` if there are no PT_LOAD segments.
+pub fn image_range(program_headers: &[ProgramHeader]) -> (usize, usize) {
+    let mut addr_start = usize::MAX;
+    let mut addr_end = 0;
+    for phdr in program_headers {
+        if phdr.p_type != PT_LOAD {
+            continue;
+        }
+        addr_start = usize::min(addr_start, phdr.p_vaddr as usize);
+        addr_end = usize::max(addr_end, (phdr.p_vaddr + phdr.p_memsz) as usize);
+    }
+    (addr_start, addr_end)
+}
+
+/// The page ranges covering `segments`, as sorted `(start, end)` pairs.
+///
+/// Each segment is rounded out to page boundaries. Segments whose ranges
+/// overlap or touch, such as two segments sharing a page, are merged into
+/// one range; a gap of at least a page between segments is left out.
+pub fn page_ranges(segments: &[Segment], page_size: usize) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = segments
+        .iter()
+        .filter(|segment| segment.mem_size > 0)
+        .map(|segment| {
+            let start = segment.vaddr as usize & !(page_size - 1);
+            let end = segment.vaddr as usize + segment.mem_size;
+            (start, end.next_multiple_of(page_size))
+        })
+        .collect();
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some((_, last_end)) if start <= *last_end => *last_end = usize::max(*last_end, end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Split the pages in `range` into runs of code and data, as sorted
+/// `(pages, is_code)` pairs that cover all of it.
+///
+/// A page is code if any `PF_X` segment in `segments` overlaps it, even if
+/// it also holds part of a segment that isn't executable.
+pub fn code_runs(
+    segments: &[Segment],
+    range: Range<usize>,
+    page_size: usize,
+) -> Vec<(Range<usize>, bool)> {
+    let code: Vec<Segment> = segments
+        .iter()
+        .filter(|segment| segment.flags & PF_X != 0)
+        .cloned()
+        .collect();
+    let mut runs = Vec::new();
+    let mut pos = range.start;
+    for (start, end) in page_ranges(&code, page_size) {
+        let start = start.clamp(range.start, range.end);
+        let end = end.clamp(range.start, range.end);
+        if start >= end {
+            continue;
+        }
+        if pos < start {
+            runs.push((pos..start, false));
+        }
+        runs.push((start..end, true));
+        pos = end;
+    }
+    if pos < range.end {
+        runs.push((pos..range.end, false));
+    }
+    runs
+}
+
+/// The pages of the segments in `segments` that lack `flag`, as sorted
+/// page ranges, leaving out every page that a segment with `flag` also
+/// touches.
+///
+/// With `PF_X` these are the pages that can be made non-executable, with
+/// `PF_W` the ones that can be made read-only, without taking the access
+/// from a segment that shares a page with them.
+pub fn pages_without(segments: &[Segment], flag: u32, page_size: usize) -> Vec<Range<usize>> {
+    let (with, without): (Vec<Segment>, Vec<Segment>) = segments
+        .iter()
+        .cloned()
+        .partition(|segment| segment.flags & flag != 0);
+    let keep = page_ranges(&with, page_size);
+    let mut pages = Vec::new();
+    for (start, end) in page_ranges(&without, page_size) {
+        let mut pos = start;
+        for &(keep_start, keep_end) in &keep {
+            if keep_end <= pos || keep_start >= end {
+                continue;
+            }
+            if pos < keep_start {
+                pages.push(pos..keep_start);
+            }
+            pos = keep_end;
+        }
+        if pos < end {
+            pages.push(pos..end);
+        }
+    }
+    pages
+}
+
+/// Zero all of `image`, the memory for the link addresses from
+/// `image_start` on, then fill each of `segments` that lies inside it with
+/// `copy`. Segments outside `image` are left to another call.
+///
+/// Zeroing everything first means the gaps between segments are clean, not
+/// just each segment's bss. `copy` gets the segment and the `mem_size`
+/// bytes it goes to.
+pub fn fill_image<E>(
+    image: &mut [u8],
+    image_start: u64,
+    segments: &[Segment],
+    mut copy: impl FnMut(&Segment, &mut [u8]) -> Result<(), E>,
+) -> Result<(), E> {
+    image.fill(0);
+    for segment in segments {
+        let offset = segment.vaddr.wrapping_sub(image_start) as usize;
+        if let Some(dest) = image.get_mut(offset..offset.saturating_add(segment.mem_size))
+            && segment.vaddr >= image_start
+        {
+            copy(segment, dest)?;
+        }
+    }
+    Ok(())
+}
+
+/// Parse `data` as an ELF kernel and work out how to load it.
+pub fn plan(data: &[u8]) -> Result<LoadPlan, PlanError> {
+    let prog = Elf::parse(data)?;
+    validate_header(&prog.header)?;
+    let mut plan = plan_segments(&prog.header, &prog.program_headers, data.len())?;
+
+    // Only `R_X86_64_RELATIVE` is supported, which is all a statically
+    // linked PIE needs. The types of anything else are collected so the
+    // caller can reject the image rather than silently leave it
+    // unrelocated.
+    if plan.relocatable {
+        for rela in prog.dynrelas.iter().chain(prog.pltrelocs.iter()) {
+            match rela.r_type {
+                R_X86_64_NONE => {}
+                R_X86_64_RELATIVE => {
+                    let offset = rela.r_offset as usize;
+                    let in_image = offset >= plan.start
+                        && offset
+                            .checked_add(size_of::<u64>())
+                            .is_some_and(|end| end <= plan.end);
+                    if !in_image {
+                        return Err(PlanError::Unsupported("relocation outside the image"));
+                    }
+                    plan.relocations.push(Relocation {
+                        offset: rela.r_offset,
+                        addend: rela.r_addend.unwrap_or(0),
+                    });
+                }
+                ty => {
+                    if let Err(i) = plan.unsupported_relocations.binary_search(&ty) {
+                        plan.unsupported_relocations.insert(i, ty);
+                    }
+                }
+            }
+        }
+    }
+    Ok(plan)
+}
+
+/// Parse the ELF header at the start of `bytes`, which must hold at least
+/// [`HEADER_SIZE`] bytes, and check it with [`validate_header`].
+pub fn parse_header(bytes: &[u8]) -> Result<Header, PlanError> {
+    let header = Elf::parse_header(bytes)?;
+    validate_header(&header)?;
+    Ok(header)
+}
+
+/// Work out how to load a fixed-address executable from its `header` and
+/// the raw program header table `phdr_bytes`, read from offset
+/// `header.e_phoff` of a file of `file_len` bytes.
+///
+/// This lets the loader copy segments straight from the file without
+/// reading all of it first. Relocatable images are rejected, since finding
+/// their relocations needs the whole file; use [`plan`] for those.
+pub fn plan_from_headers(
+    header: &Header,
+    phdr_bytes: &[u8],
+    file_len: usize,
+) -> Result<LoadPlan, PlanError> {
+    if header.e_type == ET_DYN {
+        return Err(PlanError::Unsupported(
+            "relocatable images need the whole file",
+        ));
+    }
+    let ctx = Ctx::new(header.container()?, header.endianness()?);
+    let program_headers = ProgramHeader::parse(phdr_bytes, 0, header.e_phnum as usize, ctx)?;
+    plan_segments(header, &program_headers, file_len)
+}
+
+/// The part of a [`LoadPlan`] that only needs the headers: everything but
+/// the relocations.
+fn plan_segments(
+    header: &Header,
+    program_headers: &[ProgramHeader],
+    file_len: usize,
+) -> Result<LoadPlan, PlanError> {
+    let relocatable = header.e_type == ET_DYN;
+    // Crafted headers could otherwise overflow the address arithmetic
+    // below and panic instead of failing cleanly.
+    let wraps = program_headers
+        .iter()
+        .any(|phdr| phdr.p_type == PT_LOAD && phdr.p_vaddr.checked_add(phdr.p_memsz).is_none());
+    if wraps {
+        return Err(PlanError::Unsupported(
+            "segment wraps around the address space",
+        ));
+    }
+    let (start, end) = image_range(program_headers);
+    // Without anything to load the range is empty or inverted, and every
+    // size computed from it would wrap.
+    if start >= end {
+        return Err(PlanError::Unsupported("no loadable segments"));
+    }
+
+    // The entry point must land in code we are about to load.
+    let entry = header.e_entry;
+    let entry_executable = program_headers.iter().any(|phdr| {
+        phdr.p_type == PT_LOAD
+            && phdr.p_flags & PF_X != 0
+            && (phdr.p_vaddr..phdr.p_vaddr + phdr.p_memsz).contains(&entry)
+    });
+    if !(start..end).contains(&(entry as usize)) || !entry_executable {
+        return Err(PlanError::BadEntry(entry));
+    }
+
+    let relro = match program_headers
+        .iter()
+        .find(|phdr| phdr.p_type == PT_GNU_RELRO)
+    {
+        Some(phdr) => {
+            let end = phdr
+                .p_vaddr
+                .checked_add(phdr.p_memsz)
+                .ok_or(PlanError::Unsupported(
+                    "RELRO wraps around the address space",
+                ))?;
+            Some(phdr.p_vaddr as usize..end as usize)
+        }
+        None => None,
+    };
+    let notes = program_headers
+        .iter()
+        .filter(|phdr| phdr.p_type == PT_NOTE)
+        .filter_map(|phdr| {
+            let start = phdr.p_offset as usize;
+            let end = start.checked_add(phdr.p_filesz as usize)?;
+            (end <= file_len).then_some(start..end)
+        })
+        .collect();
+
+    let mut align = PAGE_SIZE;
+    let mut segments = Vec::new();
+    for phdr in program_headers {
+        if phdr.p_type != PT_LOAD {
+            continue;
+        }
+        // Every segment must sit at the same offset from an alignment
+        // boundary in memory as it does in the file.
+        if phdr.p_align > 1 {
+            if !phdr.p_align.is_power_of_two() {
+                return Err(PlanError::Unsupported(
+                    "segment alignment is not a power of two",
+                ));
+            }
+            if phdr.p_vaddr % phdr.p_align != phdr.p_offset % phdr.p_align {
+                return Err(PlanError::Unsupported(
+                    "segment address and offset disagree",
+                ));
+            }
+            align = usize::max(align, phdr.p_align as usize);
+        }
+        if phdr.p_filesz > phdr.p_memsz {
+            return Err(PlanError::Unsupported(
+                "segment is larger in the file than in memory",
+            ));
+        }
+        if phdr.p_offset.saturating_add(phdr.p_filesz) > file_len as u64 {
+            return Err(PlanError::Unsupported("segment extends past the file"));
+        }
+        segments.push(Segment {
+            offset: phdr.p_offset as usize,
+            file_size: phdr.p_filesz as usize,
+            mem_size: phdr.p_memsz as usize,
+            vaddr: phdr.p_vaddr,
+            flags: phdr.p_flags,
+        });
+    }
+    // Sharing a page is fine, but two segments claiming the same byte
+    // would have the later copy clobber the earlier one.
+    let mut by_address: Vec<&Segment> = segments.iter().filter(|s| s.mem_size > 0).collect();
+    by_address.sort_by_key(|segment| segment.vaddr);
+    let overlaps = by_address
+        .windows(2)
+        .any(|pair| pair[0].vaddr + pair[0].mem_size as u64 > pair[1].vaddr);
+    if overlaps {
+        return Err(PlanError::Unsupported("loadable segments overlap"));
+    }
+
+    Ok(LoadPlan {
+        relocatable,
+        entry,
+        start,
+        end,
+        align,
+        segments,
+        relocations: Vec::new(),
+        unsupported_relocations: Vec::new(),
+        relro,
+        notes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+    use goblin::elf::header::EM_AARCH64;
+    use goblin::elf::reloc::{R_X86_64_GLOB_DAT, R_X86_64_JUMP_SLOT};
+
+    use goblin::elf::program_header::PT_INTERP;
+
+    use super::*;
+
+    fn phdr(p_type: u32, p_vaddr: u64, p_memsz: u64) -> ProgramHeader {
+        ProgramHeader {
+            p_type,
+            p_vaddr,
+            p_memsz,
+            ..Default::default()
+        }
+    }
+
+    fn segment(vaddr: u64, mem_size: usize) -> Segment {
+        Segment {
+            offset: 0,
+            file_size: 0,
+            mem_size,
+            vaddr,
+            flags: 0,
+        }
+    }
+
+    #[test]
+    fn single_segment() {
+        let phdrs = [phdr(PT_LOAD, 0x100000, 0x2345)];
+        assert_eq!(image_range(&phdrs), (0x100000, 0x102345));
+    }
+
+    #[test]
+    fn spans_all_segments_in_any_order() {
+        let phdrs = [
+            phdr(PT_LOAD, 0x200000, 0x1000),
+            phdr(PT_LOAD, 0x100000, 0x800),
+            phdr(PT_LOAD, 0x180000, 0x10000),
+        ];
+        assert_eq!(image_range(&phdrs), (0x100000, 0x201000));
+    }
+
+    #[test]
+    fn ignores_non_load_segments() {
+        let phdrs = [
+            phdr(PT_NOTE, 0x1000, 0x10),
+            phdr(PT_LOAD, 0x100000, 0x1000),
+            phdr(PT_INTERP, 0x900000, 0x10),
+        ];
+        assert_eq!(image_range(&phdrs), (0x100000, 0x101000));
+    }
+
+    #[test]
+    fn memsz_beyond_filesz_counts() {
+        let phdrs = [ProgramHeader {
+            p_filesz: 0x100,
+            ..phdr(PT_LOAD, 0x100000, 0x5000)
+        }];
+        assert_eq!(image_range(&phdrs), (0x100000, 0x105000));
+    }
+
+    #[test]
+    fn no_load_segments() {
+        let phdrs = [phdr(PT_NOTE, 0x1000, 0x10)];
+        assert_eq!(image_range(&phdrs), (usize::MAX, 0));
+    }
+
+    #[test]
+    fn rounds_relocatable_start_to_a_page() {
+        // Segment alignment below a page must not make the allocation start
+        // off a page boundary.
+        let phdrs = [ProgramHeader {
+            p_align: 0x10,
+            ..load(0x40, 0x2040, 0x100, 0x100, PF_X | PF_R)
+        }];
+        let data = build_elf(ET_DYN, EM_X86_64, 0x2040, &phdrs, 0x1000);
+        let plan = plan(&data).unwrap();
+        assert!(plan.relocatable);
+        assert_eq!(plan.align, PAGE_SIZE);
+        assert_eq!(plan.aligned_range(plan.align), 0x2000..0x3000);
+    }
+
+    #[test]
+    fn page_ranges_leave_out_gaps() {
+        let segments = [segment(0x100000, 0x1800), segment(0x40000000, 0x10)];
+        assert_eq!(
+            page_ranges(&segments, PAGE_SIZE),
+            [(0x100000, 0x102000), (0x40000000, 0x40001000)]
+        );
+    }
+
+    #[test]
+    fn page_ranges_merge_shared_pages() {
+        let segments = [segment(0x101800, 0x1000), segment(0x100000, 0x1234)];
+        assert_eq!(page_ranges(&segments, PAGE_SIZE), [(0x100000, 0x103000)]);
+    }
+
+    #[test]
+    fn splits_code_from_data() {
+        let text = Segment {
+            flags: PF_R | PF_X,
+            ..segment(0x100000, 0x1800)
+        };
+        let rodata = Segment {
+            flags: PF_R,
+            ..segment(0x101800, 0x1000)
+        };
+        let data = Segment {
+            flags: PF_R | PF_W,
+            ..segment(0x104000, 0x1000)
+        };
+        let segments = [data, rodata, text];
+        assert_eq!(
+            code_runs(&segments, 0x100000..0x105000, PAGE_SIZE),
+            [(0x100000..0x102000, true), (0x102000..0x105000, false)]
+        );
+        // Only the part of a code page range inside `range` counts.
+        assert_eq!(
+            code_runs(&segments, 0x101000..0x103000, PAGE_SIZE),
+            [(0x101000..0x102000, true), (0x102000..0x103000, false)]
+        );
+        assert_eq!(
+            code_runs(&segments[..1], 0x104000..0x105000, PAGE_SIZE),
+            [(0x104000..0x105000, false)]
+        );
+    }
+
+    #[test]
+    fn finds_pages_without_a_flag() {
+        let text = Segment {
+            flags: PF_R | PF_X,
+            ..segment(0x100000, 0x1800)
+        };
+        let rodata = Segment {
+            flags: PF_R,
+            ..segment(0x101800, 0x1000)
+        };
+        let data = Segment {
+            flags: PF_R | PF_W,
+            ..segment(0x104000, 0x3000)
+        };
+        let tail = Segment {
+            flags: PF_R | PF_X,
+            ..segment(0x105000, 0x800)
+        };
+        let segments = [data, rodata, text, tail];
+        // The page shared by .text and .rodata stays executable, and so
+        // does the one in the middle of .data that holds code.
+        assert_eq!(
+            pages_without(&segments, PF_X, PAGE_SIZE),
+            [0x102000..0x103000, 0x104000..0x105000, 0x106000..0x107000]
+        );
+        // .text and .rodata up to the first page of .data.
+        let read_only = 0x100000..0x103000;
+        assert_eq!(pages_without(&segments, PF_W, PAGE_SIZE), [read_only]);
+        assert!(pages_without(&segments[..1], PF_W, PAGE_SIZE).is_empty());
+    }
+
+    #[test]
+    fn page_ranges_skip_empty_segments() {
+        let segments = [segment(0x100000, 0)];
+        assert!(page_ranges(&segments, PAGE_SIZE).is_empty());
+    }
+
+    /// A little-endian ELF64 file with `phdrs` right after the header,
+    /// padded with zeros to `len` bytes.
+    fn build_elf(
+        e_type: u16,
+        e_machine: u16,
+        entry: u64,
+        phdrs: &[ProgramHeader],
+        len: usize,
+    ) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        out.extend_from_slice(&[0; 8]);
+        out.extend_from_slice(&e_type.to_le_bytes());
+        out.extend_from_slice(&e_machine.to_le_bytes());
+        out.extend_from_slice(&1u32.to_le_bytes());
+        out.extend_from_slice(&entry.to_le_bytes());
+        out.extend_from_slice(&64u64.to_le_bytes()); // e_phoff
+        out.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+        out.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        out.extend_from_slice(&64u16.to_le_bytes()); // e_ehsize
+        out.extend_from_slice(&56u16.to_le_bytes()); // e_phentsize
+        out.extend_from_slice(&(phdrs.len() as u16).to_le_bytes());
+        out.extend_from_slice(&64u16.to_le_bytes()); // e_shentsize
+        out.extend_from_slice(&0u16.to_le_bytes()); // e_shnum
+        out.extend_from_slice(&0u16.to_le_bytes()); // e_shstrndx
+        for phdr in phdrs {
+            out.extend_from_slice(&phdr.p_type.to_le_bytes());
+            out.extend_from_slice(&phdr.p_flags.to_le_bytes());
+            for field in [
+                phdr.p_offset,
+                phdr.p_vaddr,
+                phdr.p_paddr,
+                phdr.p_filesz,
+                phdr.p_memsz,
+                phdr.p_align,
+            ] {
+                out.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+        out.resize(usize::max(out.len(), len), 0);
+        out
+    }
+
+    fn load(offset: u64, vaddr: u64, filesz: u64, memsz: u64, flags: u32) -> ProgramHeader {
+        ProgramHeader {
+            p_type: PT_LOAD,
+            p_flags: flags,
+            p_offset: offset,
+            p_vaddr: vaddr,
+            p_paddr: vaddr,
+            p_filesz: filesz,
+            p_memsz: memsz,
+            p_align: 0x1000,
+        }
+    }
+
+    #[test]
+    fn rounds_relro_down_to_pages() {
+        let phdrs = [
+            load(0, 0x100000, 0x100, 0x100, PF_X | PF_R),
+            load(0x1f00, 0x101f00, 0x1100, 0x1100, PF_R | PF_W),
+            ProgramHeader {
+                p_type: PT_GNU_RELRO,
+                ..load(0x1f00, 0x101f00, 0x1100, 0x1100, PF_R)
+            },
+        ];
+        let data = build_elf(ET_EXEC, EM_X86_64, 0x100000, &phdrs, 0x3000);
+        let padded = plan(&data).unwrap();
+        assert_eq!(padded.relro, Some(0x101f00..0x103000));
+        assert_eq!(padded.relro_pages(), Some(0x101000..0x103000));
+
+        let phdrs = [
+            load(0, 0x100000, 0x100, 0x100, PF_X | PF_R),
+            ProgramHeader {
+                p_type: PT_GNU_RELRO,
+                ..load(0x10, 0x100010, 0x80, 0x80, PF_R)
+            },
+        ];
+        let data = build_elf(ET_EXEC, EM_X86_64, 0x100000, &phdrs, 0x1000);
+        assert_eq!(plan(&data).unwrap().relro_pages(), None);
+    }
+
+    #[test]
+    fn covers_unaligned_start() {
+        let phdrs = [load(0x40, 0x100040, 0x100, 0x2000, PF_X | PF_R)];
+        let data = build_elf(ET_EXEC, EM_X86_64, 0x100040, &phdrs, 0x1000);
+        let plan = plan(&data).unwrap();
+        assert_eq!((plan.start, plan.end), (0x100040, 0x102040));
+        assert_eq!(plan.aligned_range(PAGE_SIZE), 0x100000..0x103000);
+        assert_eq!(
+            page_ranges(&plan.segments, PAGE_SIZE),
+            [(0x100000, 0x103000)]
+        );
+
+        let mut image = alloc::vec![0xaa; 0x3000];
+        let offset = plan.segments[0].vaddr as usize - 0x100000;
+        plan.segments[0].copy_to(&data, &mut image[offset..offset + 0x2000]);
+        assert_eq!(image[..0x40], [0xaa; 0x40]);
+        assert_eq!(image[0x40..0x140], data[0x40..0x140]);
+        assert!(image[0x140..0x2040].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn zeroes_gaps_between_segments() {
+        let phdrs = [
+            load(0x1000, 0x101000, 0x800, 0x1000, PF_X | PF_R),
+            load(0x2000, 0x105000, 0x100, 0x100, PF_R | PF_W),
+        ];
+        let data = build_elf(ET_DYN, EM_X86_64, 0x101000, &phdrs, 0x3000);
+        let plan = plan(&data).unwrap();
+        let range = plan.aligned_range(PAGE_SIZE);
+        assert_eq!(range, 0x101000..0x106000);
+
+        let mut image = alloc::vec![0xaa; range.len()];
+        let mut copied = 0;
+        fill_image::<()>(
+            &mut image,
+            range.start as u64,
+            &plan.segments,
+            |segment, dest| {
+                segment.copy_to(&data, dest);
+                copied += 1;
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(copied, 2);
+        assert_eq!(image[..0x800], data[0x1000..0x1800]);
+        // The 0x3000 between the end of the first segment and the start of
+        // the second.
+        assert!(image[0x1000..0x4000].iter().all(|&b| b == 0));
+        assert_eq!(image[0x4000..0x4100], data[0x2000..0x2100]);
+        assert!(image[0x4100..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn detects_higher_half() {
+        let phdrs = [load(0x1000, 0xffffffff80001000, 0x800, 0x800, PF_X | PF_R)];
+        let data = build_elf(ET_EXEC, EM_X86_64, 0xffffffff80001010, &phdrs, 0x2000);
+        let high = plan(&data).unwrap();
+        assert!(high.is_higher_half());
+        assert_eq!(high.start, 0xffffffff80001000);
+
+        let phdrs = [load(0x1000, 0x101000, 0x800, 0x800, PF_X | PF_R)];
+        let data = build_elf(ET_EXEC, EM_X86_64, 0x101010, &phdrs, 0x2000);
+        assert!(!plan(&data).unwrap().is_higher_half());
+    }
+
+    #[test]
+    fn plans_executable() {
+        let phdrs = [
+            load(0x1000, 0x101000, 0x800, 0x800, PF_X | PF_R),
+            load(0x2000, 0x202000, 0x100, 0x3000, PF_R | PF_W),
+        ];
+        let data = build_elf(ET_EXEC, EM_X86_64, 0x101010, &phdrs, 0x3000);
+        let plan = plan(&data).unwrap();
+        assert!(!plan.relocatable);
+        assert_eq!(plan.entry, 0x101010);
+        assert_eq!((plan.start, plan.end), (0x101000, 0x205000));
+        assert_eq!(plan.align, 0x1000);
+        assert_eq!(
+            plan.segments,
+            [
+                Segment {
+                    offset: 0x1000,
+                    file_size: 0x800,
+                    mem_size: 0x800,
+                    vaddr: 0x101000,
+                    flags: PF_X | PF_R,
+                },
+                Segment {
+                    offset: 0x2000,
+                    file_size: 0x100,
+                    mem_size: 0x3000,
+                    vaddr: 0x202000,
+                    flags: PF_R | PF_W,
+                },
+            ]
+        );
+        assert!(plan.relocations.is_empty());
+    }
+
+    #[test]
+    fn plans_from_headers_alone() {
+        let phdrs = [
+            load(0x1000, 0x101000, 0x800, 0x800, PF_R | PF_X),
+            load(0x2000, 0x102000, 0x100, 0x3000, PF_R | PF_W),
+        ];
+        let data = build_elf(ET_EXEC, EM_X86_64, 0x101000, &phdrs, 0x3000);
+        let header = parse_header(&data[..HEADER_SIZE]).unwrap();
+        let phoff = header.e_phoff as usize;
+        let phdr_bytes =
+            &data[phoff..phoff + header.e_phnum as usize * header.e_phentsize as usize];
+        let from_headers = plan_from_headers(&header, phdr_bytes, data.len()).unwrap();
+        let full = plan(&data).unwrap();
+        assert_eq!(from_headers.segments, full.segments);
+        assert_eq!(from_headers.entry, full.entry);
+        assert_eq!(
+            (from_headers.start, from_headers.end),
+            (full.start, full.end)
+        );
+    }
+
+    #[test]
+    fn plan_from_headers_rejects_relocatable() {
+        let phdrs = [load(0x1000, 0x1000, 0x800, 0x800, PF_R | PF_X)];
+        let data = build_elf(ET_DYN, EM_X86_64, 0x1000, &phdrs, 0x2000);
+        let header = parse_header(&data).unwrap();
+        let phdr_bytes = &data[header.e_phoff as usize..];
+        assert!(matches!(
+            plan_from_headers(&header, phdr_bytes, data.len()),
+            Err(PlanError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn checks_load_base() {
+        let phdrs = [
+            load(0x1000, 0x1000, 0x800, 0x800, PF_R | PF_X),
+            ProgramHeader {
+                p_align: 0x20_0000,
+                ..load(0x1800, 0x20_1800, 0x100, 0x100, PF_R | PF_W)
+            },
+        ];
+        let data = build_elf(ET_DYN, EM_X86_64, 0x1000, &phdrs, 0x2000);
+        let relocatable = plan(&data).unwrap();
+        assert!(relocatable.check_load_base(0x4000_0000).is_ok());
+        assert!(matches!(
+            relocatable.check_load_base(0x4000_1000),
+            Err(PlanError::MisalignedLoadBase {
+                base: 0x4000_1000,
+                align: 0x20_0000
+            })
+        ));
+
+        let phdrs = [load(0x1000, 0x101000, 0x800, 0x800, PF_R | PF_X)];
+        let data = build_elf(ET_EXEC, EM_X86_64, 0x101000, &phdrs, 0x2000);
+        assert!(matches!(
+            plan(&data).unwrap().check_load_base(0x4000_0000),
+            Err(PlanError::FixedLoadBase)
+        ));
+    }
+
+    #[test]
+    fn strict_mode_rejects_unsupported_relocations() {
+        let phdrs = [load(0x1000, 0x1000, 0x800, 0x800, PF_R | PF_X)];
+        let data = build_elf(ET_DYN, EM_X86_64, 0x1000, &phdrs, 0x2000);
+        let mut plan = plan(&data).unwrap();
+        assert!(plan.check_relocations(true).is_ok());
+
+        plan.unsupported_relocations = alloc::vec![R_X86_64_GLOB_DAT, R_X86_64_JUMP_SLOT, 99];
+        assert!(plan.check_relocations(false).is_ok());
+        let err = plan.check_relocations(true).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unsupported relocation types R_X86_64_GLOB_DAT, R_X86_64_JUMP_SLOT, 99"
+        );
+    }
+
+    #[test]
+    fn rejects_entry_outside_code() {
+        let phdrs = [
+            load(0x1000, 0x101000, 0x800, 0x800, PF_X | PF_R),
+            load(0x2000, 0x202000, 0x100, 0x100, PF_R | PF_W),
+        ];
+        let data = build_elf(ET_EXEC, EM_X86_64, 0x202000, &phdrs, 0x3000);
+        assert!(matches!(plan(&data), Err(PlanError::BadEntry(0x202000))));
+    }
+
+    #[test]
+    fn rejects_other_machines() {
+        let phdrs = [load(0x1000, 0x101000, 0x800, 0x800, PF_X)];
+        let data = build_elf(ET_EXEC, EM_AARCH64, 0x101000, &phdrs, 0x2000);
+        assert!(matches!(plan(&data), Err(PlanError::Unsupported(_))));
+    }
+
+    #[test]
+    fn rejects_segment_past_end_of_file() {
+        let phdrs = [load(0x1000, 0x101000, 0x800, 0x800, PF_X)];
+        let data = build_elf(ET_EXEC, EM_X86_64, 0x101000, &phdrs, 0x1400);
+        assert!(matches!(plan(&data), Err(PlanError::Unsupported(_))));
+
+        // Starting past the end, with an offset that would overflow.
+        for offset in [0x10_0000, !0xfff] {
+            let phdrs = [load(offset, 0x101000, 0x800, 0x800, PF_X)];
+            let data = build_elf(ET_EXEC, EM_X86_64, 0x101000, &phdrs, 0x2000);
+            assert!(matches!(
+                plan(&data),
+                Err(PlanError::Unsupported("segment extends past the file"))
+            ));
+        }
+    }
+
+    #[test]
+    fn rejects_segment_wrapping_address_space() {
+        let phdrs = [load(0x1000, u64::MAX - 0xfff, 0x800, 0x2000, PF_X)];
+        let data = build_elf(ET_EXEC, EM_X86_64, u64::MAX - 0xfff, &phdrs, 0x2000);
+        assert!(matches!(
+            plan(&data),
+            Err(PlanError::Unsupported(
+                "segment wraps around the address space"
+            ))
+        ));
+    }
+
+    #[test]
+    fn rejects_overlapping_segments() {
+        let phdrs = [
+            load(0x1000, 0x101000, 0x800, 0x800, PF_X),
+            load(0x1700, 0x101700, 0x800, 0x800, PF_W),
+        ];
+        let data = build_elf(ET_EXEC, EM_X86_64, 0x101000, &phdrs, 0x2000);
+        assert!(matches!(
+            plan(&data),
+            Err(PlanError::Unsupported("loadable segments overlap"))
+        ));
+
+        // Back to back in one page is fine.
+        let phdrs = [
+            load(0x1000, 0x101000, 0x800, 0x800, PF_X),
+            load(0x1800, 0x101800, 0x800, 0x800, PF_W),
+        ];
+        let data = build_elf(ET_EXEC, EM_X86_64, 0x101000, &phdrs, 0x2000);
+        assert!(plan(&data).is_ok());
+    }
+
+    #[test]
+    fn rejects_wrapping_relro() {
+        let relro = ProgramHeader {
+            p_type: PT_GNU_RELRO,
+            p_vaddr: 0x101000,
+            p_memsz: u64::MAX,
+            ..Default::default()
+        };
+        let phdrs = [load(0x1000, 0x101000, 0x800, 0x800, PF_X), relro];
+        let data = build_elf(ET_EXEC, EM_X86_64, 0x101000, &phdrs, 0x2000);
+        assert!(matches!(
+            plan(&data),
+            Err(PlanError::Unsupported(
+                "RELRO wraps around the address space"
+            ))
+        ));
+    }
+
+    #[test]
+    fn rejects_misaligned_segment() {
+        let phdrs = [load(0x1000, 0x101800, 0x800, 0x800, PF_X)];
+        let data = build_elf(ET_EXEC, EM_X86_64, 0x101800, &phdrs, 0x2000);
+        assert!(matches!(plan(&data), Err(PlanError::Unsupported(_))));
+    }
+
+    #[test]
+    fn zeroes_bss_spanning_pages() {
+        let data = [0x55; 0x100];
+        let segment = Segment {
+            offset: 0x80,
+            file_size: 0x20,
+            mem_size: 0x2800,
+            vaddr: 0x100f00,
+            flags: PF_R | PF_W,
+        };
+        // Four pages starting at 0x100000, filled with garbage.
+        let mut memory = alloc::vec![0xaa; 0x4000];
+        segment.copy_to(&data, &mut memory[0xf00..0x3700]);
+        assert!(memory[..0xf00].iter().all(|&b| b == 0xaa));
+        assert!(memory[0xf00..0xf20].iter().all(|&b| b == 0x55));
+        assert!(memory[0xf20..0x3700].iter().all(|&b| b == 0));
+        assert!(memory[0x3700..].iter().all(|&b| b == 0xaa));
+
+        // The allocated pages reach past the end of the zeroed range.
+        let ranges = page_ranges(core::slice::from_ref(&segment), 0x1000);
+        assert_eq!(ranges, [(0x100000, 0x104000)]);
+        assert!(ranges[0].1 as u64 >= segment.vaddr + segment.mem_size as u64);
+    }
+
+    #[test]
+    fn rejects_image_without_load_segments() {
+        let data = build_elf(ET_EXEC, EM_X86_64, 0x101000, &[], 0x1000);
+        assert!(matches!(
+            plan(&data),
+            Err(PlanError::Unsupported("no loadable segments"))
+        ));
+        let phdrs = [ProgramHeader {
+            p_type: PT_NOTE,
+            p_offset: 0x800,
+            p_filesz: 0x20,
+            ..Default::default()
+        }];
+        let data = build_elf(ET_DYN, EM_X86_64, 0, &phdrs, 0x1000);
+        assert!(matches!(
+            plan(&data),
+            Err(PlanError::Unsupported("no loadable segments"))
+        ));
+    }
+
+    #[test]
+    fn formats_segment_table() {
+        let segments = [
+            Segment {
+                offset: 0x1000,
+                file_size: 0x800,
+                mem_size: 0x800,
+                vaddr: 0x101000,
+                flags: PF_R | PF_X,
+            },
+            Segment {
+                offset: 0x2000,
+                file_size: 0x100,
+                mem_size: 0x3000,
+                vaddr: 0x102000,
+                flags: PF_R | PF_W,
+            },
+        ];
+        assert_eq!(
+            SegmentTable(&segments).to_string(),
+            "vaddr               offset      filesz      memsz       flags\n\
+             0x0000000000101000  0x00001000  0x00000800  0x00000800  R-X\n\
+             0x0000000000102000  0x00002000  0x00000100  0x00003000  RW-"
+        );
+        assert_eq!(SegmentFlags(PF_R | PF_W | PF_X).to_string(), "RWX");
+        assert_eq!(SegmentFlags(0).to_string(), "---");
+    }
+
+    #[test]
+    fn rejects_non_elf() {
+        assert!(matches!(plan(b"not an elf file"), Err(PlanError::Parse(_))));
+    }
+}
@@ -0,0 +1,49 @@
+//! Picking a GOP mode, apart from the protocol so that it can be tested.
+
+use mikanos_rs_common::boot_info::PixelFormat;
+
+/// The index of the first of `modes`, given as `(width, height, format)`,
+/// that has the `preferred` resolution and a framebuffer the kernel can
+/// draw to, or `None` if there is no such mode.
+///
+/// `BltOnly` modes, which have no framebuffer, are passed as
+/// [`PixelFormat::Unknown`] and never picked.
+pub fn pick_mode(
+    modes: &[(usize, usize, PixelFormat)],
+    preferred: (usize, usize),
+) -> Option<usize> {
+    modes.iter().position(|&(width, height, format)| {
+        (width, height) == preferred && format != PixelFormat::Unknown
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODES: [(usize, usize, PixelFormat); 4] = [
+        (640, 480, PixelFormat::Bgr),
+        (1280, 720, PixelFormat::Unknown),
+        (1280, 720, PixelFormat::Bgr),
+        (1920, 1080, PixelFormat::Bitmask),
+    ];
+
+    #[test]
+    fn picks_exact_resolution() {
+        assert_eq!(pick_mode(&MODES, (640, 480)), Some(0));
+        assert_eq!(pick_mode(&MODES, (1920, 1080)), Some(3));
+    }
+
+    #[test]
+    fn keeps_current_mode_without_a_match() {
+        assert_eq!(pick_mode(&MODES, (1024, 768)), None);
+        assert_eq!(pick_mode(&MODES, (480, 640)), None);
+        assert_eq!(pick_mode(&[], (640, 480)), None);
+    }
+
+    #[test]
+    fn skips_blt_only_modes() {
+        assert_eq!(pick_mode(&MODES, (1280, 720)), Some(2));
+        assert_eq!(pick_mode(&MODES[..2], (1280, 720)), None);
+    }
+}
@@ -0,0 +1,127 @@
+//! A readable dump of the `BootInfo` the kernel is started with, for
+//! debugging the handoff.
+
+use core::fmt;
+
+use mikanos_rs_common::boot_info::{BootInfo, MemoryRegion};
+
+use crate::note::Hex;
+
+/// Formats a [`BootInfo`] as one labelled line per field, with addresses in
+/// hex. Only the pointers and sizes are shown, nothing they point to.
+pub struct BootInfoDump<'a>(pub &'a BootInfo);
+
+/// Formats a [`MemoryRegion`] as its address range, or `none` if it is
+/// empty.
+struct Region(MemoryRegion);
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let MemoryRegion { base, size } = self.0;
+        if base.is_null() {
+            return write!(f, "none");
+        }
+        let start = base as u64;
+        write!(f, "{:#x}..{:#x}", start, start + size as u64)
+    }
+}
+
+impl fmt::Display for BootInfoDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let info = self.0;
+        let fb = &info.framebuffer;
+        let map = &info.memory_map;
+        writeln!(f, "BootInfo at {:p}:", info)?;
+        writeln!(
+            f,
+            "  framebuffer:        {:p}, {}x{} (stride {}), {:?}, {} bytes",
+            fb.base,
+            fb.horizontal_resolution,
+            fb.vertical_resolution,
+            fb.pixels_per_scan_line,
+            fb.pixel_format,
+            fb.size
+        )?;
+        writeln!(
+            f,
+            "  memory map:         {:p}, {} descriptors of {} bytes",
+            map.buffer, map.descriptor_count, map.descriptor_size
+        )?;
+        writeln!(
+            f,
+            "  memory descriptors: {:p}, {} entries",
+            info.memory_descriptors.entries, info.memory_descriptors.count
+        )?;
+        writeln!(
+            f,
+            "  rsdp:               {:p} (revision {})",
+            info.rsdp, info.rsdp_revision
+        )?;
+        writeln!(f, "  smbios:             {:p}", info.smbios)?;
+        writeln!(f, "  runtime services:   {:p}", info.runtime_services)?;
+        writeln!(
+            f,
+            "  config tables:      {:p}, {} entries",
+            info.config_tables.entries, info.config_tables.count
+        )?;
+        writeln!(f, "  initrd:             {}", Region(info.initrd))?;
+        writeln!(f, "  archive:            {}", Region(info.archive))?;
+        writeln!(f, "  cmdline:            {}", Region(info.cmdline))?;
+        writeln!(
+            f,
+            "  font:               {:p}, {} bytes",
+            info.font.base, info.font.size
+        )?;
+        writeln!(f, "  modules:            {}", info.module_count)?;
+        writeln!(f, "  app entry:          {:#x}", info.app.entry)?;
+        writeln!(f, "  kernel stack:       {}", Region(info.kernel_stack))?;
+        writeln!(f, "  back buffer:        {}", Region(info.back_buffer))?;
+        writeln!(f, "  largest free:       {}", Region(info.largest_free))?;
+        writeln!(f, "  usable RAM:         {} bytes", info.usable_ram_bytes)?;
+        writeln!(f, "  phys mem offset:    {:#x}", info.phys_mem_offset)?;
+        writeln!(f, "  TSC frequency:      {} Hz", info.tsc_frequency)?;
+        writeln!(f, "  CPUs:               {}", info.cpu_count)?;
+        let version = info.loader_version;
+        writeln!(
+            f,
+            "  loader version:     {}.{}.{}",
+            version.major, version.minor, version.patch
+        )?;
+        write!(f, "  build ID:           {}", Hex(info.build_id.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+
+    use mikanos_rs_common::boot_info::{BuildId, LoaderVersion};
+
+    use super::*;
+
+    #[test]
+    fn labels_every_line() {
+        let mut info = BootInfo::empty();
+        info.framebuffer.horizontal_resolution = 800;
+        info.framebuffer.vertical_resolution = 600;
+        info.kernel_stack = MemoryRegion {
+            base: 0x10_0000 as *const u8,
+            size: 0x1_0000,
+        };
+        info.build_id = BuildId::new(&[0xab, 0xcd]);
+        info.loader_version = LoaderVersion {
+            major: 1,
+            minor: 2,
+            patch: 3,
+        };
+        let dump = format!("{}", BootInfoDump(&info));
+        assert!(dump.starts_with("BootInfo at 0x"));
+        assert!(dump.contains("800x600 (stride 0), Unknown, 0 bytes"));
+        assert!(dump.contains("  kernel stack:       0x100000..0x110000\n"));
+        assert!(dump.contains("  initrd:             none\n"));
+        assert!(dump.contains("  CPUs:               1\n"));
+        assert!(dump.contains("  loader version:     1.2.3\n"));
+        assert!(dump.ends_with("  build ID:           abcd"));
+        assert!(dump.lines().skip(1).all(|line| line.starts_with("  ")));
+    }
+}
@@ -0,0 +1,22 @@
+//! Parts of mikanos-rs-loader that don't depend on UEFI and can be tested on
+//! the host.
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod acpi;
+pub mod bmp;
+pub mod config;
+pub mod config_table;
+pub mod cpio;
+pub mod crc32;
+pub mod elf;
+pub mod gop;
+pub mod handoff;
+pub mod memory;
+pub mod menu;
+pub mod note;
+pub mod pages;
+pub mod sha256;
+pub mod util;
@@ -0,0 +1,509 @@
+//! Summaries of the firmware memory map and checks on the ranges the
+//! loader allocates.
+
+use alloc::format;
+use alloc::string::String;
+use core::fmt;
+use core::ops::Range;
+
+use mikanos_rs_common::boot_info::MemoryDescriptor;
+use mikanos_rs_common::memory_attribute::AttributeNames;
+use mikanos_rs_common::memory_type;
+
+/// `EfiReservedMemoryType`.
+const RESERVED: u32 = 0;
+/// `EfiBootServicesCode`.
+const BOOT_SERVICES_CODE: u32 = 3;
+/// `EfiBootServicesData`.
+const BOOT_SERVICES_DATA: u32 = 4;
+/// `EfiConventionalMemory`.
+const CONVENTIONAL: u32 = 7;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// The `EFI_MEMORY_TYPE` names, indexed by type, spelled like the uefi
+/// crate's `MemoryType` constants.
+const TYPE_NAMES: [&str; 16] = [
+    "RESERVED",
+    "LOADER_CODE",
+    "LOADER_DATA",
+    "BOOT_SERVICES_CODE",
+    "BOOT_SERVICES_DATA",
+    "RUNTIME_SERVICES_CODE",
+    "RUNTIME_SERVICES_DATA",
+    "CONVENTIONAL",
+    "UNUSABLE",
+    "ACPI_RECLAIM",
+    "ACPI_NON_VOLATILE",
+    "MMIO",
+    "MMIO_PORT_SPACE",
+    "PAL_CODE",
+    "PERSISTENT_MEMORY",
+    "UNACCEPTED",
+];
+
+/// The name of memory type `ty`, or `None` for OEM, OS and unknown types
+/// other than the loader's own in [`memory_type`].
+pub fn type_name(ty: u32) -> Option<&'static str> {
+    TYPE_NAMES
+        .get(ty as usize)
+        .copied()
+        .or_else(|| memory_type::name(ty))
+}
+
+/// Page counts folded out of a memory map by [`summarize_memory`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemorySummary {
+    /// Pages of conventional (free) memory.
+    pub conventional_pages: u64,
+    /// Pages of reserved memory.
+    pub reserved_pages: u64,
+    /// Pages in the largest run of physically contiguous conventional
+    /// memory.
+    pub largest_free_pages: u64,
+}
+
+impl fmt::Display for MemorySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kib = |pages: u64| pages * PAGE_SIZE / 1024;
+        write!(
+            f,
+            "conventional={} pages ({} KiB), reserved={} pages ({} KiB), largest free run={} pages ({} KiB)",
+            self.conventional_pages,
+            kib(self.conventional_pages),
+            self.reserved_pages,
+            kib(self.reserved_pages),
+            self.largest_free_pages,
+            kib(self.largest_free_pages),
+        )
+    }
+}
+
+/// Total up conventional and reserved memory in `descriptors` and find the
+/// largest free run.
+///
+/// Descriptors are expected in ascending address order, as firmware
+/// returns them; adjacent conventional descriptors count as one run.
+pub fn summarize_memory(descriptors: impl IntoIterator<Item = MemoryDescriptor>) -> MemorySummary {
+    let mut summary = MemorySummary::default();
+    // Start address and length of the free run being extended.
+    let mut run: Option<(u64, u64)> = None;
+    for desc in descriptors {
+        match desc.ty {
+            CONVENTIONAL => {
+                summary.conventional_pages += desc.page_count;
+                run = match run {
+                    Some((start, pages)) if start + pages * PAGE_SIZE == desc.phys_start => {
+                        Some((start, pages + desc.page_count))
+                    }
+                    _ => Some((desc.phys_start, desc.page_count)),
+                };
+                let (_, pages) = run.unwrap();
+                summary.largest_free_pages = u64::max(summary.largest_free_pages, pages);
+                continue;
+            }
+            RESERVED => summary.reserved_pages += desc.page_count,
+            _ => {}
+        }
+        run = None;
+    }
+    summary
+}
+
+/// Bytes of RAM in `descriptors` that the kernel is free to use: conventional
+/// memory plus boot services code and data, which become free once boot
+/// services have exited. Memory the loader allocated for the kernel is not
+/// counted.
+pub fn usable_ram_bytes(descriptors: &[MemoryDescriptor]) -> u64 {
+    descriptors
+        .iter()
+        .filter(|desc| {
+            matches!(
+                desc.ty,
+                CONVENTIONAL | BOOT_SERVICES_CODE | BOOT_SERVICES_DATA
+            )
+        })
+        .map(|desc| desc.page_count * PAGE_SIZE)
+        .sum()
+}
+
+/// Base and size in bytes of the largest run of physically contiguous
+/// conventional memory in `descriptors`, or `(0, 0)` if there is none.
+///
+/// Like [`summarize_memory`], this expects descriptors in ascending address
+/// order and joins adjacent conventional descriptors into one run. The
+/// first of several equally large runs wins.
+pub fn largest_free_region(descriptors: &[MemoryDescriptor]) -> (u64, u64) {
+    let mut largest = (0, 0);
+    let mut run: Option<(u64, u64)> = None;
+    for desc in descriptors {
+        if desc.ty != CONVENTIONAL {
+            run = None;
+            continue;
+        }
+        let size = desc.page_count * PAGE_SIZE;
+        let (start, len) = match run {
+            Some((start, len)) if start + len == desc.phys_start => (start, len + size),
+            _ => (desc.phys_start, size),
+        };
+        run = Some((start, len));
+        if len > largest.1 {
+            largest = (start, len);
+        }
+    }
+    largest
+}
+
+/// Whether `a` and `b` have an address in common. Empty ranges overlap
+/// nothing.
+pub fn ranges_overlap(a: &Range<u64>, b: &Range<u64>) -> bool {
+    !a.is_empty() && !b.is_empty() && a.start < b.end && b.start < a.end
+}
+
+/// The first of the named `regions` that overlaps `image`, if any.
+pub fn find_overlap<'a>(
+    image: &Range<u64>,
+    regions: &'a [(&'static str, Range<u64>)],
+) -> Option<&'a (&'static str, Range<u64>)> {
+    regions
+        .iter()
+        .find(|(_, region)| ranges_overlap(image, region))
+}
+
+/// The first part of `range` that `descriptors` don't mark as conventional
+/// memory, with its type, or `None` if all of it is free.
+///
+/// A part that no descriptor covers comes back with type `None`. The
+/// descriptors may be in any order.
+pub fn find_unavailable(
+    range: &Range<u64>,
+    descriptors: &[MemoryDescriptor],
+) -> Option<(Range<u64>, Option<u32>)> {
+    let end = |desc: &MemoryDescriptor| desc.phys_start + desc.page_count * PAGE_SIZE;
+    let mut pos = range.start;
+    while pos < range.end {
+        let desc = descriptors
+            .iter()
+            .find(|desc| desc.phys_start <= pos && pos < end(desc));
+        match desc {
+            Some(desc) if desc.ty == CONVENTIONAL => pos = end(desc),
+            Some(desc) => return Some((pos..u64::min(end(desc), range.end), Some(desc.ty))),
+            None => {
+                let next = descriptors
+                    .iter()
+                    .map(|desc| desc.phys_start)
+                    .filter(|&start| start > pos)
+                    .min()
+                    .unwrap_or(range.end);
+                return Some((pos..u64::min(next, range.end), None));
+            }
+        }
+    }
+    None
+}
+
+/// Coalesce runs of consecutive descriptors in `descriptors` that have the
+/// same type and attributes and are physically contiguous, and return how
+/// many descriptors are left at the front of the slice.
+///
+/// This works in place without allocating, so it can run after exiting
+/// boot services. Descriptors past the returned count are left over from
+/// the merge and should be ignored.
+pub fn merge_descriptors(descriptors: &mut [MemoryDescriptor]) -> usize {
+    let mut len = 0;
+    for i in 0..descriptors.len() {
+        let desc = descriptors[i];
+        if len > 0 {
+            let last = &mut descriptors[len - 1];
+            if last.ty == desc.ty
+                && last.attribute == desc.attribute
+                && last.phys_start + last.page_count * PAGE_SIZE == desc.phys_start
+            {
+                last.page_count += desc.page_count;
+                continue;
+            }
+        }
+        descriptors[len] = desc;
+        len += 1;
+    }
+    len
+}
+
+/// First line of the CSV memory map dump, naming the columns
+/// [`format_memmap_line`] fills.
+pub const MEMMAP_CSV_HEADER: &str =
+    "Index, Type, Type(name), PhysicalStart, NumberOfPages, Attribute, Attribute(name)\n";
+
+/// The CSV line for descriptor number `index`, with its newline.
+///
+/// The physical start is `0x`-prefixed hex padded to 8 characters, and
+/// only the low 20 attribute bits show up in the hex column; the names
+/// include the high ones like `RUNTIME`. Types without a [`type_name`] are
+/// written like the uefi crate's `Debug` does, as `MemoryType(<decimal>)`.
+pub fn format_memmap_line(index: usize, desc: &MemoryDescriptor) -> String {
+    let name = match type_name(desc.ty) {
+        Some(name) => String::from(name),
+        None => format!("MemoryType({})", desc.ty),
+    };
+    format!(
+        "{}, {:#x}, {}, {:#08x}, {}, {:#x}, {}\n",
+        index,
+        desc.ty,
+        name,
+        desc.phys_start,
+        desc.page_count,
+        desc.attribute & 0xfffff,
+        AttributeNames(desc.attribute),
+    )
+}
+
+/// Displays descriptors as a JSON array with one object per descriptor.
+///
+/// Every value is a string so that 64-bit addresses survive parsers that
+/// read numbers as doubles: `type` is the [`type_name`] (or the type in hex
+/// if it has none), `phys_start` and `attribute` are hex, `page_count` is
+/// decimal and `attribute_names` is the [`AttributeNames`]. None of these
+/// can contain characters that need escaping.
+pub struct MemoryMapJson<'a>(pub &'a [MemoryDescriptor]);
+
+impl fmt::Display for MemoryMapJson<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[")?;
+        for (i, desc) in self.0.iter().enumerate() {
+            f.write_str(if i == 0 { "\n" } else { ",\n" })?;
+            f.write_str("  {\"type\": \"")?;
+            match type_name(desc.ty) {
+                Some(name) => f.write_str(name)?,
+                None => write!(f, "{:#x}", desc.ty)?,
+            }
+            write!(
+                f,
+                "\", \"phys_start\": \"{:#x}\", \"page_count\": \"{}\", \"attribute\": \"{:#x}\", \"attribute_names\": \"{}\"}}",
+                desc.phys_start,
+                desc.page_count,
+                desc.attribute,
+                AttributeNames(desc.attribute),
+            )?;
+        }
+        if !self.0.is_empty() {
+            f.write_str("\n")?;
+        }
+        f.write_str("]\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    fn desc(ty: u32, phys_start: u64, page_count: u64) -> MemoryDescriptor {
+        MemoryDescriptor {
+            ty,
+            phys_start,
+            page_count,
+            attribute: 0,
+        }
+    }
+
+    #[test]
+    fn summarizes_memory() {
+        let map = [
+            desc(RESERVED, 0x0, 1),
+            desc(CONVENTIONAL, 0x1000, 3),
+            desc(CONVENTIONAL, 0x4000, 2),
+            desc(2, 0x6000, 4),
+            desc(CONVENTIONAL, 0xa000, 4),
+            desc(CONVENTIONAL, 0x10_0000, 1),
+            desc(RESERVED, 0x20_0000, 8),
+        ];
+        assert_eq!(
+            summarize_memory(map),
+            MemorySummary {
+                conventional_pages: 10,
+                reserved_pages: 9,
+                largest_free_pages: 5,
+            }
+        );
+    }
+
+    #[test]
+    fn counts_reclaimable_memory_as_usable() {
+        let map = [
+            desc(RESERVED, 0x0, 1),
+            desc(CONVENTIONAL, 0x1000, 3),
+            desc(BOOT_SERVICES_CODE, 0x4000, 2),
+            desc(BOOT_SERVICES_DATA, 0x6000, 4),
+            desc(2, 0xa000, 8),
+            desc(CONVENTIONAL, 0x10_0000, 1),
+        ];
+        assert_eq!(usable_ram_bytes(&map), 10 * PAGE_SIZE);
+        assert_eq!(usable_ram_bytes(&[]), 0);
+    }
+
+    #[test]
+    fn names_loader_memory_types() {
+        assert_eq!(type_name(7), Some("CONVENTIONAL"));
+        assert_eq!(type_name(memory_type::KERNEL_IMAGE), Some("KERNEL_IMAGE"));
+        assert_eq!(type_name(memory_type::KERNEL_CODE), Some("KERNEL_CODE"));
+        assert_eq!(type_name(0x7000_0000), None);
+    }
+
+    #[test]
+    fn finds_largest_free_region() {
+        let map = [
+            desc(CONVENTIONAL, 0x1000, 3),
+            desc(CONVENTIONAL, 0x4000, 2),
+            desc(2, 0x6000, 4),
+            desc(CONVENTIONAL, 0xa000, 5),
+            desc(CONVENTIONAL, 0x10_0000, 8),
+        ];
+        assert_eq!(largest_free_region(&map), (0x10_0000, 8 * PAGE_SIZE));
+        assert_eq!(largest_free_region(&map[..4]), (0x1000, 5 * PAGE_SIZE));
+        assert_eq!(largest_free_region(&[desc(RESERVED, 0, 16)]), (0, 0));
+        assert_eq!(largest_free_region(&[]), (0, 0));
+    }
+
+    #[test]
+    fn finds_overlapping_region() {
+        let image = 0x100000..0x200000;
+        let regions = [
+            ("stack", 0x80000..0x100000),
+            ("boot info", 0x1ff000..0x200000),
+            ("memory map", 0x150000..0x151000),
+        ];
+        assert_eq!(find_overlap(&image, &regions), Some(&regions[1]));
+        assert_eq!(find_overlap(&image, &regions[..1]), None);
+    }
+
+    #[test]
+    fn empty_ranges_never_overlap() {
+        assert!(!ranges_overlap(&(0x1000..0x1000), &(0..0x2000)));
+        assert!(ranges_overlap(&(0x1000..0x1001), &(0..0x2000)));
+    }
+
+    #[test]
+    fn finds_unavailable_memory() {
+        let map = [
+            desc(CONVENTIONAL, 0x10_0000, 2),
+            desc(BOOT_SERVICES_DATA, 0x10_2000, 2),
+            desc(CONVENTIONAL, 0x10_4000, 4),
+            // Listed out of order, with a gap before it.
+            desc(CONVENTIONAL, 0x10_a000, 2),
+        ];
+        assert_eq!(find_unavailable(&(0x10_0000..0x10_2000), &map), None);
+        assert_eq!(
+            find_unavailable(&(0x10_1000..0x10_5000), &map),
+            Some((0x10_2000..0x10_4000, Some(BOOT_SERVICES_DATA)))
+        );
+        assert_eq!(
+            find_unavailable(&(0x10_3000..0x10_3800), &map),
+            Some((0x10_3000..0x10_3800, Some(BOOT_SERVICES_DATA)))
+        );
+        // Adjacent conventional descriptors don't need to be merged.
+        let split = [desc(CONVENTIONAL, 0x1000, 1), desc(CONVENTIONAL, 0x2000, 1)];
+        assert_eq!(find_unavailable(&(0x1000..0x3000), &split), None);
+    }
+
+    #[test]
+    fn memory_outside_the_map_is_unavailable() {
+        let map = [
+            desc(CONVENTIONAL, 0x10_a000, 2),
+            desc(CONVENTIONAL, 0x10_0000, 8),
+        ];
+        assert_eq!(
+            find_unavailable(&(0x10_6000..0x10_c000), &map),
+            Some((0x10_8000..0x10_a000, None))
+        );
+        assert_eq!(
+            find_unavailable(&(0x10_b000..0x10_d000), &map),
+            Some((0x10_c000..0x10_d000, None))
+        );
+        assert_eq!(
+            find_unavailable(&(0x1000..0x2000), &[]),
+            Some((0x1000..0x2000, None))
+        );
+    }
+
+    #[test]
+    fn empty_map_has_nothing_free() {
+        assert_eq!(summarize_memory([]), MemorySummary::default());
+    }
+
+    #[test]
+    fn merges_fragmented_map() {
+        let mut map = [
+            desc(CONVENTIONAL, 0x1000, 1),
+            desc(CONVENTIONAL, 0x2000, 2),
+            desc(CONVENTIONAL, 0x4000, 1),
+            // A gap before this one.
+            desc(CONVENTIONAL, 0x8000, 1),
+            // Same address run, different type.
+            desc(RESERVED, 0x9000, 1),
+            desc(RESERVED, 0xa000, 1),
+            MemoryDescriptor {
+                attribute: 0xf,
+                ..desc(RESERVED, 0xb000, 1)
+            },
+        ];
+        let len = merge_descriptors(&mut map);
+        assert_eq!(len, 4);
+        assert_eq!(
+            map[..len],
+            [
+                desc(CONVENTIONAL, 0x1000, 4),
+                desc(CONVENTIONAL, 0x8000, 1),
+                desc(RESERVED, 0x9000, 2),
+                MemoryDescriptor {
+                    attribute: 0xf,
+                    ..desc(RESERVED, 0xb000, 1)
+                },
+            ]
+        );
+        assert_eq!(merge_descriptors(&mut []), 0);
+    }
+
+    #[test]
+    fn formats_csv_lines() {
+        let conventional = MemoryDescriptor {
+            attribute: 0xf,
+            ..desc(CONVENTIONAL, 0x1000, 3)
+        };
+        assert_eq!(
+            format_memmap_line(0, &conventional),
+            "0, 0x7, CONVENTIONAL, 0x001000, 3, 0xf, UC|WC|WT|WB\n"
+        );
+        let runtime = MemoryDescriptor {
+            attribute: 0x8000_0000_0000_4008,
+            ..desc(6, 0xfff0_0000, 16)
+        };
+        assert_eq!(
+            format_memmap_line(12, &runtime),
+            "12, 0x6, RUNTIME_SERVICES_DATA, 0xfff00000, 16, 0x4008, WB|XP|RUNTIME\n"
+        );
+        assert_eq!(
+            format_memmap_line(1, &desc(0x7000_0001, 0, 1)),
+            "1, 0x70000001, MemoryType(1879048193), 0x000000, 1, 0x0, \n"
+        );
+    }
+
+    #[test]
+    fn writes_descriptors_as_json() {
+        let descs = [
+            MemoryDescriptor {
+                attribute: 0xf,
+                ..desc(CONVENTIONAL, 0x1000, 3)
+            },
+            MemoryDescriptor {
+                attribute: 0x8000_0000_0000_4008,
+                ..desc(0x7000_0001, 0xfff0_0000, 16)
+            },
+        ];
+        assert_eq!(
+            MemoryMapJson(&descs).to_string(),
+            "[\n  {\"type\": \"CONVENTIONAL\", \"phys_start\": \"0x1000\", \"page_count\": \"3\", \"attribute\": \"0xf\", \"attribute_names\": \"UC|WC|WT|WB\"},\n  \
+             {\"type\": \"0x70000001\", \"phys_start\": \"0xfff00000\", \"page_count\": \"16\", \"attribute\": \"0x8000000000004008\", \"attribute_names\": \"WB|XP|RUNTIME\"}\n]\n"
+        );
+        assert_eq!(MemoryMapJson(&[]).to_string(), "[]\n");
+    }
+}
@@ -0,0 +1,176 @@
+//! The boot menu's logic, apart from the console so that it can be tested
+//! with made-up key presses.
+
+use log::info;
+
+use crate::config::BootEntry;
+
+/// A key press the menu reacts to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuKey {
+    Up,
+    Down,
+    Enter,
+    /// A digit key, `1` meaning the first entry.
+    Digit(u32),
+    Other,
+}
+
+/// Where the menu gets its key presses from.
+pub trait KeySource {
+    /// Wait for the next key press. With a timeout in seconds, give up and
+    /// return `None` once it elapses.
+    fn next_key(&mut self, timeout_secs: Option<u64>) -> Option<MenuKey>;
+}
+
+/// Let the user pick one of `entries`.
+///
+/// First counts down from `timeout_secs` and boots the first entry at
+/// zero. A key press during the countdown cancels it and shows the menu,
+/// where up and down move the selection and Enter confirms it; a digit
+/// picks the matching entry right away. A timeout of zero boots the first
+/// entry without counting down or showing the menu. `draw` shows the menu
+/// with the entry at the given index selected, before every key press.
+///
+/// `entries` must not be empty.
+pub fn select_entry<'a>(
+    entries: &'a [BootEntry],
+    timeout_secs: u64,
+    keys: &mut impl KeySource,
+    mut draw: impl FnMut(&[BootEntry], usize),
+) -> &'a BootEntry {
+    if entries.len() == 1 || timeout_secs == 0 || !countdown(&entries[0], timeout_secs, keys) {
+        return &entries[0];
+    }
+    let mut selected = 0;
+    loop {
+        draw(entries, selected);
+        let Some(key) = keys.next_key(None) else {
+            return &entries[selected];
+        };
+        match key {
+            MenuKey::Up => selected = selected.saturating_sub(1),
+            MenuKey::Down => selected = usize::min(selected + 1, entries.len() - 1),
+            MenuKey::Enter => return &entries[selected],
+            MenuKey::Digit(n) if (1..=entries.len()).contains(&(n as usize)) => {
+                return &entries[n as usize - 1];
+            }
+            MenuKey::Digit(_) | MenuKey::Other => {}
+        }
+    }
+}
+
+/// Count down from `secs`, one log line per second, before booting
+/// `entry`. Returns whether a key was pressed to cancel the countdown.
+fn countdown(entry: &BootEntry, secs: u64, keys: &mut impl KeySource) -> bool {
+    for remaining in (1..=secs).rev() {
+        info!(
+            "Booting {} in {}... (press any key for the menu)",
+            entry.label, remaining
+        );
+        if keys.next_key(Some(1)).is_some() {
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::{String, ToString};
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// Plays back `keys`, one per call, and records the timeouts asked for.
+    struct FakeKeys {
+        keys: Vec<Option<MenuKey>>,
+        timeouts: Vec<Option<u64>>,
+    }
+
+    impl FakeKeys {
+        fn new(keys: &[Option<MenuKey>]) -> Self {
+            Self {
+                keys: keys.iter().rev().copied().collect(),
+                timeouts: Vec::new(),
+            }
+        }
+    }
+
+    impl KeySource for FakeKeys {
+        fn next_key(&mut self, timeout_secs: Option<u64>) -> Option<MenuKey> {
+            self.timeouts.push(timeout_secs);
+            self.keys.pop().expect("the menu asked for more keys")
+        }
+    }
+
+    fn entries() -> Vec<BootEntry> {
+        ["a", "b", "c"]
+            .iter()
+            .map(|label| BootEntry {
+                label: label.to_string(),
+                path: ["\\", label, ".elf"].concat(),
+            })
+            .collect()
+    }
+
+    /// Run the menu on `keys` and return the label it picked and the
+    /// selections it drew.
+    fn run(keys: &[Option<MenuKey>]) -> (String, Vec<usize>) {
+        let entries = entries();
+        let mut keys = FakeKeys::new(keys);
+        let mut drawn = Vec::new();
+        let entry = select_entry(&entries, 3, &mut keys, |_, selected| drawn.push(selected));
+        assert!(keys.keys.is_empty(), "keys left over");
+        (entry.label.clone(), drawn)
+    }
+
+    #[test]
+    fn boots_first_entry_when_countdown_expires() {
+        let entries = entries();
+        let mut keys = FakeKeys::new(&[None, None, None]);
+        let entry = select_entry(&entries, 3, &mut keys, |_, _| panic!("drew the menu"));
+        assert_eq!(entry.label, "a");
+        assert_eq!(keys.timeouts, [Some(1); 3]);
+    }
+
+    #[test]
+    fn key_cancels_countdown() {
+        let (label, drawn) = run(&[None, Some(MenuKey::Other), Some(MenuKey::Enter)]);
+        assert_eq!(label, "a");
+        assert_eq!(drawn, [0]);
+    }
+
+    #[test]
+    fn up_and_down_stay_in_the_list() {
+        let (label, drawn) = run(&[
+            Some(MenuKey::Other),
+            Some(MenuKey::Up),
+            Some(MenuKey::Down),
+            Some(MenuKey::Down),
+            Some(MenuKey::Down),
+            Some(MenuKey::Enter),
+        ]);
+        assert_eq!(label, "c");
+        assert_eq!(drawn, [0, 0, 1, 2, 2]);
+    }
+
+    #[test]
+    fn digit_picks_entry() {
+        let (label, _) = run(&[Some(MenuKey::Other), Some(MenuKey::Digit(2))]);
+        assert_eq!(label, "b");
+    }
+
+    #[test]
+    fn ignores_out_of_range_digit() {
+        let (label, drawn) = run(&[
+            Some(MenuKey::Other),
+            Some(MenuKey::Digit(0)),
+            Some(MenuKey::Digit(4)),
+            Some(MenuKey::Down),
+            Some(MenuKey::Enter),
+        ]);
+        assert_eq!(label, "b");
+        assert_eq!(drawn, [0, 0, 0, 1]);
+    }
+}
@@ -0,0 +1,123 @@
+//! ELF notes, as found in `PT_NOTE` segments.
+//!
+//! Each note is a 12-byte header holding the sizes of the owner name and
+//! the descriptor and the note type, followed by the NUL-terminated name
+//! and the descriptor, each padded to a multiple of four bytes.
+
+use core::fmt;
+
+/// Type of the GNU note holding the build ID.
+pub const NT_GNU_BUILD_ID: u32 = 3;
+
+/// Size of a note header.
+const HEADER_SIZE: usize = 12;
+
+/// A note, borrowing from the segment it is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Note<'a> {
+    /// The owner name without its NUL terminator.
+    pub owner: &'a [u8],
+    pub ty: u32,
+    pub desc: &'a [u8],
+}
+
+/// Iterates over the notes in a segment, stopping at the first one that
+/// runs past its end.
+pub struct Notes<'a> {
+    data: &'a [u8],
+}
+
+/// The notes in the contents of a `PT_NOTE` segment, in order.
+pub fn notes(data: &[u8]) -> Notes<'_> {
+    Notes { data }
+}
+
+/// The GNU build ID in the notes in `data`, if there is one.
+pub fn build_id(data: &[u8]) -> Option<&[u8]> {
+    notes(data)
+        .find(|note| note.owner == b"GNU" && note.ty == NT_GNU_BUILD_ID)
+        .map(|note| note.desc)
+}
+
+/// The `u32` at `offset` in `data`.
+fn word(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+impl<'a> Iterator for Notes<'a> {
+    type Item = Note<'a>;
+
+    fn next(&mut self) -> Option<Note<'a>> {
+        let header = self.data.get(..HEADER_SIZE)?;
+        let name_size = word(header, 0) as usize;
+        let desc_size = word(header, 4) as usize;
+        let ty = word(header, 8);
+        let name_end = HEADER_SIZE.checked_add(name_size)?;
+        let desc_start = name_end.next_multiple_of(4);
+        let desc_end = desc_start.checked_add(desc_size)?;
+        let name = self.data.get(HEADER_SIZE..name_end)?;
+        let desc = self.data.get(desc_start..desc_end)?;
+        let owner = name.strip_suffix(&[0]).unwrap_or(name);
+        self.data = self.data.get(desc_end.next_multiple_of(4)..).unwrap_or(&[]);
+        Some(Note { owner, ty, desc })
+    }
+}
+
+/// Formats bytes as lowercase hex without separators, the way `readelf -n`
+/// prints a build ID.
+pub struct Hex<'a>(pub &'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::format;
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// Append a note to `data` the way a linker lays it out.
+    fn push(data: &mut Vec<u8>, owner: &[u8], ty: u32, desc: &[u8]) {
+        data.extend_from_slice(&(owner.len() as u32 + 1).to_le_bytes());
+        data.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+        data.extend_from_slice(&ty.to_le_bytes());
+        data.extend_from_slice(owner);
+        data.push(0);
+        data.resize(data.len().next_multiple_of(4), 0);
+        data.extend_from_slice(desc);
+        data.resize(data.len().next_multiple_of(4), 0);
+    }
+
+    #[test]
+    fn finds_the_gnu_build_id() {
+        let id = [0xde, 0xad, 0xbe, 0xef, 0x01, 0x23, 0x45, 0x67, 0x89];
+        let mut data = Vec::new();
+        push(&mut data, b"Xen", NT_GNU_BUILD_ID, b"not this one");
+        push(&mut data, b"GNU", 1, &[0; 16]);
+        push(&mut data, b"GNU", NT_GNU_BUILD_ID, &id);
+        assert_eq!(notes(&data).count(), 3);
+        assert_eq!(build_id(&data), Some(&id[..]));
+        assert_eq!(format!("{}", Hex(&id)), "deadbeef0123456789");
+    }
+
+    #[test]
+    fn skips_missing_or_truncated_notes() {
+        assert_eq!(build_id(&[]), None);
+
+        let mut data = Vec::new();
+        push(&mut data, b"GNU", 1, &[0; 4]);
+        assert_eq!(build_id(&data), None);
+
+        let mut data = Vec::new();
+        push(&mut data, b"GNU", NT_GNU_BUILD_ID, &[0xab; 20]);
+        assert_eq!(build_id(&data[..data.len() - 1]), None);
+        assert_eq!(notes(&data[..HEADER_SIZE - 1]).count(), 0);
+    }
+}
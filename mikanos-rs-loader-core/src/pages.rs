@@ -0,0 +1,150 @@
+//! Page allocations that are given back if the loader bails out.
+
+use core::mem::ManuallyDrop;
+use core::ptr::NonNull;
+
+/// Where pages come from: the firmware in the loader, a fake in tests.
+pub trait PageAllocator {
+    /// How and where to allocate, as the allocator understands it.
+    type Request;
+    type Error;
+
+    /// Allocate `count` pages as `request` asks.
+    fn allocate(&self, request: Self::Request, count: usize) -> Result<NonNull<u8>, Self::Error>;
+
+    /// Free `count` pages at `ptr`, which [`PageAllocator::allocate`]
+    /// returned.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may use the pages afterwards.
+    unsafe fn free(&self, ptr: NonNull<u8>, count: usize);
+}
+
+/// Pages from a [`PageAllocator`].
+///
+/// The pages are freed again when this is dropped, so an error path that
+/// bails out with `?` does not leak them or keep their address range
+/// reserved. Call [`Pages::commit`] once the pages should outlive the
+/// loader.
+pub struct Pages<A: PageAllocator> {
+    allocator: A,
+    ptr: NonNull<u8>,
+    count: usize,
+}
+
+impl<A: PageAllocator> Pages<A> {
+    /// Allocate `count` pages from `allocator`.
+    pub fn new(allocator: A, request: A::Request, count: usize) -> Result<Self, A::Error> {
+        let ptr = allocator.allocate(request, count)?;
+        Ok(Self {
+            allocator,
+            ptr,
+            count,
+        })
+    }
+
+    /// Base address of the pages.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    /// Number of pages.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Keep the pages allocated and return their base address.
+    pub fn commit(self) -> NonNull<u8> {
+        ManuallyDrop::new(self).ptr
+    }
+}
+
+impl<A: PageAllocator> Drop for Pages<A> {
+    fn drop(&mut self) {
+        unsafe { self.allocator.free(self.ptr, self.count) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    use super::*;
+
+    const PAGE_SIZE: usize = 0x1000;
+
+    /// Hands out made-up addresses and records which are live. Fails every
+    /// allocation while `budget` of them are live.
+    struct FakeAllocator {
+        next: RefCell<usize>,
+        budget: usize,
+        live: RefCell<Vec<(usize, usize)>>,
+    }
+
+    impl FakeAllocator {
+        fn new(budget: usize) -> Self {
+            Self {
+                next: RefCell::new(0x10_0000),
+                budget,
+                live: RefCell::new(Vec::new()),
+            }
+        }
+    }
+
+    impl PageAllocator for &FakeAllocator {
+        type Request = ();
+        type Error = ();
+
+        fn allocate(&self, _: (), count: usize) -> Result<NonNull<u8>, ()> {
+            if self.live.borrow().len() == self.budget {
+                return Err(());
+            }
+            let mut next = self.next.borrow_mut();
+            let addr = *next;
+            *next += count * PAGE_SIZE;
+            self.live.borrow_mut().push((addr, count));
+            Ok(NonNull::new(addr as *mut u8).unwrap())
+        }
+
+        unsafe fn free(&self, ptr: NonNull<u8>, count: usize) {
+            let mut live = self.live.borrow_mut();
+            let index = live
+                .iter()
+                .position(|&entry| entry == (ptr.as_ptr() as usize, count))
+                .expect("freed pages that were never allocated");
+            live.remove(index);
+        }
+    }
+
+    /// Allocate the pieces of an image, the way the loader does, failing
+    /// wherever the allocator runs out.
+    fn load(allocator: &FakeAllocator) -> Result<Vec<NonNull<u8>>, ()> {
+        let image = Pages::new(allocator, (), 4)?;
+        let stack = Pages::new(allocator, (), 16)?;
+        let boot_info = Pages::new(allocator, (), 1)?;
+        Ok(Vec::from([
+            image.commit(),
+            stack.commit(),
+            boot_info.commit(),
+        ]))
+    }
+
+    #[test]
+    fn frees_pages_on_a_failed_load() {
+        for budget in 0..3 {
+            let allocator = FakeAllocator::new(budget);
+            assert!(load(&allocator).is_err());
+            assert!(allocator.live.borrow().is_empty(), "budget {}", budget);
+        }
+    }
+
+    #[test]
+    fn committed_pages_stay_allocated() {
+        let allocator = FakeAllocator::new(3);
+        let pages = load(&allocator).unwrap();
+        assert_eq!(pages.len(), 3);
+        assert_eq!(allocator.live.borrow().len(), 3);
+    }
+}
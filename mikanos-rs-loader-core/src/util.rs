@@ -0,0 +1,72 @@
+//! Small formatting helpers for diagnostics.
+
+use core::fmt;
+
+/// Bytes shown per line of a [`HexDump`].
+const BYTES_PER_LINE: usize = 16;
+
+/// Displays bytes like `hexdump -C`: an offset, sixteen hex bytes in two
+/// groups of eight, and the same bytes as ASCII with `.` for anything
+/// unprintable. Lines are separated by newlines, without one at the end.
+pub struct HexDump<'a>(&'a [u8]);
+
+/// Dump the first `len` bytes of `data`, or all of it if it is shorter.
+pub fn hexdump(data: &[u8], len: usize) -> HexDump<'_> {
+    HexDump(&data[..usize::min(len, data.len())])
+}
+
+impl fmt::Display for HexDump<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, line) in self.0.chunks(BYTES_PER_LINE).enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{:08x}  ", i * BYTES_PER_LINE)?;
+            for j in 0..BYTES_PER_LINE {
+                match line.get(j) {
+                    Some(byte) => write!(f, "{:02x} ", byte)?,
+                    None => write!(f, "   ")?,
+                }
+                if j == BYTES_PER_LINE / 2 - 1 {
+                    write!(f, " ")?;
+                }
+            }
+            write!(f, " |")?;
+            for &byte in line {
+                let c = if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                };
+                write!(f, "{}", c)?;
+            }
+            write!(f, "|")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn formats_full_and_partial_lines() {
+        let data = b"\x7fELF\x02\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00>\x00";
+        assert_eq!(
+            hexdump(data, 64).to_string(),
+            "00000000  7f 45 4c 46 02 01 01 00  00 00 00 00 00 00 00 00  |.ELF............|\n\
+             00000010  02 00 3e 00                                       |..>.|"
+        );
+    }
+
+    #[test]
+    fn stops_at_len() {
+        assert_eq!(
+            hexdump(b"hello, world", 5).to_string(),
+            "00000000  68 65 6c 6c 6f                                    |hello|"
+        );
+        assert_eq!(hexdump(b"", 64).to_string(), "");
+    }
+}
@@ -0,0 +1,33 @@
+//! Records the git commit the loader is built from, for the version line it
+//! logs at startup.
+
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Run `git` with `args` and return its trimmed output, if it succeeds.
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = String::from_utf8(output.stdout).ok()?;
+    Some(text.trim().to_string())
+}
+
+fn main() {
+    let hash = git(&["rev-parse", "--short", "HEAD"]).unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=MIKANOS_GIT_HASH={}", hash);
+
+    // Rebuild when HEAD moves, whether by checkout or by a new commit on
+    // the current branch.
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]).map(PathBuf::from) {
+        println!("cargo:rerun-if-changed={}", git_dir.join("HEAD").display());
+        if let Some(branch) = git(&["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed={}", git_dir.join(branch).display());
+        }
+        println!(
+            "cargo:rerun-if-changed={}",
+            git_dir.join("packed-refs").display()
+        );
+    }
+}
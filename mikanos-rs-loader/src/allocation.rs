@@ -0,0 +1,67 @@
+use core::ptr::NonNull;
+
+use log::{debug, error, warn};
+use mikanos_rs_loader_core::pages::{PageAllocator, Pages};
+use uefi::Status;
+use uefi::boot::{self, AllocateType, MemoryType};
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// `boot::allocate_pages` and `boot::free_pages`.
+struct Firmware;
+
+impl PageAllocator for Firmware {
+    type Request = (AllocateType, MemoryType);
+    type Error = uefi::Error;
+
+    fn allocate(
+        &self,
+        (ty, mem_ty): (AllocateType, MemoryType),
+        count: usize,
+    ) -> uefi::Result<NonNull<u8>> {
+        let ptr = boot::allocate_pages(ty, mem_ty, count)?;
+        debug!("Allocated {} pages of {:?} at {:p}", count, mem_ty, ptr);
+        Ok(ptr)
+    }
+
+    unsafe fn free(&self, ptr: NonNull<u8>, count: usize) {
+        if let Err(err) = unsafe { boot::free_pages(ptr, count) } {
+            warn!("Failed to free {} pages at {:p}: {}", count, ptr, err);
+        }
+    }
+}
+
+/// [`Pages`] from the firmware, freed on drop the same way, with the
+/// address checked before the firmware sees it.
+pub struct PageAllocation(Pages<Firmware>);
+
+impl PageAllocation {
+    /// Allocate `pages` pages. An [`AllocateType::Address`] has to be page
+    /// aligned; callers round it down first. An unaligned one fails with
+    /// `INVALID_PARAMETER` and an error naming the address, since the
+    /// firmware's error for it doesn't say what is wrong.
+    pub fn new(ty: AllocateType, mem_ty: MemoryType, pages: usize) -> uefi::Result<Self> {
+        if let AllocateType::Address(addr) = ty
+            && !addr.is_multiple_of(PAGE_SIZE)
+        {
+            error!("Allocation address {:#x} is not page aligned", addr);
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        Ok(Self(Pages::new(Firmware, (ty, mem_ty), pages)?))
+    }
+
+    /// Base address of the pages.
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.0.as_ptr()
+    }
+
+    /// Size of the pages in bytes.
+    pub fn len(&self) -> usize {
+        self.0.count() * PAGE_SIZE as usize
+    }
+
+    /// Keep the pages allocated and return their base address.
+    pub fn commit(self) -> NonNull<u8> {
+        self.0.commit()
+    }
+}
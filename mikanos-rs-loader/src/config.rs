@@ -0,0 +1,71 @@
+use alloc::string::String;
+
+use log::{info, warn};
+use mikanos_rs_loader_core::config::{self, ConfigError};
+use uefi::prelude::*;
+use uefi::proto::media::file::{Directory, FileMode};
+use uefi::runtime::{self, VariableVendor};
+use uefi::{CStr16, guid};
+
+pub use mikanos_rs_loader_core::config::{BootEntry, DEFAULT_KERNEL_PATH, LoaderConfig};
+
+use crate::error::LoaderError;
+use crate::{open_file, read_file};
+
+/// Path of the loader configuration file on the boot volume.
+const CONFIG_PATH: &CStr16 = cstr16!("\\loader.conf");
+
+/// The EFI variable that overrides the kernel path for the next boots.
+const KERNEL_VAR: &CStr16 = cstr16!("MikanKernel");
+
+/// Vendor GUID of [`KERNEL_VAR`].
+const KERNEL_VAR_VENDOR: VariableVendor =
+    VariableVendor(guid!("6d696b61-6e6f-4573-8072-732d6c6f6164"));
+
+/// Read `\loader.conf` from `root_dir`, falling back to the defaults if it
+/// doesn't exist.
+pub fn read_config(root_dir: &mut Directory) -> Result<LoaderConfig, LoaderError> {
+    let mut file = match open_file(root_dir, CONFIG_PATH, FileMode::Read) {
+        Ok(file) => file,
+        Err(LoaderError::NotFound(_)) => {
+            info!("No {}, using the default config.", CONFIG_PATH);
+            return Ok(LoaderConfig::default());
+        }
+        Err(err) => return Err(err),
+    };
+    let buf = read_file(&mut file)?;
+    let text = core::str::from_utf8(&buf).map_err(|err| ConfigError {
+        line: buf[..err.valid_up_to()]
+            .iter()
+            .filter(|&&b| b == b'\n')
+            .count()
+            + 1,
+        reason: "not valid UTF-8",
+    })?;
+    Ok(LoaderConfig::parse(text)?)
+}
+
+/// The kernel path in the `MikanKernel` EFI variable, which a running
+/// system sets to pick the kernel for the next boot. It takes precedence
+/// over `\boot.cpio`, the boot menu and the `kernel=` config.
+///
+/// Returns `None`, falling back to those, if the variable doesn't exist or
+/// isn't a UCS-2 path; see [`config::parse_kernel_var`].
+pub fn read_boot_kernel_var() -> Option<String> {
+    let value = match runtime::get_variable_boxed(KERNEL_VAR, &KERNEL_VAR_VENDOR) {
+        Ok((value, _)) => value,
+        Err(err) if err.status() == Status::NOT_FOUND => return None,
+        Err(err) => {
+            warn!("Failed to read the {} variable: {}", KERNEL_VAR, err);
+            return None;
+        }
+    };
+    let path = config::parse_kernel_var(&value);
+    if path.is_none() {
+        warn!(
+            "Ignoring the {} variable, it isn't a UCS-2 path.",
+            KERNEL_VAR
+        );
+    }
+    path
+}
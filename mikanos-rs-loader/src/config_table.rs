@@ -0,0 +1,145 @@
+use core::slice;
+
+use mikanos_rs_common::boot_info::{ConfigTable, ConfigTables};
+use mikanos_rs_loader_core::acpi;
+use mikanos_rs_loader_core::config_table;
+use uefi::Guid;
+use uefi::boot;
+use uefi::system;
+use uefi::table::cfg::{ACPI_GUID, ACPI2_GUID, ConfigTableEntry, SMBIOS_GUID, SMBIOS3_GUID};
+
+use crate::allocation::PageAllocation;
+
+/// Return the address of the first table in `tables` matching one of
+/// `guids`, trying the GUIDs in order of preference.
+fn find_table(tables: &[ConfigTableEntry], guids: &[Guid]) -> Option<*const u8> {
+    let entries = tables
+        .iter()
+        .map(|entry| (entry.guid, entry.address.cast::<u8>()));
+    config_table::find_preferred(entries, guids)
+}
+
+/// Offset of the `Revision` byte in the RSDP.
+const RSDP_REVISION_OFFSET: usize = 15;
+
+/// Find the ACPI RSDP, preferring the ACPI 2.0 table over the 1.0 one, and
+/// return it with its `Revision` byte.
+///
+/// The revision is read from the structure rather than inferred from the
+/// GUID, since it decides whether the kernel may use the XSDT address.
+///
+/// The configuration table is only reachable while boot services are
+/// active, so this has to run before exiting them.
+pub fn find_rsdp() -> Option<(*const u8, u8)> {
+    let rsdp = system::with_config_table(|tables| find_table(tables, &[ACPI2_GUID, ACPI_GUID]))?;
+    let revision = unsafe { rsdp.add(RSDP_REVISION_OFFSET).read() };
+    Some((rsdp, revision))
+}
+
+/// Offsets of the RSDT and XSDT addresses in the RSDP.
+const RSDT_ADDRESS_OFFSET: usize = 16;
+const XSDT_ADDRESS_OFFSET: usize = 24;
+
+/// The whole ACPI table at `addr`, sized by its header.
+///
+/// # Safety
+///
+/// `addr` must point to a readable ACPI table, which the firmware's
+/// identity map covers while boot services are active.
+unsafe fn table_at(addr: u64) -> &'static [u8] {
+    let header = unsafe { slice::from_raw_parts(addr as *const u8, acpi::HEADER_SIZE) };
+    let len = acpi::table_len(header).unwrap_or(0).max(acpi::HEADER_SIZE);
+    unsafe { slice::from_raw_parts(addr as *const u8, len) }
+}
+
+/// Find the ACPI table with `signature` through the XSDT of the RSDP at
+/// `rsdp`, or through the RSDT if `revision` is 0 and there is no XSDT.
+/// Only tables with a valid checksum count.
+///
+/// # Safety
+///
+/// `rsdp` and `revision` must come from [`find_rsdp`], and boot services
+/// must still be active.
+pub unsafe fn find_acpi_table(
+    rsdp: *const u8,
+    revision: u8,
+    signature: &[u8; 4],
+) -> Option<&'static [u8]> {
+    let (root_addr, entry_size) = if revision >= 2 {
+        let xsdt = unsafe { rsdp.add(XSDT_ADDRESS_OFFSET).cast::<u64>().read_unaligned() };
+        (xsdt, 8)
+    } else {
+        let rsdt = unsafe { rsdp.add(RSDT_ADDRESS_OFFSET).cast::<u32>().read_unaligned() };
+        (rsdt as u64, 4)
+    };
+    if root_addr == 0 {
+        return None;
+    }
+    let root = unsafe { table_at(root_addr) };
+    acpi::root_entries(root, entry_size)
+        .filter(|&addr| addr != 0)
+        .map(|addr| unsafe { table_at(addr) })
+        .find(|table| acpi::is_valid_table(table, signature))
+}
+
+/// The number of enabled processors in the MADT, or 1, for the processor
+/// the loader runs on, if there is no usable MADT.
+///
+/// # Safety
+///
+/// Like [`find_acpi_table`].
+pub unsafe fn count_cpus(rsdp: *const u8, revision: u8) -> u32 {
+    let madt = unsafe { find_acpi_table(rsdp, revision, acpi::MADT_SIGNATURE) };
+    match madt.and_then(acpi::count_enabled_cpus) {
+        Some(count) if count > 0 => count,
+        _ => 1,
+    }
+}
+
+/// Find the SMBIOS entry point, preferring the 64-bit SMBIOS 3.0 one over
+/// the 2.x one.
+///
+/// Like [`find_rsdp`], this has to run before exiting boot services.
+pub fn find_smbios() -> Option<*const u8> {
+    system::with_config_table(|tables| find_table(tables, &[SMBIOS3_GUID, SMBIOS_GUID]))
+}
+
+/// Copy the configuration table into LOADER_DATA pages that survive
+/// exit_boot_services, since the firmware may free its own copy then.
+///
+/// Like [`find_rsdp`], this has to run before exiting boot services.
+pub fn copy_config_tables() -> uefi::Result<ConfigTables> {
+    system::with_config_table(|tables| {
+        if tables.is_empty() {
+            return Ok(ConfigTables::empty());
+        }
+        let pages = size_of_val(tables).div_ceil(0x1000);
+        let base = PageAllocation::new(
+            boot::AllocateType::AnyPages,
+            boot::MemoryType::LOADER_DATA,
+            pages,
+        )?
+        .commit();
+        let entries = base.cast::<ConfigTable>().as_ptr();
+        for (i, table) in tables.iter().enumerate() {
+            let entry = ConfigTable {
+                guid: table.guid.to_bytes(),
+                address: table.address.cast::<u8>(),
+            };
+            unsafe { entries.add(i).write(entry) };
+        }
+        Ok(ConfigTables {
+            entries,
+            count: tables.len(),
+        })
+    })
+}
+
+/// The runtime services table of the running firmware, or null if the
+/// system table can't be found.
+pub fn find_runtime_services() -> *const u8 {
+    match uefi::table::system_table_raw() {
+        Some(st) => unsafe { st.as_ref().runtime_services.cast::<u8>() },
+        None => core::ptr::null(),
+    }
+}
@@ -0,0 +1,244 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+
+use mikanos_rs_loader_core::config::ConfigError;
+use mikanos_rs_loader_core::cpio::CpioError;
+use mikanos_rs_loader_core::elf::PlanError;
+use mikanos_rs_loader_core::memory;
+
+/// Errors that abort loading the kernel.
+#[derive(Debug)]
+pub enum LoaderError {
+    /// A UEFI call failed.
+    Uefi(uefi::Error),
+    /// The compressed kernel could not be decompressed.
+    Decompress(&'static str),
+    /// A file ended before the size the firmware reported for it.
+    ShortRead { expected: usize, read: usize },
+    /// A file of `size` bytes doesn't fit in the largest run of `free`
+    /// bytes of free memory, or in the address space at all.
+    FileTooLarge { size: u64, free: u64 },
+    /// The kernel file doesn't match the CRC-32 given in the config.
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// The kernel file doesn't match the SHA-256 digest in the named
+    /// digest file.
+    DigestMismatch(String),
+    /// `require_signature` is set but the named digest file doesn't exist.
+    MissingDigest(String),
+    /// The named digest file doesn't hold a SHA-256 digest.
+    BadDigest(String),
+    /// The kernel is not an ELF file this loader can run.
+    Elf(PlanError),
+    /// The boot archive is not a cpio archive in the newc format.
+    Archive(CpioError),
+    /// The loader image wasn't loaded from a device, so there is no boot
+    /// volume to read the config and kernel from.
+    NoDevice,
+    /// The loader's boot device has no file system to read the config and
+    /// kernel from.
+    NoFileSystem,
+    /// A file or one of its parent directories doesn't exist.
+    NotFound(String),
+    /// Neither the configured kernel nor the fallback exists. Holds the
+    /// paths that were tried.
+    KernelNotFound(Vec<String>),
+    /// A file was expected but the path names a directory.
+    IsDirectory(String),
+    /// The firmware failed to open a file or directory, after retrying if
+    /// the error looked transient.
+    OpenFailed { path: String, status: uefi::Status },
+    /// A path names something of the wrong kind.
+    BadPath { path: String, reason: &'static str },
+    /// The font file is not a usable PSF font.
+    BadFont(&'static str),
+    /// The loader configuration is malformed.
+    Config(ConfigError),
+    /// The config picks a display by an index past the `count` displays
+    /// the firmware has.
+    NoSuchDisplay { index: usize, count: usize },
+    /// A fixed-address module is linked over memory already in use.
+    Overlap { path: String, image: Range<u64> },
+    /// The kernel image ended up overlapping memory the loader allocated
+    /// for the handoff.
+    KernelOverlap {
+        region: &'static str,
+        image: Range<u64>,
+        other: Range<u64>,
+    },
+    /// A fixed-address kernel is linked at `range`, which the memory map
+    /// has as memory of type `ty` instead of free memory, or doesn't
+    /// describe at all if `ty` is `None`.
+    LinkedOverUsedMemory { range: Range<u64>, ty: Option<u32> },
+    /// The pages for the kernel image could not be allocated at the
+    /// physical address `addr`.
+    OutOfMemory { addr: u64, pages: usize },
+    /// No free block of `pages` pages was found for a kernel image that
+    /// is linked at `linked`, but can be loaded anywhere.
+    NoRoomForImage { linked: u64, pages: usize },
+    /// The kernel is linked in the higher half, but the loader can't build
+    /// the page tables that would map it there.
+    HigherHalfUnsupported,
+    /// `verify=true` found the kernel's entry point unmapped or not
+    /// executable in the kernel's page tables.
+    EntryNotExecutable(u64),
+    /// `entry_mode=protected` is set, but the kernel can't be started in
+    /// protected mode, for the given reason.
+    ProtectedMode(String),
+    /// `ExitBootServices` failed for a reason other than a stale map key,
+    /// or kept rejecting the key.
+    ExitBootServices(uefi::Status),
+}
+
+impl fmt::Display for LoaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Uefi(err) => write!(f, "UEFI error: {}", err),
+            Self::Decompress(reason) => write!(f, "failed to decompress kernel: {}", reason),
+            Self::ShortRead { expected, read } => {
+                write!(f, "file is truncated: read {} of {} bytes", read, expected)
+            }
+            Self::FileTooLarge { size, free } => write!(
+                f,
+                "file is too large to read: {} bytes, but the largest free block of memory is {} bytes",
+                size, free
+            ),
+            Self::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "kernel CRC-32 mismatch: expected {:#010x}, got {:#010x}",
+                expected, actual
+            ),
+            Self::DigestMismatch(path) => {
+                write!(f, "kernel doesn't match the SHA-256 digest in {}", path)
+            }
+            Self::MissingDigest(path) => write!(
+                f,
+                "{}: not found, and require_signature refuses an unverified kernel",
+                path
+            ),
+            Self::BadDigest(path) => write!(f, "{}: expected a SHA-256 digest in hex", path),
+            Self::Elf(err) => write!(f, "{}", err),
+            Self::Archive(err) => write!(f, "invalid boot archive: {}", err),
+            Self::NoDevice => write!(
+                f,
+                "the loader was not started from a device, as happens with some network boots; \
+                 boot it from a FAT partition that also holds the kernel"
+            ),
+            Self::NoFileSystem => write!(
+                f,
+                "the boot device has no readable file system; \
+                 boot the loader from a FAT partition that also holds the kernel"
+            ),
+            Self::NotFound(path) => write!(f, "{}: not found", path),
+            Self::KernelNotFound(paths) => {
+                write!(f, "no kernel found, tried")?;
+                for path in paths {
+                    write!(f, " {}", path)?;
+                }
+                Ok(())
+            }
+            Self::IsDirectory(path) => write!(f, "{}: is a directory", path),
+            Self::OpenFailed { path, status } => {
+                write!(f, "{}: failed to open: {:?}", path, status)
+            }
+            Self::BadPath { path, reason } => write!(f, "{}: {}", path, reason),
+            Self::BadFont(reason) => write!(f, "invalid font: {}", reason),
+            Self::Config(err) => write!(f, "invalid loader config: {}", err),
+            Self::NoSuchDisplay { index, count } => write!(
+                f,
+                "display={} is out of range: the firmware has {} display(s), numbered from 0",
+                index, count
+            ),
+            Self::Overlap { path, image } => write!(
+                f,
+                "{}: image at {:#x}..{:#x} overlaps one loaded earlier",
+                path, image.start, image.end
+            ),
+            Self::KernelOverlap {
+                region,
+                image,
+                other,
+            } => write!(
+                f,
+                "kernel image at {:#x}..{:#x} overlaps the {} at {:#x}..{:#x}",
+                image.start, image.end, region, other.start, other.end
+            ),
+            Self::LinkedOverUsedMemory { range, ty } => {
+                write!(
+                    f,
+                    "kernel is linked at {:#x}..{:#x}, which ",
+                    range.start, range.end
+                )?;
+                match ty.map(|ty| (ty, memory::type_name(ty))) {
+                    Some((_, Some(name))) => write!(f, "is {} memory", name)?,
+                    Some((ty, None)) => write!(f, "is memory of type {:#x}", ty)?,
+                    None => write!(f, "the memory map doesn't cover")?,
+                }
+                write!(f, "; link the kernel elsewhere or as a PIE (ET_DYN)")
+            }
+            Self::OutOfMemory { addr, pages } => {
+                write!(f, "failed to allocate {} pages at {:#x}", pages, addr)
+            }
+            Self::NoRoomForImage { linked, pages } => write!(
+                f,
+                "failed to allocate {} pages for the kernel image linked at {:#x}",
+                pages, linked
+            ),
+            Self::HigherHalfUnsupported => write!(
+                f,
+                "higher-half kernels can't be mapped while 5-level paging is active"
+            ),
+            Self::EntryNotExecutable(entry) => write!(
+                f,
+                "kernel entry point {:#x} is not mapped executable",
+                entry
+            ),
+            Self::ProtectedMode(reason) => {
+                write!(f, "can't start the kernel in protected mode: {}", reason)
+            }
+            Self::ExitBootServices(status) => {
+                write!(f, "failed to exit boot services: {:?}", status)
+            }
+        }
+    }
+}
+
+impl LoaderError {
+    /// The physical memory an allocation failure or overlap is about, to
+    /// show the memory map around it. Failures about link addresses, which
+    /// say nothing about where in physical memory the loader looked, have
+    /// none.
+    pub fn memory_range(&self) -> Option<Range<u64>> {
+        match self {
+            Self::LinkedOverUsedMemory { range, .. } => Some(range.clone()),
+            Self::OutOfMemory { addr, pages } => Some(*addr..*addr + *pages as u64 * 0x1000),
+            Self::KernelOverlap { image, .. } => Some(image.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl From<uefi::Error> for LoaderError {
+    fn from(err: uefi::Error) -> Self {
+        Self::Uefi(err)
+    }
+}
+
+impl From<PlanError> for LoaderError {
+    fn from(err: PlanError) -> Self {
+        Self::Elf(err)
+    }
+}
+
+impl From<CpioError> for LoaderError {
+    fn from(err: CpioError) -> Self {
+        Self::Archive(err)
+    }
+}
+
+impl From<ConfigError> for LoaderError {
+    fn from(err: ConfigError) -> Self {
+        Self::Config(err)
+    }
+}
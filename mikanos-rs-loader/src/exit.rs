@@ -0,0 +1,123 @@
+use core::slice;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use log::warn;
+use uefi::mem::memory_map::{MemoryMapKey, MemoryMapMeta, MemoryMapRef};
+use uefi::prelude::*;
+
+use crate::error::LoaderError;
+use crate::{logger, memmap};
+
+/// How many times to fetch the memory map and try to exit boot services
+/// before giving up.
+const EXIT_RETRIES: usize = 5;
+
+/// Set once [`exit_boot_services`] makes its first attempt. From then on
+/// the firmware only allows fetching the memory map into a buffer that
+/// already exists and exiting, even if every attempt failed.
+static EXITING: AtomicBool = AtomicBool::new(false);
+
+/// Whether boot services can still be used for anything else than exiting
+/// them.
+pub fn boot_services_usable() -> bool {
+    !EXITING.load(Ordering::Relaxed)
+}
+
+/// Extra pages in the memory map buffer for descriptors the map gains
+/// between sizing the buffer and exiting boot services.
+const MAP_BUFFER_SLACK_PAGES: usize = 2;
+
+/// Allocate LOADER_DATA pages for the memory map that is handed to the
+/// kernel.
+///
+/// The buffer has to exist before the exit loop starts, because allocating
+/// inside the loop would change the map it is trying to capture.
+pub fn allocate_map_buffer() -> Result<&'static mut [u8], LoaderError> {
+    let map_size = memmap::fetch_memory_map()?.bytes().len();
+    let page_size = 0x1000;
+    let page_cnt = map_size.div_ceil(page_size) + MAP_BUFFER_SLACK_PAGES;
+    let ptr = boot::allocate_pages(
+        boot::AllocateType::AnyPages,
+        boot::MemoryType::LOADER_DATA,
+        page_cnt,
+    )?;
+    unsafe {
+        Ok(slice::from_raw_parts_mut(
+            ptr.as_ptr(),
+            page_cnt * page_size,
+        ))
+    }
+}
+
+/// Fetch the memory map into `buf` and exit boot services with its key.
+///
+/// If the firmware rejects the key with `INVALID_PARAMETER` because the map
+/// changed in the meantime, the map is fetched again and the exit retried,
+/// up to [`EXIT_RETRIES`] times. Any other status, from either call, fails
+/// right away, since retrying can't fix it. Nothing between a successful
+/// fetch and the exit call allocates or logs.
+///
+/// Console logging is turned off before the first attempt, since the
+/// console may already be gone after a failed attempt. Serial logging keeps
+/// working, and each failed attempt is logged there.
+///
+/// # Safety
+///
+/// On success boot services are no longer available. Nothing that relies
+/// on them, including the global allocator, may be used afterwards.
+pub unsafe fn exit_boot_services(
+    buf: &'static mut [u8],
+) -> Result<MemoryMapRef<'static>, LoaderError> {
+    let unsupported = || uefi::Error::from(Status::UNSUPPORTED);
+    let st = uefi::table::system_table_raw().ok_or_else(unsupported)?;
+    let bt = unsafe { st.as_ref().boot_services.as_ref() }.ok_or_else(unsupported)?;
+    let image_handle = boot::image_handle().as_ptr();
+
+    logger::set_console(false);
+    EXITING.store(true, Ordering::Relaxed);
+
+    let mut status = Status::ABORTED;
+    for attempt in 1..=EXIT_RETRIES {
+        let mut map_size = buf.len();
+        let mut map_key = 0;
+        let mut desc_size = 0;
+        let mut desc_version = 0;
+        status = unsafe {
+            (bt.get_memory_map)(
+                &mut map_size,
+                buf.as_mut_ptr().cast(),
+                &mut map_key,
+                &mut desc_size,
+                &mut desc_version,
+            )
+        };
+        if status.is_error() {
+            // The buffer is as large as it gets, so a failed fetch won't go
+            // any better the next time.
+            warn!("GetMemoryMap failed: {:?}", status);
+            return Err(LoaderError::ExitBootServices(status));
+        }
+        status = unsafe { (bt.exit_boot_services)(image_handle, map_key) };
+        if status == Status::INVALID_PARAMETER {
+            warn!(
+                "ExitBootServices attempt {} of {}: stale map key, retrying",
+                attempt, EXIT_RETRIES
+            );
+            continue;
+        }
+        if status.is_success() {
+            let meta = MemoryMapMeta {
+                map_size,
+                desc_size,
+                // The key is meaningless once boot services are gone.
+                map_key: MemoryMapKey::default(),
+                desc_version,
+            };
+            return MemoryMapRef::new(buf, meta)
+                .map_err(|_| LoaderError::ExitBootServices(Status::BAD_BUFFER_SIZE));
+        }
+        warn!("ExitBootServices attempt {} failed: {:?}", attempt, status);
+        return Err(LoaderError::ExitBootServices(status));
+    }
+    Err(LoaderError::ExitBootServices(status))
+}
@@ -0,0 +1,77 @@
+use mikanos_rs_common::boot_info::{FontInfo, MemoryRegion};
+
+use crate::error::LoaderError;
+
+/// Path of the console font on the boot volume.
+pub const FONT_PATH: &str = "\\fonts\\default.psf";
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_HEADER_SIZE: u32 = 4;
+/// PSF1 mode bit for a 512-glyph font.
+const PSF1_MODE512: u8 = 0x01;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_MIN_HEADER_SIZE: u32 = 32;
+
+/// Read the glyph geometry from the PSF1 or PSF2 header of `font` and check
+/// that the file holds every glyph it claims to.
+pub fn parse(font: MemoryRegion) -> Result<FontInfo, LoaderError> {
+    let data = unsafe { core::slice::from_raw_parts(font.base, font.size) };
+    let mut info = if data.starts_with(&PSF1_MAGIC) {
+        parse_psf1(data)?
+    } else if data.starts_with(&PSF2_MAGIC) {
+        parse_psf2(data)?
+    } else {
+        return Err(LoaderError::BadFont("not a PSF1 or PSF2 file"));
+    };
+    if info.glyph_width == 0 || info.glyph_height == 0 {
+        return Err(LoaderError::BadFont("empty glyphs"));
+    }
+    let glyphs_end =
+        info.header_size as u64 + info.glyph_count as u64 * info.bytes_per_glyph as u64;
+    if glyphs_end > data.len() as u64 {
+        return Err(LoaderError::BadFont("file is shorter than its glyphs"));
+    }
+    info.base = font.base;
+    info.size = font.size;
+    Ok(info)
+}
+
+fn parse_psf1(data: &[u8]) -> Result<FontInfo, LoaderError> {
+    let [_, _, mode, charsize] = *data
+        .first_chunk::<4>()
+        .ok_or(LoaderError::BadFont("truncated PSF1 header"))?;
+    Ok(FontInfo {
+        header_size: PSF1_HEADER_SIZE,
+        glyph_count: if mode & PSF1_MODE512 != 0 { 512 } else { 256 },
+        glyph_width: 8,
+        glyph_height: charsize as u32,
+        bytes_per_glyph: charsize as u32,
+        ..FontInfo::empty()
+    })
+}
+
+fn parse_psf2(data: &[u8]) -> Result<FontInfo, LoaderError> {
+    let header = data
+        .first_chunk::<32>()
+        .ok_or(LoaderError::BadFont("truncated PSF2 header"))?;
+    let field = |i: usize| u32::from_le_bytes(header[4 * i..4 * i + 4].try_into().unwrap());
+    let info = FontInfo {
+        header_size: field(2),
+        glyph_count: field(4),
+        bytes_per_glyph: field(5),
+        glyph_height: field(6),
+        glyph_width: field(7),
+        ..FontInfo::empty()
+    };
+    if info.header_size < PSF2_MIN_HEADER_SIZE {
+        return Err(LoaderError::BadFont("PSF2 header is too small"));
+    }
+    if info.bytes_per_glyph as u64 != info.glyph_height as u64 * info.glyph_width.div_ceil(8) as u64
+    {
+        return Err(LoaderError::BadFont(
+            "glyph size doesn't match its dimensions",
+        ));
+    }
+    Ok(info)
+}
@@ -0,0 +1,152 @@
+use alloc::vec::Vec;
+
+use log::{info, warn};
+use mikanos_rs_common::boot_info::{self, FrameBufferInfo, PixelMasks};
+use mikanos_rs_common::framebuffer;
+use mikanos_rs_loader_core::gop::pick_mode;
+use uefi::boot::{OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol};
+use uefi::prelude::*;
+use uefi::proto::console::gop::{GraphicsOutput, Mode, ModeInfo};
+
+use crate::error::LoaderError;
+
+/// The most modes `list_modes` prints.
+const MAX_LISTED_MODES: usize = 32;
+
+/// Log every mode `gop` offers as a table of index, resolution and pixel
+/// format.
+pub fn list_modes(gop: &GraphicsOutput) {
+    info!("{:>5}  {:>11}  format", "index", "resolution");
+    let mut count = 0;
+    for (i, mode) in gop.modes().enumerate() {
+        count += 1;
+        if i >= MAX_LISTED_MODES {
+            continue;
+        }
+        let (width, height) = mode.info().resolution();
+        info!(
+            "{:>5}  {:>5}x{:<5}  {:?}",
+            i,
+            width,
+            height,
+            mode.info().pixel_format()
+        );
+    }
+    if count > MAX_LISTED_MODES {
+        info!("... and {} more", count - MAX_LISTED_MODES);
+    }
+}
+
+/// Switch to the mode whose resolution matches `preferred`, or keep the
+/// current one if there is no preference.
+///
+/// Falls back to the current mode with a warning if no mode matches.
+/// Returns the info of the mode in effect afterwards.
+pub fn select_graphics_mode(
+    gop: &mut GraphicsOutput,
+    preferred: Option<(usize, usize)>,
+) -> uefi::Result<ModeInfo> {
+    let Some(preferred) = preferred else {
+        let mode_info = gop.current_mode_info();
+        let (width, height) = mode_info.resolution();
+        info!("Keeping graphics mode {}x{}", width, height);
+        return Ok(mode_info);
+    };
+    let modes: Vec<Mode> = gop.modes().collect();
+    let candidates: Vec<_> = modes
+        .iter()
+        .map(|mode| {
+            let (width, height) = mode.info().resolution();
+            let format = boot_info::PixelFormat::from_uefi(mode.info().pixel_format() as u32);
+            (width, height, format)
+        })
+        .collect();
+    match pick_mode(&candidates, preferred).map(|i| &modes[i]) {
+        Some(mode) => {
+            gop.set_mode(mode)?;
+            info!("Selected graphics mode {}x{}", preferred.0, preferred.1);
+            Ok(*mode.info())
+        }
+        None => {
+            let mode_info = gop.current_mode_info();
+            let (width, height) = mode_info.resolution();
+            warn!(
+                "No graphics mode for {}x{}, using {}x{}",
+                preferred.0, preferred.1, width, height,
+            );
+            Ok(mode_info)
+        }
+    }
+}
+
+/// Open the `n`th of the handles that support GOP, one per display on
+/// most firmware. Logs the current resolution of every handle first, so
+/// the right index can be picked for the `display=` config.
+///
+/// Fails with `NOT_FOUND` if there is no GOP at all, and with
+/// [`LoaderError::NoSuchDisplay`] if there are fewer than `n + 1`.
+pub fn open_gop_index(n: usize) -> Result<ScopedProtocol<GraphicsOutput>, LoaderError> {
+    let handles = boot::find_handles::<GraphicsOutput>()?;
+    for (i, &handle) in handles.iter().enumerate() {
+        // Only peek at the mode: an exclusive open would disconnect the
+        // console driver from displays the loader doesn't end up using.
+        let params = OpenProtocolParams {
+            handle,
+            agent: boot::image_handle(),
+            controller: None,
+        };
+        let gop = unsafe {
+            boot::open_protocol::<GraphicsOutput>(params, OpenProtocolAttributes::GetProtocol)
+        };
+        match gop {
+            Ok(gop) => {
+                let (width, height) = gop.current_mode_info().resolution();
+                info!("Display {}: {}x{}", i, width, height);
+            }
+            Err(err) => info!("Display {}: {:?}", i, err.status()),
+        }
+    }
+    let &handle = handles.get(n).ok_or(LoaderError::NoSuchDisplay {
+        index: n,
+        count: handles.len(),
+    })?;
+    Ok(boot::open_protocol_exclusive::<GraphicsOutput>(handle)?)
+}
+
+/// Switch display `display` to the `preferred` resolution, if there is one
+/// and it is available, and query its linear framebuffer. With
+/// `show_modes`, log the available modes first.
+///
+/// Fails with `UNSUPPORTED` if the mode is `BltOnly`, since there is no
+/// framebuffer the kernel could draw to.
+pub fn open_gop(
+    display: usize,
+    preferred: Option<(usize, usize)>,
+    show_modes: bool,
+) -> Result<FrameBufferInfo, LoaderError> {
+    let mut gop = open_gop_index(display)?;
+
+    if show_modes {
+        list_modes(&gop);
+    }
+    let mode_info = select_graphics_mode(&mut gop, preferred)?;
+    let (width, height) = mode_info.resolution();
+    let masks = mode_info
+        .pixel_bitmask()
+        .map_or(FrameBufferInfo::empty().masks, |mask| PixelMasks {
+            red: mask.red,
+            green: mask.green,
+            blue: mask.blue,
+            reserved: mask.reserved,
+        });
+    let mode = framebuffer::ModeInfo {
+        horizontal_resolution: width as u32,
+        vertical_resolution: height as u32,
+        pixel_format: mode_info.pixel_format() as u32,
+        pixel_information: masks,
+        pixels_per_scan_line: mode_info.stride() as u32,
+    };
+    let mut frame_buffer = gop.frame_buffer();
+    framebuffer::framebuffer_info(frame_buffer.as_mut_ptr(), frame_buffer.size(), &mode)
+        .ok_or_else(|| uefi::Error::from(Status::UNSUPPORTED).into())
+}
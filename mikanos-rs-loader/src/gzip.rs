@@ -0,0 +1,76 @@
+use alloc::vec::Vec;
+
+use crate::error::LoaderError;
+
+/// The first two bytes of every gzip member.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The only compression method gzip defines (deflate).
+const CM_DEFLATE: u8 = 8;
+
+const FHCRC: u8 = 1 << 1;
+const FEXTRA: u8 = 1 << 2;
+const FNAME: u8 = 1 << 3;
+const FCOMMENT: u8 = 1 << 4;
+
+/// Size of the fixed gzip header.
+const HEADER_SIZE: usize = 10;
+
+/// Size of the CRC32 and ISIZE trailer.
+const TRAILER_SIZE: usize = 8;
+
+/// Whether `data` starts with the gzip magic.
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&GZIP_MAGIC)
+}
+
+/// Decompress a single gzip member.
+///
+/// The length recorded in the trailer is checked against the output; the
+/// CRC32 is not.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, LoaderError> {
+    if data.len() < HEADER_SIZE + TRAILER_SIZE || !is_gzip(data) {
+        return Err(LoaderError::Decompress("truncated gzip header"));
+    }
+    if data[2] != CM_DEFLATE {
+        return Err(LoaderError::Decompress(
+            "unsupported gzip compression method",
+        ));
+    }
+    let flags = data[3];
+    let body_end = data.len() - TRAILER_SIZE;
+
+    // Skip the optional header fields, in the order RFC 1952 puts them.
+    let mut pos = HEADER_SIZE;
+    if flags & FEXTRA != 0 {
+        let xlen = data
+            .get(pos..pos + 2)
+            .ok_or(LoaderError::Decompress("truncated gzip header"))?;
+        pos += 2 + u16::from_le_bytes([xlen[0], xlen[1]]) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let len = data
+                .get(pos..body_end)
+                .and_then(|rest| rest.iter().position(|&b| b == 0))
+                .ok_or(LoaderError::Decompress("truncated gzip header"))?;
+            pos += len + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+    let body = data
+        .get(pos..body_end)
+        .ok_or(LoaderError::Decompress("truncated gzip header"))?;
+
+    let out = miniz_oxide::inflate::decompress_to_vec(body)
+        .map_err(|_| LoaderError::Decompress("corrupt deflate stream"))?;
+
+    let trailer = &data[body_end..];
+    let isize = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if out.len() as u32 != isize {
+        return Err(LoaderError::Decompress("gzip length mismatch"));
+    }
+    Ok(out)
+}
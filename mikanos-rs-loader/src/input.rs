@@ -0,0 +1,40 @@
+//! Blocking key input from the UEFI console.
+
+use core::time::Duration;
+
+use uefi::boot::{self, EventType, TimerTrigger, Tpl};
+use uefi::proto::console::text::Key;
+use uefi::system;
+
+/// Wait for the next key press on the console input, either a Unicode
+/// character or the scan code of a special key.
+///
+/// The CPU sleeps in `boot::wait_for_event` instead of polling. With a
+/// `timeout`, gives up and returns `None` once it elapses. Also returns
+/// `None` if the console has no input or the firmware reports an error.
+pub fn read_key(timeout: Option<Duration>) -> Option<Key> {
+    system::with_stdin(|stdin| -> uefi::Result<Option<Key>> {
+        let key_event = stdin
+            .wait_for_key_event()
+            .ok_or(uefi::Status::UNSUPPORTED)?;
+        let Some(timeout) = timeout else {
+            boot::wait_for_event(&mut [key_event]).map_err(|err| err.to_err_without_payload())?;
+            return stdin.read_key();
+        };
+        let timer = unsafe { boot::create_event(EventType::TIMER, Tpl::APPLICATION, None, None)? };
+        let mut events = [key_event, unsafe { timer.unsafe_clone() }];
+        // The timer counts in units of 100ns.
+        let ticks = (timeout.as_nanos() / 100) as u64;
+        // Close the timer whether or not the wait worked.
+        let index = boot::set_timer(&timer, TimerTrigger::Relative(ticks)).and_then(|()| {
+            boot::wait_for_event(&mut events).map_err(|err| err.to_err_without_payload())
+        });
+        boot::close_event(timer)?;
+        if index? == 1 {
+            return Ok(None);
+        }
+        stdin.read_key()
+    })
+    .ok()
+    .flatten()
+}
@@ -0,0 +1,139 @@
+use core::fmt::{self, Write};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+
+use log::{LevelFilter, Log, Metadata, Record};
+use uefi::boot::{self, AllocateType, MemoryType};
+use uefi::system;
+
+use mikanos_rs_common::boot_info::BootLog;
+use mikanos_rs_common::memory_type;
+use mikanos_rs_common::serial::{self, SerialPort};
+
+/// Size of the boot log handed to the kernel, a multiple of the page size.
+const BOOT_LOG_SIZE: usize = 16 * 1024;
+
+static LOGGER: Logger = Logger {
+    console: AtomicBool::new(false),
+    serial: AtomicBool::new(false),
+    log_base: AtomicPtr::new(ptr::null_mut()),
+    log_written: AtomicU64::new(0),
+};
+
+/// Logger that writes to the UEFI text console and mirrors every message
+/// to COM1 and to the boot log for the kernel.
+///
+/// The console is only usable while boot services are active; the serial
+/// port and the boot log keep working after exit_boot_services.
+struct Logger {
+    console: AtomicBool,
+    serial: AtomicBool,
+    /// The [`BootLog`] buffer, or null if it couldn't be allocated.
+    log_base: AtomicPtr<u8>,
+    log_written: AtomicU64,
+}
+
+/// Appends to a [`BootLog`] whose buffer the loader allocated.
+struct BootLogWriter<'a>(&'a mut BootLog);
+
+impl Write for BootLogWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        unsafe { self.0.append(s.as_bytes()) };
+        Ok(())
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let file = record.file().unwrap_or("<unknown file>");
+        let line = record.line().unwrap_or(0);
+        let mut boot_log = boot_log();
+        if !boot_log.base.is_null() {
+            let _ = writeln!(
+                BootLogWriter(&mut boot_log),
+                "[{:>5}]: {}@{:03}: {}",
+                record.level(),
+                file,
+                line,
+                record.args(),
+            );
+            self.log_written.store(boot_log.written, Ordering::Release);
+        }
+        if self.serial.load(Ordering::Acquire) {
+            let _ = writeln!(
+                SerialPort::com1(),
+                "[{:>5}]: {}@{:03}: {}",
+                record.level(),
+                file,
+                line,
+                record.args(),
+            );
+        }
+        if self.console.load(Ordering::Acquire) {
+            system::with_stdout(|stdout| {
+                let _ = writeln!(
+                    stdout,
+                    "[{:>5}]: {}@{:03}: {}",
+                    record.level(),
+                    file,
+                    line,
+                    record.args(),
+                );
+            });
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the loader logger, writing to the UEFI console, COM1 and the
+/// boot log.
+///
+/// Call this at the top of `main`, before anything logs.
+pub fn init() {
+    if serial::init() {
+        serial::print_banner("mikanos-rs-loader");
+        LOGGER.serial.store(true, Ordering::Release);
+    }
+    // Without the buffer, the kernel just gets no boot log.
+    let pages = boot::allocate_pages(
+        AllocateType::AnyPages,
+        MemoryType::custom(memory_type::BOOT_LOG),
+        BOOT_LOG_SIZE / 0x1000,
+    );
+    if let Ok(base) = pages {
+        LOGGER.log_base.store(base.as_ptr(), Ordering::Release);
+    }
+    LOGGER.console.store(true, Ordering::Release);
+    // Can only fail if a logger is already installed.
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(LevelFilter::Info);
+}
+
+/// Turn the UEFI console output on or off. Serial output is unaffected.
+pub fn set_console(enabled: bool) {
+    LOGGER.console.store(enabled, Ordering::Release);
+}
+
+/// The boot log as far as it is written, for `BootInfo::boot_log`. Empty if
+/// the buffer couldn't be allocated.
+pub fn boot_log() -> BootLog {
+    let base = LOGGER.log_base.load(Ordering::Acquire);
+    if base.is_null() {
+        return BootLog::empty();
+    }
+    BootLog {
+        base,
+        capacity: BOOT_LOG_SIZE,
+        written: LOGGER.log_written.load(Ordering::Acquire),
+    }
+}
+
+/// Whether log messages currently go to the UEFI console.
+pub fn console_enabled() -> bool {
+    LOGGER.console.load(Ordering::Acquire)
+}
@@ -2,145 +2,1833 @@
 #![no_main]
 
 extern crate alloc;
-use alloc::{format, vec, vec::Vec};
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::{vec, vec::Vec};
 
-use core::slice;
+mod allocation;
+mod config;
+mod config_table;
+mod error;
+mod exit;
+mod font;
+mod gop;
+mod gzip;
+mod input;
+mod logger;
+mod memmap;
+mod menu;
+mod paging;
+mod panic;
+mod progress;
+#[cfg(feature = "segment-permissions")]
+mod protect;
+#[cfg(feature = "protected-mode")]
+mod protected;
+mod stack;
+mod timing;
+mod verify;
 
-use goblin::elf;
-use log::info;
+use core::convert::Infallible;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::{ptr, slice};
+
+use allocation::PageAllocation;
+use config::LoaderConfig;
+use error::LoaderError;
+use goblin::elf::program_header::{PF_W, PF_X};
+use log::{debug, error, info, warn};
+use mikanos_rs_common::boot_info::{
+    BootInfo, BootTime, BuildId, ConfigTable, FrameBufferInfo, LoaderVersion, MAX_CMDLINE_LEN,
+    MemoryDescriptors, MemoryMapInfo, MemoryRegion, ModuleInfo, PixelMasks,
+};
+use mikanos_rs_common::memory_type;
+use mikanos_rs_loader_core::bmp;
+use mikanos_rs_loader_core::config::{EntryAbi, EntryMode, OnError, OnKernelReturn};
+use mikanos_rs_loader_core::cpio;
+use mikanos_rs_loader_core::crc32::crc32;
+use mikanos_rs_loader_core::elf;
+use mikanos_rs_loader_core::handoff;
+use mikanos_rs_loader_core::memory;
+use mikanos_rs_loader_core::note;
+use mikanos_rs_loader_core::sha256;
+use mikanos_rs_loader_core::util;
+use progress::ProgressBar;
 use uefi::mem::memory_map::MemoryMap;
 use uefi::prelude::*;
+use uefi::proto::ProtocolPointer;
 use uefi::proto::loaded_image::LoadedImage;
 use uefi::proto::media::file::{
-    Directory, File, FileAttribute, FileHandle, FileInfo, FileMode, RegularFile,
+    Directory, File, FileAttribute, FileHandle, FileInfo, FileMode, FileSystemInfo, RegularFile,
 };
 use uefi::proto::media::fs::SimpleFileSystem;
+use uefi::runtime::{self, ResetType};
+use uefi::{CStr16, CString16};
 
-fn open_root_dir() -> uefi::Result<Directory> {
-    let loaded_image = boot::open_protocol_exclusive::<LoadedImage>(boot::image_handle())?;
-    let device_handle = loaded_image.device().expect("Device handle should exist.");
-    let mut fs = boot::open_protocol_exclusive::<SimpleFileSystem>(device_handle)?;
-    fs.open_volume()
-}
-
-fn save_memory_map(file: FileHandle) -> uefi::Result {
-    let mut file = file.into_regular_file().unwrap();
-
-    // Print header
-    let header = "Index, Type, Type(name), PhysicalStart, NumberOfPages, Attribute\n";
-    file.write(header.as_bytes()).unwrap();
-
-    let memory_map = boot::memory_map(boot::MemoryType::LOADER_DATA)?;
-    for (i, desc) in memory_map.entries().enumerate() {
-        file.write(
-            format!(
-                "{}, {:#x}, {:?}, {:#08x}, {}, {:#x}\n",
-                i,
-                desc.ty.0,
-                desc.ty,
-                desc.phys_start,
-                desc.page_count,
-                desc.att.bits() & 0xfffff,
-            )
-            .as_bytes(),
-        )
-        .unwrap();
+/// Open protocol `P` on `handle` exclusively, run `f` on it and close it
+/// again before returning, whatever `f` returns.
+///
+/// An exclusive open disconnects every other driver using the protocol
+/// until it is closed, so the loader never keeps one open longer than a
+/// single call. Anything `f` obtained through the protocol that is its own
+/// handle, such as a `Directory` from `SimpleFileSystem::open_volume`,
+/// stays valid after the protocol is closed.
+fn with_protocol<P: ProtocolPointer + ?Sized, T>(
+    handle: Handle,
+    f: impl FnOnce(&mut P) -> T,
+) -> uefi::Result<T> {
+    let mut protocol = boot::open_protocol_exclusive::<P>(handle)?;
+    let result = f(&mut protocol);
+    drop(protocol);
+    Ok(result)
+}
+
+/// Open the root directory of the file system the loader was loaded from.
+///
+/// Fails with [`LoaderError::NoDevice`] if the loader didn't come from a
+/// device, as with some network boots, and with
+/// [`LoaderError::NoFileSystem`] if the device has no file system the
+/// firmware can read.
+///
+/// `LoadedImage` is closed before `SimpleFileSystem` is opened, and that in
+/// turn as soon as the root directory is open.
+fn open_root_dir() -> Result<Directory, LoaderError> {
+    let device_handle =
+        with_protocol::<LoadedImage, _>(boot::image_handle(), |image| image.device())?
+            .ok_or(LoaderError::NoDevice)?;
+    match with_protocol::<SimpleFileSystem, _>(device_handle, |fs| fs.open_volume()) {
+        Ok(root_dir) => Ok(root_dir?),
+        Err(err) if err.status() == Status::UNSUPPORTED => Err(LoaderError::NoFileSystem),
+        Err(err) => Err(err.into()),
     }
-    Ok(())
 }
 
-fn read_file(file: &mut RegularFile) -> uefi::Result<Vec<u8>> {
-    let info = file.get_boxed_info::<FileInfo>()?;
-    let size = info.file_size() as usize;
-    let mut buf = vec![0; size];
-    file.read(&mut buf)?;
+/// The physical range the firmware loaded the loader's own image into.
+///
+/// The firmware may put the loader anywhere and applies the image's base
+/// relocations, so nothing may assume it runs at its link address. This
+/// asks `LoadedImage` instead.
+fn loader_image_range() -> uefi::Result<Range<u64>> {
+    let (base, size) = with_protocol::<LoadedImage, _>(boot::image_handle(), |image| image.info())?;
+    Ok(base as u64..base as u64 + size)
+}
+
+/// Open the root directory of the file system whose volume label is
+/// `label`, ignoring ASCII case as FAT does. Returns `Ok(None)` if no file
+/// system has the label.
+///
+/// File systems that can't be opened or queried are skipped.
+fn open_root_dir_by_label(label: &str) -> Result<Option<Directory>, LoaderError> {
+    for handle in boot::find_handles::<SimpleFileSystem>()? {
+        let Ok(Ok(mut root_dir)) =
+            with_protocol::<SimpleFileSystem, _>(handle, |fs| fs.open_volume())
+        else {
+            continue;
+        };
+        let Ok(info) = root_dir.get_boxed_info::<FileSystemInfo>() else {
+            continue;
+        };
+        if info.volume_label().to_string().eq_ignore_ascii_case(label) {
+            return Ok(Some(root_dir));
+        }
+    }
+    Ok(None)
+}
+
+/// How many more times [`open_entry`] tries after a transient failure.
+const OPEN_RETRIES: usize = 3;
+
+/// How long [`open_entry`] waits before each retry, in microseconds.
+const OPEN_RETRY_DELAY_US: usize = 100_000;
+
+/// Open `name` in `dir` with `mode`, retrying a few times if the device
+/// reports a transient error, as some USB media do early on.
+///
+/// A missing file is reported as [`LoaderError::NotFound`] right away, and
+/// any other failure as [`LoaderError::OpenFailed`].
+fn open_entry(
+    dir: &mut Directory,
+    name: &CStr16,
+    mode: FileMode,
+) -> Result<FileHandle, LoaderError> {
+    let mut retries = 0;
+    loop {
+        match dir.open(name, mode, FileAttribute::empty()) {
+            Ok(handle) => return Ok(handle),
+            Err(err) if err.status() == Status::NOT_FOUND => {
+                return Err(LoaderError::NotFound(name.to_string()));
+            }
+            Err(err)
+                if retries < OPEN_RETRIES
+                    && matches!(err.status(), Status::DEVICE_ERROR | Status::NOT_READY) =>
+            {
+                retries += 1;
+                warn!(
+                    "Opening {} failed with {:?}, retrying ({}/{})",
+                    name,
+                    err.status(),
+                    retries,
+                    OPEN_RETRIES
+                );
+                boot::stall(OPEN_RETRY_DELAY_US);
+            }
+            Err(err) => {
+                return Err(LoaderError::OpenFailed {
+                    path: name.to_string(),
+                    status: err.status(),
+                });
+            }
+        }
+    }
+}
+
+/// Open the regular file `path` in `dir` with `mode`, see [`open_entry`].
+///
+/// A directory is reported as [`LoaderError::IsDirectory`].
+fn open_file(
+    dir: &mut Directory,
+    path: &CStr16,
+    mode: FileMode,
+) -> Result<RegularFile, LoaderError> {
+    open_entry(dir, path, mode)?
+        .into_regular_file()
+        .ok_or_else(|| LoaderError::IsDirectory(path.to_string()))
+}
+
+/// Open the regular file at `path`, walking one directory at a time from
+/// `root_dir`.
+///
+/// `path` is a `\`-separated path such as `\EFI\mikanos\kernel.elf`. A
+/// missing component is reported as [`LoaderError::NotFound`] naming the
+/// part of the path that was looked up.
+fn open_path(root_dir: &mut Directory, path: &str) -> Result<RegularFile, LoaderError> {
+    let mut components = path.split('\\').filter(|c| !c.is_empty()).peekable();
+    let mut dir: Option<Directory> = None;
+    let mut opened = String::new();
+    while let Some(component) = components.next() {
+        opened.push('\\');
+        opened.push_str(component);
+        let name = CString16::try_from(component).map_err(|_| LoaderError::BadPath {
+            path: opened.clone(),
+            reason: "is not a valid UEFI path",
+        })?;
+        let parent = match dir.as_mut() {
+            Some(dir) => dir,
+            None => &mut *root_dir,
+        };
+        if components.peek().is_none() {
+            return open_file(parent, &name, FileMode::Read).map_err(|err| with_path(err, opened));
+        }
+        let handle = open_entry(parent, &name, FileMode::Read)
+            .map_err(|err| with_path(err, opened.clone()))?;
+        dir = Some(handle.into_directory().ok_or(LoaderError::BadPath {
+            path: opened.clone(),
+            reason: "is not a directory",
+        })?);
+    }
+    Err(LoaderError::BadPath {
+        path: path.to_string(),
+        reason: "is empty",
+    })
+}
+
+/// `err` from opening one component of a path, naming all of `path` up to
+/// that component instead.
+fn with_path(err: LoaderError, path: String) -> LoaderError {
+    match err {
+        LoaderError::NotFound(_) => LoaderError::NotFound(path),
+        LoaderError::IsDirectory(_) => LoaderError::IsDirectory(path),
+        LoaderError::OpenFailed { status, .. } => LoaderError::OpenFailed { path, status },
+        err => err,
+    }
+}
+
+/// How many bytes `read_file` asks the firmware for at a time.
+const READ_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The size of `file` as its `FileInfo` reports it.
+fn file_len(file: &mut RegularFile) -> Result<usize, LoaderError> {
+    let size = file.get_boxed_info::<FileInfo>()?.file_size();
+    usize::try_from(size).map_err(|_| LoaderError::FileTooLarge { size, free: 0 })
+}
+
+/// Read the whole of `file` from its current position.
+///
+/// Fails with [`LoaderError::FileTooLarge`] if there is no free run of
+/// memory the file fits in, before trying to allocate the buffer, and with
+/// [`LoaderError::ShortRead`] if the file ends before the size its
+/// `FileInfo` reports, instead of handing back a zero-padded buffer.
+fn read_file(file: &mut RegularFile) -> Result<Vec<u8>, LoaderError> {
+    let len = file_len(file)?;
+    // The buffer is one pool allocation, so it needs contiguous pages.
+    let free = memmap::summary()?.largest_free_pages * 0x1000;
+    let too_large = LoaderError::FileTooLarge {
+        size: len as u64,
+        free,
+    };
+    if len as u64 > free {
+        return Err(too_large);
+    }
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(len).map_err(|_| too_large)?;
+    buf.resize(len, 0);
+    read_exact(file, &mut buf)?;
     Ok(buf)
 }
 
-fn load_elf(elf_data: &[u8]) -> elf::Elf {
-    let prog = elf::Elf::parse(elf_data).unwrap();
+/// Read up to `len` bytes of `file` starting at `offset`.
+///
+/// The result is shorter than `len` only if the file ends first, and empty
+/// if `offset` is past the end. The file position is left right after the
+/// last byte read, so callers that care about it must set it again.
+fn read_file_at(file: &mut RegularFile, offset: u64, len: usize) -> uefi::Result<Vec<u8>> {
+    file.set_position(offset)?;
+    let mut buf = vec![0; len];
+    let read = read_into(file, &mut buf)?;
+    buf.truncate(read);
+    Ok(buf)
+}
 
-    // Calculate address range
-    let mut addr_start = usize::MAX;
-    let mut addr_end = 0;
-    for phdr in prog.program_headers.iter() {
-        if phdr.p_type != elf::program_header::PT_LOAD {
-            continue;
+/// Fill all of `buf` from the current position of `file`.
+fn read_exact(file: &mut RegularFile, buf: &mut [u8]) -> Result<(), LoaderError> {
+    let read = read_into(file, buf)?;
+    if read != buf.len() {
+        return Err(LoaderError::ShortRead {
+            expected: buf.len(),
+            read,
+        });
+    }
+    Ok(())
+}
+
+/// Fill `buf` from the current position of `file` and return how many
+/// bytes were read, which is less than `buf.len()` only at the end of the
+/// file.
+///
+/// The file is read in [`READ_CHUNK_SIZE`] pieces, so the firmware never
+/// has to handle one huge read request.
+fn read_into(file: &mut RegularFile, buf: &mut [u8]) -> uefi::Result<usize> {
+    let mut pos = 0;
+    while pos < buf.len() {
+        let end = usize::min(pos + READ_CHUNK_SIZE, buf.len());
+        let read = file.read(&mut buf[pos..end]).discard_errdata()?;
+        if read == 0 {
+            break;
         }
-        addr_start = usize::min(addr_start, phdr.p_vaddr as usize);
-        addr_end = usize::max(addr_end, (phdr.p_vaddr + phdr.p_memsz) as usize);
+        pos += read;
+    }
+    Ok(pos)
+}
+
+/// Copy the file at `path` into LOADER_DATA pages that survive
+/// exit_boot_services.
+///
+/// A missing or empty file yields an empty region instead of an error.
+fn load_file_to_pages(root_dir: &mut Directory, path: &str) -> Result<MemoryRegion, LoaderError> {
+    let mut file = match open_path(root_dir, path) {
+        Ok(file) => file,
+        Err(LoaderError::NotFound(_)) => return Ok(MemoryRegion::empty()),
+        Err(err) => return Err(err),
+    };
+    let buf = read_file(&mut file)?;
+    copy_to_pages(&buf)
+}
+
+/// Copy `buf` into LOADER_DATA pages that survive exit_boot_services, or
+/// return an empty region if `buf` is empty. The rest of the last page is
+/// zeroed, so a kernel that maps whole pages doesn't see firmware leftovers
+/// past the end.
+fn copy_to_pages(buf: &[u8]) -> Result<MemoryRegion, LoaderError> {
+    if buf.is_empty() {
+        return Ok(MemoryRegion::empty());
     }
 
-    // Allocate memory for kernel image
-    let memsz = addr_end - addr_start;
     let page_size = 0x1000;
-    let page_cnt = (memsz + page_size - 1) / page_size;
-    boot::allocate_pages(
-        boot::AllocateType::Address(addr_start as u64),
+    let page_cnt = buf.len().div_ceil(page_size);
+    let base = PageAllocation::new(
+        boot::AllocateType::AnyPages,
         boot::MemoryType::LOADER_DATA,
         page_cnt,
-    )
-    .unwrap();
+    )?
+    .commit();
+    let dest = unsafe { slice::from_raw_parts_mut(base.as_ptr(), page_cnt * page_size) };
+    let (data, tail) = dest.split_at_mut(buf.len());
+    data.copy_from_slice(buf);
+    tail.fill(0);
+    Ok(MemoryRegion {
+        base: base.as_ptr(),
+        size: buf.len(),
+    })
+}
 
-    // Copy loadable segments
-    for phdr in prog.program_headers.iter() {
-        if phdr.p_type != elf::program_header::PT_LOAD {
-            continue;
+/// Copy `cmdline` into LOADER_DATA pages that survive
+/// exit_boot_services, followed by a NUL for kernels that expect a C
+/// string.
+///
+/// Anything past [`MAX_CMDLINE_LEN`] is dropped, though the config parser
+/// already rejects longer lines. An empty command line yields an empty
+/// region.
+fn load_cmdline(cmdline: &str) -> Result<MemoryRegion, LoaderError> {
+    let mut len = usize::min(cmdline.len(), MAX_CMDLINE_LEN);
+    // Don't split a character, so the kernel always gets valid UTF-8.
+    while !cmdline.is_char_boundary(len) {
+        len -= 1;
+    }
+    if len == 0 {
+        return Ok(MemoryRegion::empty());
+    }
+
+    let page_size = 0x1000;
+    let page_cnt = (len + 1).div_ceil(page_size);
+    let base = PageAllocation::new(
+        boot::AllocateType::AnyPages,
+        boot::MemoryType::LOADER_DATA,
+        page_cnt,
+    )?
+    .commit();
+    let dest = unsafe { slice::from_raw_parts_mut(base.as_ptr(), len + 1) };
+    dest[..len].copy_from_slice(&cmdline.as_bytes()[..len]);
+    dest[len] = 0;
+    Ok(MemoryRegion {
+        base: base.as_ptr(),
+        size: len,
+    })
+}
+
+/// Allocate zeroed LOADER_DATA pages for a back buffer the size of
+/// `framebuffer`. A missing framebuffer yields an empty region.
+fn allocate_back_buffer(framebuffer: &FrameBufferInfo) -> Result<MemoryRegion, LoaderError> {
+    if framebuffer.base.is_null() || framebuffer.size == 0 {
+        return Ok(MemoryRegion::empty());
+    }
+    let page_size = 0x1000;
+    let base = PageAllocation::new(
+        boot::AllocateType::AnyPages,
+        boot::MemoryType::LOADER_DATA,
+        framebuffer.size.div_ceil(page_size),
+    )?
+    .commit();
+    unsafe { ptr::write_bytes(base.as_ptr(), 0, framebuffer.size) };
+    Ok(MemoryRegion {
+        base: base.as_ptr(),
+        size: framebuffer.size,
+    })
+}
+
+/// Where an ELF image ended up in memory.
+struct LoadedElf {
+    /// Entry point address, with the load bias applied.
+    entry: u64,
+    /// Addresses spanned by the loaded segments, with the load bias
+    /// applied.
+    image: Range<u64>,
+    /// Physical address of `image.start`. The same as `image.start` unless
+    /// the image is linked in the higher half and has to be mapped there.
+    phys_start: u64,
+    /// The whole pages of the `PT_GNU_RELRO` region, with the load bias
+    /// applied.
+    relro: Option<Range<u64>>,
+    /// The loaded segments, in the order they were copied.
+    segments: Vec<LoadedSegment>,
+    /// The whole pages of segments without `PF_X` that no executable
+    /// segment shares, with the load bias applied.
+    no_execute: Vec<Range<u64>>,
+    /// The same for segments without `PF_W`, or nothing without the
+    /// `segment-permissions` feature.
+    read_only: Vec<Range<u64>>,
+    /// From the image's notes, set by the caller of [`load_plan`].
+    build_id: BuildId,
+}
+
+/// A segment of a loaded ELF image, as [`verify`] checks it.
+struct LoadedSegment {
+    /// Addresses the segment spans, with the load bias applied.
+    range: Range<u64>,
+    /// The segment's first byte once it was loaded, if it has any.
+    first_byte: Option<u8>,
+}
+
+impl LoadedElf {
+    /// The physical memory holding the image.
+    fn phys_range(&self) -> Range<u64> {
+        self.phys_start..self.phys_start + (self.image.end - self.image.start)
+    }
+}
+
+/// Allocate `page_cnt` pages for a relocatable image linked at
+/// `image_start` and return the allocation and the address the image
+/// starts at.
+///
+/// The link address is tried first. If it is taken, the firmware picks the
+/// pages and the returned base is aligned to `align`.
+fn allocate_relocatable(
+    image_start: usize,
+    page_cnt: usize,
+    align: usize,
+    mem_ty: boot::MemoryType,
+) -> Result<(PageAllocation, usize), LoaderError> {
+    let page_size = 0x1000;
+    // Page zero is never a usable load address, even if a PIE linked at
+    // zero asks for it.
+    if image_start != 0
+        && let Ok(allocation) = PageAllocation::new(
+            boot::AllocateType::Address(image_start as u64),
+            mem_ty,
+            page_cnt,
+        )
+    {
+        let base = allocation.as_ptr() as usize;
+        return Ok((allocation, base));
+    }
+    info!(
+        "Kernel link address {:#x} is unavailable, letting the firmware choose",
+        image_start
+    );
+    // The firmware only guarantees page alignment, so allocate
+    // `align - page_size` extra bytes and start at the first `align`
+    // boundary inside the allocation. This keeps the bias a multiple of
+    // every segment's `p_align`.
+    let slack = (align - page_size) / page_size;
+    let allocation = PageAllocation::new(boot::AllocateType::AnyPages, mem_ty, page_cnt + slack)
+        .map_err(|_| LoaderError::NoRoomForImage {
+            linked: image_start as u64,
+            pages: page_cnt + slack,
+        })?;
+    let base = (allocation.as_ptr() as usize).next_multiple_of(align);
+    Ok((allocation, base))
+}
+
+/// Load the ELF image in `elf_data`, into `reserved` if its pages were
+/// allocated up front, or at `config.load_base` if it is set. With
+/// `config.verbose`, dump its header before parsing and log its segments
+/// before loading them.
+fn load_elf(
+    elf_data: &[u8],
+    reserved: Option<ImagePages>,
+    config: &LoaderConfig,
+) -> Result<LoadedElf, LoaderError> {
+    if config.verbose {
+        info!("ELF header:\n{}", util::hexdump(elf_data, elf::HEADER_SIZE));
+    }
+    let mut plan = plan_elf(elf_data, config.strict_relocations)?;
+    apply_huge_pages(&mut plan, config.huge_pages);
+    let reserved = match config.load_base {
+        Some(base) => {
+            plan.check_load_base(base)?;
+            info!("Loading the kernel at {:#x} from the config", base);
+            Some(allocate_at(&plan, base, KERNEL_IMAGE)?)
         }
-        let dest =
-            unsafe { slice::from_raw_parts_mut(phdr.p_vaddr as *mut u8, phdr.p_memsz as usize) };
-        dest[..phdr.p_filesz as usize].copy_from_slice(
-            &elf_data[phdr.p_offset as usize..(phdr.p_offset + phdr.p_filesz) as usize],
+        None => reserved,
+    };
+    let mut kernel = load_plan(
+        &plan,
+        reserved,
+        KERNEL_IMAGE,
+        config.verbose,
+        |segment, dest| {
+            segment.copy_to(elf_data, dest);
+            Ok(())
+        },
+    )?;
+    kernel.build_id = find_build_id(&plan, |range| Ok(elf_data[range].to_vec()))?;
+    Ok(kernel)
+}
+
+/// Load a fixed-address ELF executable by reading each segment from `file`
+/// straight into its pages, without buffering the whole file.
+///
+/// Returns `Ok(None)`, with nothing allocated, if the file is compressed or
+/// relocatable and has to go through [`load_elf`] instead. The file
+/// position is undefined afterwards.
+fn stream_elf(
+    file: &mut RegularFile,
+    config: &LoaderConfig,
+) -> Result<Option<LoadedElf>, LoaderError> {
+    let verbose = config.verbose;
+    let Some(mut plan) = read_fixed_plan(file, verbose)? else {
+        return Ok(None);
+    };
+    apply_huge_pages(&mut plan, config.huge_pages);
+    let mut kernel = load_plan(&plan, None, KERNEL_IMAGE, verbose, |segment, dest| {
+        let (contents, bss) = dest.split_at_mut(segment.file_size);
+        file.set_position(segment.offset as u64)?;
+        read_exact(file, contents)?;
+        bss.fill(0);
+        Ok(())
+    })?;
+    kernel.build_id = find_build_id(&plan, |range| {
+        let mut notes = vec![0; range.len()];
+        file.set_position(range.start as u64)?;
+        read_exact(file, &mut notes)?;
+        Ok(notes)
+    })?;
+    Ok(Some(kernel))
+}
+
+/// Look for a GNU build ID in the notes of `plan`, which `read` fetches by
+/// their file range, and log it.
+fn find_build_id(
+    plan: &elf::LoadPlan,
+    mut read: impl FnMut(Range<usize>) -> Result<Vec<u8>, LoaderError>,
+) -> Result<BuildId, LoaderError> {
+    for range in &plan.notes {
+        let notes = read(range.clone())?;
+        if let Some(id) = note::build_id(&notes) {
+            info!("Kernel build ID: {}", note::Hex(id));
+            return Ok(BuildId::new(id));
+        }
+    }
+    Ok(BuildId::empty())
+}
+
+/// With `huge_pages`, raise the alignment of `plan` to 2 MiB, so that a
+/// relocatable or higher-half image can be mapped with 2 MiB pages. A
+/// fixed-address image below [`paging::IDENTITY_MAP_SIZE`] already is.
+fn apply_huge_pages(plan: &mut elf::LoadPlan, huge_pages: bool) {
+    if huge_pages {
+        plan.align = plan.align.max(paging::LARGE_PAGE_SIZE as usize);
+    }
+}
+
+/// Plan loading the fixed-address ELF executable in `file` from just its
+/// headers. With `verbose`, dump the file header first.
+///
+/// Returns `Ok(None)` if the file is compressed or relocatable. The file
+/// position is undefined afterwards.
+fn read_fixed_plan(
+    file: &mut RegularFile,
+    verbose: bool,
+) -> Result<Option<elf::LoadPlan>, LoaderError> {
+    let file_len = file_len(file)?;
+    let header_bytes = read_file_at(file, 0, elf::HEADER_SIZE)?;
+    if gzip::is_gzip(&header_bytes) {
+        return Ok(None);
+    }
+    if verbose {
+        info!(
+            "ELF header:\n{}",
+            util::hexdump(&header_bytes, elf::HEADER_SIZE)
         );
-        dest[phdr.p_filesz as usize..].fill(0);
+    }
+    let header = elf::parse_header(&header_bytes)?;
+    if header.e_type == goblin::elf::header::ET_DYN {
+        return Ok(None);
+    }
+    let phdr_len = header.e_phnum as usize * header.e_phentsize as usize;
+    let phdr_bytes = read_file_at(file, header.e_phoff, phdr_len)?;
+    let plan = elf::plan_from_headers(&header, &phdr_bytes, file_len)?;
+    Ok(Some(plan))
+}
+
+/// Allocate the pages a fixed-address kernel in `file` is linked at, before
+/// the file is read into memory.
+///
+/// Returns `Ok(None)` for kernels that don't need pages at a fixed address:
+/// compressed, relocatable and higher-half ones. The file position is
+/// undefined afterwards.
+fn reserve_kernel_pages(file: &mut RegularFile) -> Result<Option<ImagePages>, LoaderError> {
+    match read_fixed_plan(file, false)? {
+        Some(plan) if !plan.is_higher_half() => Ok(Some(allocate_image(&plan, KERNEL_IMAGE)?)),
+        _ => Ok(None),
+    }
+}
+
+/// The memory types of an ELF image's pages: `code` for the pages of its
+/// executable segments and `data` for the rest.
+#[derive(Clone, Copy)]
+struct ImageTypes {
+    code: boot::MemoryType,
+    data: boot::MemoryType,
+}
+
+/// The pages an ELF image is loaded into, and how its link addresses map
+/// onto them.
+struct ImagePages {
+    allocations: Vec<PageAllocation>,
+    /// Added to a link address to get the address the image runs at.
+    bias: u64,
+    /// Added to a link address to get the address its bytes are written to
+    /// through the firmware's identity map.
+    copy_bias: u64,
+}
+
+/// Allocate the pages for `plan`.
+///
+/// A fixed-address executable must go exactly where it was linked, so each
+/// of its segments gets its own page range and large gaps between segments
+/// are never reserved. A relocatable one needs its segments to keep their
+/// relative layout, so it gets one contiguous block, tried at its link
+/// address first and otherwise wherever the firmware has room. A
+/// higher-half image can't be allocated where it is linked at all: it gets
+/// one contiguous block anywhere, aligned like a relocatable one so that
+/// its physical and link addresses agree modulo [`elf::LoadPlan::align`],
+/// is written there through the firmware's identity map and is mapped at
+/// its link address by the kernel's page tables.
+///
+/// Pages of executable segments get `types.code` and the rest
+/// `types.data`, so that the memory map shows where the code is. That
+/// takes one allocation per run of code or data pages rather than one per
+/// range: more memory map entries, and a contiguous block is only
+/// allocated to find room for the image, then given back and taken again
+/// run by run. A page that holds both code and data is code.
+fn allocate_image(plan: &elf::LoadPlan, types: ImageTypes) -> Result<ImagePages, LoaderError> {
+    let page_size = 0x1000;
+    let mut allocations = Vec::new();
+    let (bias, copy_bias) = if plan.relocatable {
+        let range = plan.aligned_range(plan.align);
+        let image_start = range.start;
+        let page_cnt = range.len() / page_size;
+        let (block, base) = allocate_relocatable(image_start, page_cnt, plan.align, types.data)?;
+        drop(block);
+        allocations = allocate_runs(plan, range, base as u64, types)?;
+        let bias = (base as u64).wrapping_sub(image_start as u64);
+        (bias, bias)
+    } else if plan.is_higher_half() {
+        let range = plan.aligned_range(plan.align);
+        let image_start = range.start;
+        // As for a relocatable image, the slack lets the block start on an
+        // `align` boundary.
+        let pages = (range.len() + plan.align - page_size) / page_size;
+        let block =
+            PageAllocation::new(boot::AllocateType::AnyPages, types.data, pages).map_err(|_| {
+                LoaderError::NoRoomForImage {
+                    linked: image_start as u64,
+                    pages,
+                }
+            })?;
+        let base = (block.as_ptr() as u64).next_multiple_of(plan.align as u64);
+        drop(block);
+        allocations = allocate_runs(plan, range, base, types)?;
+        (0, base.wrapping_sub(image_start as u64))
+    } else {
+        // The kernel can only go where it is linked, so make sure that is
+        // free before allocating it piece by piece.
+        let ranges = elf::page_ranges(&plan.segments, page_size);
+        let descriptors = memmap::descriptors()?;
+        for &(start, end) in &ranges {
+            let pages = start as u64..end as u64;
+            if let Some((range, ty)) = memory::find_unavailable(&pages, &descriptors) {
+                return Err(LoaderError::LinkedOverUsedMemory { range, ty });
+            }
+        }
+        for (start, end) in ranges {
+            allocations.extend(allocate_runs(plan, start..end, start as u64, types)?);
+        }
+        (0, 0)
+    };
+    Ok(ImagePages {
+        allocations,
+        bias,
+        copy_bias,
+    })
+}
+
+/// Allocate the pages for the relocatable `plan` so that its aligned start
+/// lands on `base`, which [`elf::LoadPlan::check_load_base`] accepted.
+fn allocate_at(
+    plan: &elf::LoadPlan,
+    base: u64,
+    types: ImageTypes,
+) -> Result<ImagePages, LoaderError> {
+    let range = plan.aligned_range(plan.align);
+    let bias = base.wrapping_sub(range.start as u64);
+    Ok(ImagePages {
+        allocations: allocate_runs(plan, range, base, types)?,
+        bias,
+        copy_bias: bias,
+    })
+}
+
+/// Allocate the pages of `plan` linked at `range` so that they start at
+/// `base`, one allocation per run from [`elf::code_runs`], in address
+/// order.
+fn allocate_runs(
+    plan: &elf::LoadPlan,
+    range: Range<usize>,
+    base: u64,
+    types: ImageTypes,
+) -> Result<Vec<PageAllocation>, LoaderError> {
+    let page_size = 0x1000;
+    elf::code_runs(&plan.segments, range.clone(), page_size)
+        .into_iter()
+        .map(|(run, code)| {
+            let addr = base + (run.start - range.start) as u64;
+            let pages = run.len() / page_size;
+            let mem_ty = if code { types.code } else { types.data };
+            PageAllocation::new(boot::AllocateType::Address(addr), mem_ty, pages)
+                .map_err(|_| LoaderError::OutOfMemory { addr, pages })
+        })
+        .collect()
+}
+
+/// The memory that `allocations`, in address order, cover back to back, as
+/// `(start, len)` pairs.
+fn contiguous_spans(allocations: &[PageAllocation]) -> Vec<(*mut u8, usize)> {
+    let mut spans: Vec<(*mut u8, usize)> = Vec::new();
+    for allocation in allocations {
+        match spans.last_mut() {
+            Some((start, len)) if start.wrapping_add(*len) == allocation.as_ptr() => {
+                *len += allocation.len()
+            }
+            _ => spans.push((allocation.as_ptr(), allocation.len())),
+        }
+    }
+    spans
+}
+
+/// Fill every segment of `plan` with `copy` and apply the relocations. The
+/// pages come from `reserved` if they were allocated up front, and are
+/// allocated here as `types` otherwise.
+///
+/// `copy` gets each segment and the `mem_size` bytes it goes to, and has to
+/// fill all of them. With `verbose`, the segment table is logged first.
+fn load_plan(
+    plan: &elf::LoadPlan,
+    reserved: Option<ImagePages>,
+    types: ImageTypes,
+    verbose: bool,
+    mut copy: impl FnMut(&elf::Segment, &mut [u8]) -> Result<(), LoaderError>,
+) -> Result<LoadedElf, LoaderError> {
+    if verbose {
+        info!("Loadable segments:\n{}", elf::SegmentTable(&plan.segments));
+    }
+
+    let ImagePages {
+        allocations,
+        bias,
+        copy_bias,
+    } = match reserved {
+        Some(pages) => pages,
+        None => allocate_image(plan, types)?,
+    };
+
+    info!(
+        "Loading ELF image at {:#x}",
+        (plan.start as u64).wrapping_add(bias)
+    );
+    debug!(
+        "ELF image: {:#x}..{:#x}, {} allocations, bias {:#x}, copy bias {:#x}",
+        plan.start,
+        plan.end,
+        allocations.len(),
+        bias,
+        copy_bias
+    );
+
+    // Copy loadable segments, into pages that are zeroed first so that
+    // nothing the firmware left there stays between them. A segment can
+    // span a code and a data allocation, so they are filled together.
+    for (start, len) in contiguous_spans(&allocations) {
+        let image = unsafe { slice::from_raw_parts_mut(start, len) };
+        let image_start = (start as u64).wrapping_sub(copy_bias);
+        elf::fill_image::<LoaderError>(image, image_start, &plan.segments, |segment, dest| {
+            copy(segment, dest)?;
+            debug!(
+                "Copied segment: vaddr={:#x} filesz={:#x} memsz={:#x} flags={} to {:p}",
+                segment.vaddr,
+                segment.file_size,
+                segment.mem_size,
+                elf::SegmentFlags(segment.flags),
+                dest.as_ptr(),
+            );
+            Ok(())
+        })?;
+    }
+
+    for rela in &plan.relocations {
+        let target = rela.offset.wrapping_add(copy_bias) as *mut u64;
+        let value = bias.wrapping_add(rela.addend as u64);
+        unsafe { target.write_unaligned(value) };
+    }
+    #[cfg(feature = "segment-permissions")]
+    protect::protect_segments(&plan.segments, copy_bias)?;
+    // Read back once every write is done, which also checks that the
+    // protection left the segments readable.
+    let segments = plan
+        .segments
+        .iter()
+        .map(|segment| {
+            let start = segment.vaddr.wrapping_add(bias);
+            let copy = segment.vaddr.wrapping_add(copy_bias) as *const u8;
+            LoadedSegment {
+                range: start..start + segment.mem_size as u64,
+                first_byte: (segment.mem_size > 0).then(|| unsafe { copy.read_volatile() }),
+            }
+        })
+        .collect();
+
+    for allocation in allocations {
+        allocation.commit();
+    }
+    Ok(LoadedElf {
+        entry: plan.entry.wrapping_add(bias),
+        image: (plan.start as u64).wrapping_add(bias)..(plan.end as u64).wrapping_add(bias),
+        phys_start: (plan.start as u64).wrapping_add(copy_bias),
+        relro: plan.relro_pages().map(|pages| {
+            (pages.start as u64).wrapping_add(bias)..(pages.end as u64).wrapping_add(bias)
+        }),
+        segments,
+        no_execute: biased_pages_without(plan, PF_X, bias),
+        read_only: if cfg!(feature = "segment-permissions") {
+            biased_pages_without(plan, PF_W, bias)
+        } else {
+            Vec::new()
+        },
+        build_id: BuildId::empty(),
+    })
+}
+
+/// The pages of `plan` from [`elf::pages_without`] `flag`, with `bias`
+/// applied.
+fn biased_pages_without(plan: &elf::LoadPlan, flag: u32, bias: u64) -> Vec<Range<u64>> {
+    elf::pages_without(&plan.segments, flag, 0x1000)
+        .into_iter()
+        .map(|pages| (pages.start as u64).wrapping_add(bias)..(pages.end as u64).wrapping_add(bias))
+        .collect()
+}
+
+/// Parse `data` as an ELF image and work out how to load it.
+///
+/// Relocation types the loader can't apply fail the load if `strict`, and
+/// are only warned about otherwise.
+fn plan_elf(data: &[u8], strict: bool) -> Result<elf::LoadPlan, LoaderError> {
+    let plan = elf::plan(data)?;
+    plan.check_relocations(strict)?;
+    if !plan.unsupported_relocations.is_empty() {
+        warn!(
+            "Leaving relocations of types {} unapplied, the image is probably linked wrong",
+            elf::RelocationTypes(&plan.unsupported_relocations)
+        );
+    }
+    Ok(plan)
+}
+
+/// Load the ELF module at `path` where it doesn't collide with any of the
+/// `occupied` address ranges.
+///
+/// A fixed-address module must not be linked into an occupied range. A
+/// relocatable one is moved out of the way if its link address is taken.
+fn load_module(
+    root_dir: &mut Directory,
+    path: &str,
+    occupied: &[Range<u64>],
+    config: &LoaderConfig,
+) -> Result<ModuleInfo, LoaderError> {
+    let mut file = open_path(root_dir, path)?;
+    let mut data = read_file(&mut file)?;
+    if gzip::is_gzip(&data) {
+        data = gzip::decompress(&data)?;
+    }
+    let plan = plan_elf(&data, config.strict_relocations)?;
+    // Only the kernel's image is mapped at its link address.
+    if !plan.relocatable && plan.is_higher_half() {
+        return Err(elf::PlanError::Unsupported("higher-half modules are not supported").into());
+    }
+    let linked = plan.start as u64..plan.end as u64;
+    if !plan.relocatable
+        && occupied
+            .iter()
+            .any(|range| memory::ranges_overlap(range, &linked))
+    {
+        return Err(LoaderError::Overlap {
+            path: path.to_string(),
+            image: linked,
+        });
+    }
+    let module = load_plan(
+        &plan,
+        None,
+        MODULE_IMAGE,
+        config.verbose,
+        |segment, dest| {
+            segment.copy_to(&data, dest);
+            Ok(())
+        },
+    )?;
+    Ok(ModuleInfo {
+        base: module.image.start,
+        size: module.image.end - module.image.start,
+        entry: module.entry,
+    })
+}
+
+/// The kernel entry point for `abi=ptr`, the default. Under sysv64 the
+/// `BootInfo` reference is passed in `rdi`.
+type EntryPoint = extern "sysv64" fn(&BootInfo);
+
+/// The kernel entry point for `abi=value`. `BootInfo` is too large for
+/// registers, so sysv64 passes the copy on the stack, starting at `rsp + 8`
+/// once the callee is entered.
+type ValueEntryPoint = extern "sysv64" fn(BootInfo);
+
+/// The kernel entry point for `abi=split`: the framebuffer in `rdi` and the
+/// memory map in `rsi`, both by reference.
+type SplitEntryPoint = extern "sysv64" fn(&FrameBufferInfo, &MemoryMapInfo);
+
+/// The kernel entry point, typed for the [`EntryAbi`] the config asks for.
+#[derive(Clone, Copy)]
+enum KernelEntry {
+    Pointer(EntryPoint),
+    Value(ValueEntryPoint),
+    Split(SplitEntryPoint),
+}
+
+impl KernelEntry {
+    /// Treat `addr` as an entry point taking `BootInfo` the way `abi` says.
+    ///
+    /// `addr` is where the kernel's entry point ended up, with any load bias
+    /// of the kernel already applied. It has nothing to do with where the
+    /// loader itself was loaded, so the loader's own relocation doesn't
+    /// affect it.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be the entry point of the loaded kernel.
+    unsafe fn new(addr: u64, abi: EntryAbi) -> Self {
+        unsafe {
+            match abi {
+                EntryAbi::Pointer => Self::Pointer(core::mem::transmute::<u64, EntryPoint>(addr)),
+                EntryAbi::Value => Self::Value(core::mem::transmute::<u64, ValueEntryPoint>(addr)),
+                EntryAbi::Split => Self::Split(core::mem::transmute::<u64, SplitEntryPoint>(addr)),
+            }
+        }
     }
 
-    prog
+    /// The address the loader jumps to.
+    fn addr(self) -> *const () {
+        match self {
+            Self::Pointer(entry) => entry as *const (),
+            Self::Value(entry) => entry as *const (),
+            Self::Split(entry) => entry as *const (),
+        }
+    }
+}
+
+/// Open the kernel at `path`, or at [`config::DEFAULT_KERNEL_PATH`] if
+/// `path` doesn't exist. Returns the file and the path that was opened.
+fn open_kernel<'a>(
+    root_dir: &mut Directory,
+    path: &'a str,
+) -> Result<(RegularFile, &'a str), LoaderError> {
+    let mut tried = Vec::new();
+    for candidate in [path, config::DEFAULT_KERNEL_PATH] {
+        if tried.iter().any(|p: &String| p == candidate) {
+            continue;
+        }
+        match open_path(root_dir, candidate) {
+            Ok(file) => {
+                if !tried.is_empty() {
+                    warn!("{} not found, booting {} instead", path, candidate);
+                }
+                return Ok((file, candidate));
+            }
+            Err(LoaderError::NotFound(_)) => tried.push(candidate.to_string()),
+            Err(err) => return Err(err),
+        }
+    }
+    Err(LoaderError::KernelNotFound(tried))
 }
 
-type EntryPoint = extern "sysv64" fn();
-fn load_kernel(kernel_file: &mut RegularFile) -> uefi::Result<EntryPoint> {
-    let buf = read_file(kernel_file)?;
+/// Read the SHA-256 digest the kernel at `kernel_path` should have from
+/// the file next to it with `.sha256` appended, as written by `sha256sum`.
+///
+/// Returns `Ok(None)` if there is no digest file, unless `required`.
+fn read_kernel_digest(
+    root_dir: &mut Directory,
+    kernel_path: &str,
+    required: bool,
+) -> Result<Option<(String, [u8; 32])>, LoaderError> {
+    let path = format!("{}.sha256", kernel_path);
+    let text = match open_path(root_dir, &path).and_then(|mut file| read_file(&mut file)) {
+        Ok(text) => text,
+        Err(LoaderError::NotFound(_)) if required => return Err(LoaderError::MissingDigest(path)),
+        Err(LoaderError::NotFound(_)) => {
+            warn!("No {}, booting an unverified kernel.", path);
+            return Ok(None);
+        }
+        Err(err) => return Err(err),
+    };
+    match core::str::from_utf8(&text)
+        .ok()
+        .and_then(sha256::parse_digest)
+    {
+        Some(digest) => Ok(Some((path, digest))),
+        None => Err(LoaderError::BadDigest(path)),
+    }
+}
+
+/// Read, check and load the kernel in `kernel_file`.
+///
+/// The CRC-32 of the file as stored, before any decompression, is compared
+/// against `config.kernel_crc` if there is one, and its SHA-256 against
+/// `digest`, a digest file path and its contents, if given. With
+/// `config.stream_kernel`, an uncompressed fixed-address kernel without an
+/// expected CRC or digest is copied straight from the file by
+/// [`stream_elf`].
+fn load_kernel_file(
+    kernel_file: &mut RegularFile,
+    digest: Option<(String, [u8; 32])>,
+    config: &LoaderConfig,
+    progress: &mut ProgressBar,
+) -> Result<(KernelEntry, LoadedElf), LoaderError> {
+    let stopwatch = timing::Stopwatch::start();
+    let expected_crc = config.kernel_crc;
+    // The checksums cover the whole file, so they rule out streaming. A
+    // load base does too, so that `load_elf` gets to reject it for a
+    // fixed-address kernel.
+    if config.stream_kernel
+        && expected_crc.is_none()
+        && digest.is_none()
+        && config.load_base.is_none()
+    {
+        if let Some(kernel) = stream_elf(kernel_file, config)? {
+            progress.advance();
+            info!("Kernel loaded in {} ms", stopwatch.elapsed_ms());
+            progress.advance();
+            let entry = unsafe { KernelEntry::new(kernel.entry, config.abi) };
+            return Ok((entry, kernel));
+        }
+        info!("Kernel is compressed or relocatable, reading all of it.");
+    }
+    // The order of allocations matters. A fixed-address kernel's pages are
+    // reserved before the file is read, so that neither the read buffer nor
+    // anything else allocated while loading can end up in them and make the
+    // fixed-address allocation fail. `load_elf` then fills the reservation.
+    let reserved = reserve_kernel_pages(kernel_file)?;
+    kernel_file.set_position(0)?;
+    let mut buf = read_file(kernel_file)?;
     info!("Read kernel file: size={}", buf.len());
-    let prog = load_elf(&buf);
-    let entry: EntryPoint = unsafe { core::mem::transmute(prog.entry) };
-    Ok(entry)
+    let crc = crc32(&buf);
+    match expected_crc {
+        Some(expected) if expected != crc => {
+            return Err(LoaderError::ChecksumMismatch {
+                expected,
+                actual: crc,
+            });
+        }
+        Some(_) => info!("Kernel CRC-32 {:#010x} matches", crc),
+        None => info!("Kernel CRC-32: {:#010x}", crc),
+    }
+    if let Some((path, expected)) = digest {
+        if sha256::sha256(&buf) != expected {
+            return Err(LoaderError::DigestMismatch(path));
+        }
+        info!("Kernel matches the SHA-256 digest in {}", path);
+    }
+    if gzip::is_gzip(&buf) {
+        buf = gzip::decompress(&buf)?;
+        info!("Decompressed kernel: size={}", buf.len());
+    }
+    progress.advance();
+    let kernel = load_elf(&buf, reserved, config)?;
+    info!("Kernel loaded in {} ms", stopwatch.elapsed_ms());
+    progress.advance();
+    let entry = unsafe { KernelEntry::new(kernel.entry, config.abi) };
+    Ok((entry, kernel))
+}
+
+/// Read the current time from the firmware clock, or return
+/// [`BootTime::empty`] if the firmware can't provide it.
+fn read_boot_time() -> BootTime {
+    match runtime::get_time() {
+        Ok(time) => {
+            info!("Boot time: {}", time);
+            BootTime {
+                year: time.year(),
+                month: time.month(),
+                day: time.day(),
+                hour: time.hour(),
+                minute: time.minute(),
+                second: time.second(),
+                nanosecond: time.nanosecond(),
+                time_zone: time.time_zone().unwrap_or(BootTime::UNSPECIFIED_TIME_ZONE),
+                daylight: time.daylight().bits(),
+            }
+        }
+        Err(err) => {
+            warn!("Failed to read the time: {:?}", err.status());
+            BootTime::empty()
+        }
+    }
+}
+
+/// Allocate a `BootInfo` in pages of type [`memory_type::BOOT_INFO`] so
+/// that it survives `exit_boot_services` and can be handed to the kernel.
+fn allocate_boot_info() -> uefi::Result<&'static mut BootInfo> {
+    let page_size = 0x1000;
+    let page_cnt = size_of::<BootInfo>().div_ceil(page_size);
+    let ptr = boot::allocate_pages(
+        boot::AllocateType::AnyPages,
+        boot::MemoryType::custom(memory_type::BOOT_INFO),
+        page_cnt,
+    )?
+    .cast::<BootInfo>();
+    unsafe {
+        ptr.write(BootInfo::empty());
+        Ok(&mut *ptr.as_ptr())
+    }
+}
+
+/// Seconds the error stays on screen before `on_error=reboot` resets the
+/// machine.
+const REBOOT_DELAY_SECS: u64 = 5;
+
+/// Set from the config's `on_error`. Failures before the config is read
+/// halt.
+static REBOOT_ON_ERROR: AtomicBool = AtomicBool::new(false);
+
+/// Report `err`, with the memory map around the memory it is about if
+/// there is any, and halt or reboot, as the config asks.
+fn fail(err: LoaderError) -> ! {
+    error!("Failed to boot: {}", err);
+    if let Some(range) = err.memory_range() {
+        memmap::log_excerpt(range);
+    }
+    if !REBOOT_ON_ERROR.load(Ordering::Relaxed) {
+        halt();
+    }
+    info!("Rebooting in {} seconds...", REBOOT_DELAY_SECS);
+    // Boot services may already be gone if exiting them failed halfway.
+    timing::tsc_delay(REBOOT_DELAY_SECS * 1_000_000);
+    runtime::reset(ResetType::COLD, Status::ABORTED, None)
+}
+
+/// Seconds `on_kernel_return=stall` waits before halting.
+const KERNEL_RETURN_STALL_SECS: u64 = 10;
+
+/// What runs if the kernel returns, for `action`.
+///
+/// Boot services are gone by then, so the handlers only log to COM1 and
+/// the boot log, and can only wait on the TSC.
+fn kernel_return_handler(action: OnKernelReturn) -> extern "sysv64" fn() -> ! {
+    extern "sysv64" fn halt_on_return() -> ! {
+        warn!("The kernel returned, halting.");
+        unsafe { core::arch::asm!("cli", options(nomem, nostack)) };
+        halt()
+    }
+    extern "sysv64" fn reboot_on_return() -> ! {
+        warn!("The kernel returned, rebooting.");
+        runtime::reset(ResetType::COLD, Status::ABORTED, None)
+    }
+    extern "sysv64" fn stall_on_return() -> ! {
+        warn!(
+            "The kernel returned, halting in {} seconds.",
+            KERNEL_RETURN_STALL_SECS
+        );
+        timing::tsc_delay(KERNEL_RETURN_STALL_SECS * 1_000_000);
+        halt()
+    }
+    match action {
+        OnKernelReturn::Halt => halt_on_return,
+        OnKernelReturn::Reboot => reboot_on_return,
+        OnKernelReturn::Stall => stall_on_return,
+    }
+}
+
+/// Stop the CPU for good.
+fn halt() -> ! {
+    loop {
+        unsafe { core::arch::asm!("hlt") };
+    }
 }
 
 #[entry]
 fn main() -> Status {
+    logger::init();
+    info!(
+        "mikanos-rs-loader {} ({})",
+        env!("CARGO_PKG_VERSION"),
+        env!("MIKANOS_GIT_HASH")
+    );
     uefi::helpers::init().unwrap();
-    info!("Hello, mikanos-rs!");
-
-    let mut root_dir = open_root_dir().expect("Failed to open root directory.");
-    let memmap_file = root_dir
-        .open(
-            cstr16!("\\memmap"),
-            FileMode::CreateReadWrite,
-            FileAttribute::empty(),
-        )
-        .expect("Failed to open memmap file.");
-    save_memory_map(memmap_file).expect("Failed to save memory map.");
-
-    let mut kernel_file = root_dir
-        .open(
-            cstr16!("\\kernel.elf"),
-            FileMode::Read,
-            FileAttribute::empty(),
-        )
-        .expect("Failed to open kernel file.")
-        .into_regular_file()
-        .unwrap();
-    let entry = load_kernel(&mut kernel_file).expect("Failed to load kernel");
-    info!("Successfully loaded kernel!");
+    // Calibrate while boot services are still there to calibrate against.
+    timing::calibrate();
+
+    // The firmware resets the machine if the watchdog fires, which it
+    // would during a long load or once the kernel stops servicing it.
+    // Codes below 0x10000 are reserved for the firmware.
+    match boot::set_watchdog_timer(0, 0x10000, None) {
+        Ok(()) => info!("Disabled the watchdog timer."),
+        Err(err) => warn!("Failed to disable the watchdog timer: {}", err),
+    }
+
+    let Err(err) = Loader::new().and_then(Loader::run);
+    fail(err)
+}
+
+/// Memory types of the kernel image's pages, see [`memory_type`].
+const KERNEL_IMAGE: ImageTypes = ImageTypes {
+    code: boot::MemoryType::custom(memory_type::KERNEL_CODE),
+    data: boot::MemoryType::custom(memory_type::KERNEL_IMAGE),
+};
+
+/// Memory types of a module's pages.
+const MODULE_IMAGE: ImageTypes = ImageTypes {
+    code: boot::MemoryType::LOADER_CODE,
+    data: boot::MemoryType::LOADER_DATA,
+};
+
+/// Image drawn in the middle of the screen right before the kernel starts,
+/// if it exists.
+const SPLASH_PATH: &str = "\\splash.bmp";
 
-    info!("Exiting boot services...");
-    // Is it correct to use LOADER_DATA type here?
-    let _ = unsafe { boot::exit_boot_services(boot::MemoryType::LOADER_DATA) };
+/// cpio archive in the newc format, optionally gzipped, that the kernel is
+/// loaded from if it exists. The kernel is its [`ARCHIVE_KERNEL`] member.
+const ARCHIVE_PATH: &str = "\\boot.cpio";
 
-    entry();
+/// Name of the kernel in [`ARCHIVE_PATH`], with or without a leading `./`.
+const ARCHIVE_KERNEL: &str = "kernel.elf";
 
-    info!("All done.");
-    boot::stall(10_000_000);
-    Status::SUCCESS
+/// Everything the boot sequence carries from reading the config to jumping
+/// into the kernel.
+struct Loader {
+    /// Root directory of the volume the loader was started from.
+    root_dir: Directory,
+    config: LoaderConfig,
+    /// The framebuffer handed to the kernel, with any config overrides
+    /// applied.
+    framebuffer: FrameBufferInfo,
+    /// The boot archive the kernel came from, or an empty region.
+    archive: MemoryRegion,
+}
+
+impl Loader {
+    /// Open the boot volume, apply `\loader.conf` and set up the display.
+    fn new() -> Result<Self, LoaderError> {
+        let mut root_dir = open_root_dir()?;
+        let config = config::read_config(&mut root_dir)?;
+        log::set_max_level(config.log_level);
+        logger::set_console(config.log_console);
+        REBOOT_ON_ERROR.store(config.on_error == OnError::Reboot, Ordering::Relaxed);
+        debug!("Loader config: {:?}", config);
+        if let Some(label) = &config.volume {
+            match open_root_dir_by_label(label)? {
+                Some(dir) => {
+                    info!("Loading from volume {}", label);
+                    root_dir = dir;
+                }
+                None => warn!("No volume labelled {}, using the boot volume.", label),
+            }
+        }
+
+        // Without GOP, or with only a BltOnly mode, the loader keeps to the
+        // text console and the kernel gets no framebuffer.
+        let framebuffer = gop::open_gop(config.display, config.resolution, config.list_modes);
+        let mut framebuffer = match framebuffer {
+            Ok(framebuffer) => framebuffer,
+            Err(LoaderError::Uefi(err))
+                if matches!(err.status(), Status::NOT_FOUND | Status::UNSUPPORTED) =>
+            {
+                warn!(
+                    "No usable framebuffer ({:?}), continuing without one.",
+                    err.status()
+                );
+                FrameBufferInfo::empty()
+            }
+            Err(err) => return Err(err),
+        };
+        if let Some(format) = config.pixel_format
+            && !framebuffer.base.is_null()
+        {
+            info!(
+                "Overriding pixel format {:?} with {:?} from the config",
+                framebuffer.pixel_format, format
+            );
+            framebuffer.pixel_format = format;
+            framebuffer.masks = PixelMasks {
+                red: 0,
+                green: 0,
+                blue: 0,
+                reserved: 0,
+            };
+        }
+        info!(
+            "Framebuffer: {}x{} (stride {}) at {:p}",
+            framebuffer.horizontal_resolution,
+            framebuffer.vertical_resolution,
+            framebuffer.pixels_per_scan_line,
+            framebuffer.base,
+        );
+        Ok(Self {
+            root_dir,
+            config,
+            framebuffer,
+            archive: MemoryRegion::empty(),
+        })
+    }
+
+    /// Load the kernel and jump into it. Only returns if something went
+    /// wrong.
+    fn run(mut self) -> Result<Infallible, LoaderError> {
+        if self.config.save_memmap {
+            self.save_memory_map()?;
+        }
+        let has_framebuffer = !self.framebuffer.base.is_null();
+        let mut progress = ProgressBar::new(
+            (self.config.progress && has_framebuffer).then_some(self.framebuffer),
+            3,
+        );
+        let (entry, kernel) = self.load_kernel(&mut progress)?;
+        info!("Successfully loaded kernel!");
+        let boot_info = self.prepare_boot_info()?;
+        self.load_modules(&kernel.phys_range(), boot_info)?;
+        self.boot(entry, kernel, boot_info)
+    }
+
+    /// Open the regular file `path` on the boot volume, see [`open_file`].
+    fn open_file(&mut self, path: &CStr16, mode: FileMode) -> Result<RegularFile, LoaderError> {
+        open_file(&mut self.root_dir, path, mode)
+    }
+
+    /// Dump the current memory map to the boot volume in the configured
+    /// format.
+    fn save_memory_map(&mut self) -> Result<(), LoaderError> {
+        let format = self.config.memmap_format;
+        let mut file = self.open_file(memmap::file_path(format), FileMode::CreateReadWrite)?;
+        memmap::save_memory_map(&mut file, format)?;
+        Ok(())
+    }
+
+    /// Load the kernel from [`ARCHIVE_PATH`] if it exists. Otherwise pick
+    /// it, from the boot menu if there are entries, and load it. See
+    /// [`load_kernel_file`] for the order its memory is allocated in.
+    fn load_kernel(
+        &mut self,
+        progress: &mut ProgressBar,
+    ) -> Result<(KernelEntry, LoadedElf), LoaderError> {
+        let var_kernel = config::read_boot_kernel_var();
+        if var_kernel.is_none()
+            && let Some(loaded) = self.load_kernel_from_archive(progress)?
+        {
+            return Ok(loaded);
+        }
+        let config = &self.config;
+        let kernel_path = if let Some(path) = &var_kernel {
+            info!("Booting {} from the MikanKernel variable", path);
+            path
+        } else if config.entries.is_empty() {
+            &config.kernel
+        } else {
+            let entry = menu::select_entry(&config.entries, config.menu_timeout);
+            info!("Booting {}", entry.label);
+            &entry.path
+        };
+        let (mut kernel_file, kernel_path) = open_kernel(&mut self.root_dir, kernel_path)?;
+        let digest = match config.kernel_sha256 {
+            Some(digest) if kernel_path == config.kernel => {
+                Some((String::from("the loader config"), digest))
+            }
+            _ => read_kernel_digest(&mut self.root_dir, kernel_path, config.require_signature)?,
+        };
+        progress.advance();
+        load_kernel_file(&mut kernel_file, digest, config, progress)
+    }
+
+    /// Load the kernel from the [`ARCHIVE_KERNEL`] member of
+    /// [`ARCHIVE_PATH`], keeping the whole archive for the kernel. Returns
+    /// `Ok(None)` if there is no archive.
+    ///
+    /// The archive is verified against `\boot.cpio.sha256` like a kernel
+    /// file, but `kernel_crc` and the boot menu don't apply to it. Its pages
+    /// are allocated before the kernel's, so a fixed-address kernel fails to
+    /// load if the firmware happens to put the archive where the kernel is
+    /// linked.
+    fn load_kernel_from_archive(
+        &mut self,
+        progress: &mut ProgressBar,
+    ) -> Result<Option<(KernelEntry, LoadedElf)>, LoaderError> {
+        let mut file = match open_path(&mut self.root_dir, ARCHIVE_PATH) {
+            Ok(file) => file,
+            Err(LoaderError::NotFound(_)) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let config = &self.config;
+        let digest =
+            read_kernel_digest(&mut self.root_dir, ARCHIVE_PATH, config.require_signature)?;
+        let mut buf = read_file(&mut file)?;
+        info!("Read {}: size={}", ARCHIVE_PATH, buf.len());
+        if config.kernel_crc.is_some() {
+            warn!("kernel_crc doesn't apply to {}, ignoring it.", ARCHIVE_PATH);
+        }
+        if let Some((path, expected)) = digest {
+            if sha256::sha256(&buf) != expected {
+                return Err(LoaderError::DigestMismatch(path));
+            }
+            info!("{} matches the SHA-256 digest in {}", ARCHIVE_PATH, path);
+        }
+        if gzip::is_gzip(&buf) {
+            buf = gzip::decompress(&buf)?;
+            info!("Decompressed {}: size={}", ARCHIVE_PATH, buf.len());
+        }
+        progress.advance();
+
+        for entry in cpio::entries(&buf) {
+            let entry = entry?;
+            debug!(
+                "{}: {} mode={:o} size={}",
+                ARCHIVE_PATH,
+                entry.name,
+                entry.mode,
+                entry.data.len()
+            );
+        }
+        let Some(elf_data) = cpio::find(&buf, ARCHIVE_KERNEL)? else {
+            return Err(LoaderError::NotFound(format!(
+                "{} in {}",
+                ARCHIVE_KERNEL, ARCHIVE_PATH
+            )));
+        };
+        let stopwatch = timing::Stopwatch::start();
+        progress.advance();
+        let kernel = load_elf(elf_data, None, config)?;
+        info!("Kernel loaded in {} ms", stopwatch.elapsed_ms());
+        progress.advance();
+        let entry = unsafe { KernelEntry::new(kernel.entry, config.abi) };
+        self.archive = copy_to_pages(&buf)?;
+        Ok(Some((entry, kernel)))
+    }
+
+    /// Allocate the `BootInfo` and fill in everything but the memory map,
+    /// which only exists once boot services are gone.
+    fn prepare_boot_info(&mut self) -> Result<&'static mut BootInfo, LoaderError> {
+        let boot_info = allocate_boot_info()?;
+        boot_info.framebuffer = self.framebuffer;
+        if self.config.double_buffer {
+            boot_info.back_buffer = allocate_back_buffer(&self.framebuffer)?;
+            info!(
+                "Allocated a {} byte back buffer at {:p}",
+                boot_info.back_buffer.size, boot_info.back_buffer.base
+            );
+        }
+        boot_info.phys_mem_offset = paging::PHYS_MEM_OFFSET;
+        boot_info.archive = self.archive;
+        boot_info.initrd = load_file_to_pages(&mut self.root_dir, &self.config.initrd)?;
+        if boot_info.initrd.base.is_null() {
+            info!("No initrd loaded.");
+        } else {
+            info!(
+                "Loaded initrd: size={} at {:p}",
+                boot_info.initrd.size, boot_info.initrd.base,
+            );
+        }
+        let font = load_file_to_pages(&mut self.root_dir, font::FONT_PATH)?;
+        if font.base.is_null() {
+            warn!("No font at {}.", font::FONT_PATH);
+        } else {
+            match font::parse(font) {
+                Ok(info) => {
+                    info!(
+                        "Loaded font: {} glyphs of {}x{}",
+                        info.glyph_count, info.glyph_width, info.glyph_height,
+                    );
+                    boot_info.font = info;
+                }
+                Err(err) => warn!("Ignoring {}: {}", font::FONT_PATH, err),
+            }
+        }
+        match config_table::find_rsdp() {
+            Some((rsdp, revision)) => {
+                debug!("ACPI RSDP revision {} at {:p}", revision, rsdp);
+                boot_info.rsdp = rsdp;
+                boot_info.rsdp_revision = revision;
+                boot_info.cpu_count = unsafe { config_table::count_cpus(rsdp, revision) };
+                info!("CPUs: {}", boot_info.cpu_count);
+            }
+            None => warn!("ACPI RSDP not found."),
+        }
+        match config_table::find_smbios() {
+            Some(smbios) => boot_info.smbios = smbios,
+            None => warn!("SMBIOS entry point not found."),
+        }
+        boot_info.runtime_services = config_table::find_runtime_services();
+        match config_table::copy_config_tables() {
+            Ok(tables) => boot_info.config_tables = tables,
+            Err(err) => warn!("Failed to copy the configuration table: {:?}", err.status()),
+        }
+        boot_info.boot_time = read_boot_time();
+        boot_info.tsc_frequency = timing::calibrate();
+        boot_info.cmdline = load_cmdline(&self.config.cmdline)?;
+        if boot_info.cmdline.size > 0 {
+            info!("Kernel command line: {}", self.config.cmdline);
+        }
+        Ok(boot_info)
+    }
+
+    /// Load the modules the config lists into `boot_info.modules`, keeping
+    /// them clear of the kernel and of each other.
+    fn load_modules(
+        &mut self,
+        kernel_image: &Range<u64>,
+        boot_info: &mut BootInfo,
+    ) -> Result<(), LoaderError> {
+        let mut occupied = vec![kernel_image.clone()];
+        for (slot, path) in boot_info.modules.iter_mut().zip(&self.config.modules) {
+            let module = load_module(&mut self.root_dir, path, &occupied, &self.config)?;
+            info!(
+                "Loaded module {}: {:#x}..{:#x}, entry {:#x}",
+                path,
+                module.base,
+                module.base + module.size,
+                module.entry,
+            );
+            occupied.push(module.base..module.base + module.size);
+            *slot = module;
+            boot_info.module_count += 1;
+        }
+        // The app is optional, so only a missing file is fine.
+        match load_module(
+            &mut self.root_dir,
+            &self.config.app,
+            &occupied,
+            &self.config,
+        ) {
+            Ok(app) => {
+                info!(
+                    "Loaded app {}: {:#x}..{:#x}, entry {:#x}",
+                    self.config.app,
+                    app.base,
+                    app.base + app.size,
+                    app.entry,
+                );
+                boot_info.app = app;
+            }
+            Err(LoaderError::NotFound(_)) => debug!("No app at {}", self.config.app),
+            Err(err) => return Err(err),
+        }
+        Ok(())
+    }
+
+    /// Build the kernel's page tables, exit boot services and jump to
+    /// `entry` with `boot_info`. Only returns if something went wrong
+    /// before exiting, or in a dry run.
+    fn boot(
+        mut self,
+        entry: KernelEntry,
+        kernel: LoadedElf,
+        boot_info: &'static mut BootInfo,
+    ) -> Result<Infallible, LoaderError> {
+        let framebuffer = self.framebuffer;
+        // A missing or broken splash image is not worth failing the boot.
+        let splash_file = open_path(&mut self.root_dir, SPLASH_PATH)
+            .and_then(|mut file| read_file(&mut file))
+            .ok();
+        let splash = splash_file.as_deref().and_then(|data| {
+            bmp::parse(data)
+                .inspect_err(|err| debug!("Ignoring {}: {}", SPLASH_PATH, err))
+                .ok()
+        });
+        let kernel_stack = stack::allocate_kernel_stack(self.config.kernel_stack_size())?;
+        let stack_top = kernel_stack.base as u64 + kernel_stack.size as u64;
+        boot_info.kernel_stack = kernel_stack;
+        boot_info.build_id = kernel.build_id;
+        boot_info.loader_version = LoaderVersion {
+            major: env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+            minor: env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+            patch: env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+        };
+        let map_buffer = exit::allocate_map_buffer()?;
+        let descriptors = memmap::allocate_descriptors(map_buffer.len())?;
+
+        // Everything the kernel or the handoff touches, in case some of it
+        // is above the range the page tables map anyway.
+        let boot_info_addr = boot_info as *const BootInfo as u64;
+        let descriptors_addr = descriptors.as_ptr() as u64;
+        let map_buffer_addr = map_buffer.as_ptr() as u64;
+        let initrd_addr = boot_info.initrd.base as u64;
+        let font_addr = boot_info.font.base as u64;
+        // The allocations for the handoff. The firmware shouldn't hand out
+        // pages the kernel already occupies, but if it does the kernel
+        // would be overwritten silently.
+        let regions = [
+            ("kernel stack", kernel_stack.base as u64..stack_top),
+            (
+                "boot info",
+                boot_info_addr..boot_info_addr + size_of::<BootInfo>() as u64,
+            ),
+            (
+                "memory descriptors",
+                descriptors_addr..descriptors_addr + size_of_val(descriptors) as u64,
+            ),
+            (
+                "memory map buffer",
+                map_buffer_addr..map_buffer_addr + map_buffer.len() as u64,
+            ),
+        ];
+        if let Some((region, range)) = memory::find_overlap(&kernel.phys_range(), &regions) {
+            return Err(LoaderError::KernelOverlap {
+                region,
+                image: kernel.phys_range(),
+                other: range.clone(),
+            });
+        }
+        let mut mapped = vec![
+            kernel.phys_range(),
+            framebuffer.base as u64..framebuffer.base as u64 + framebuffer.size as u64,
+            initrd_addr..initrd_addr + boot_info.initrd.size as u64,
+            font_addr..font_addr + boot_info.font.size as u64,
+        ];
+        mapped.extend(regions.iter().map(|(_, range)| range.clone()));
+        mapped.extend(
+            boot_info.modules[..boot_info.module_count]
+                .iter()
+                .map(|module| module.base..module.base + module.size),
+        );
+        if boot_info.cmdline.size > 0 {
+            let cmdline_addr = boot_info.cmdline.base as u64;
+            mapped.push(cmdline_addr..cmdline_addr + boot_info.cmdline.size as u64 + 1);
+        }
+        if boot_info.back_buffer.size > 0 {
+            let back_addr = boot_info.back_buffer.base as u64;
+            mapped.push(back_addr..back_addr + boot_info.back_buffer.size as u64);
+        }
+        if boot_info.archive.size > 0 {
+            let archive_addr = boot_info.archive.base as u64;
+            mapped.push(archive_addr..archive_addr + boot_info.archive.size as u64);
+        }
+        if boot_info.config_tables.count > 0 {
+            let tables_addr = boot_info.config_tables.entries as u64;
+            let tables_size = boot_info.config_tables.count * size_of::<ConfigTable>();
+            mapped.push(tables_addr..tables_addr + tables_size as u64);
+        }
+        if boot_info.app.size > 0 {
+            mapped.push(boot_info.app.base..boot_info.app.base + boot_info.app.size);
+        }
+        let boot_log = logger::boot_log();
+        if !boot_log.base.is_null() {
+            let log_addr = boot_log.base as u64;
+            mapped.push(log_addr..log_addr + boot_log.capacity as u64);
+        }
+        // The loader keeps running from its own image between loading CR3
+        // and jumping to the kernel, wherever the firmware put it.
+        mapped.push(loader_image_range()?);
+        // Runtime services keep running on the kernel's page tables.
+        mapped.extend(memmap::runtime_ranges()?);
+        // A higher-half kernel also needs its link addresses mapped.
+        let remapped = if kernel.phys_start != kernel.image.start {
+            vec![(kernel.image.clone(), kernel.phys_start)]
+        } else {
+            Vec::new()
+        };
+        // The relocations were applied while loading, so the region can be
+        // made read-only now, along with the segments that aren't writable.
+        let mut read_only: Vec<Range<u64>> = match &kernel.relro {
+            Some(relro) if self.config.protect_relro => vec![relro.clone()],
+            _ => Vec::new(),
+        };
+        read_only.extend(kernel.read_only.iter().cloned());
+        let nx = paging::enable_nx();
+        let cr3 = paging::build_page_tables(
+            &mapped,
+            &remapped,
+            &read_only,
+            &kernel.no_execute,
+            self.config.huge_pages,
+            nx,
+        )?;
+        if cr3.is_none() {
+            if !remapped.is_empty() {
+                return Err(LoaderError::HigherHalfUnsupported);
+            }
+            warn!("5-level paging is active, keeping the firmware page tables.");
+            if !read_only.is_empty() {
+                warn!("The kernel's read-only pages stay writable.");
+            }
+        }
+        if self.config.verify {
+            match cr3 {
+                Some(cr3) => verify::verify_kernel(cr3, &kernel, &read_only, nx)?,
+                None => warn!("Skipping the mapping check on the firmware page tables."),
+            }
+        }
+
+        if self.config.entry_mode == EntryMode::Protected {
+            #[cfg(not(feature = "protected-mode"))]
+            return Err(LoaderError::ProtectedMode(
+                "the loader was built without the `protected-mode` feature".to_string(),
+            ));
+            #[cfg(feature = "protected-mode")]
+            {
+                protected::check(
+                    kernel.entry,
+                    remapped.is_empty(),
+                    kernel.phys_range(),
+                    &regions,
+                    boot_info,
+                    loader_image_range()?,
+                )?;
+                warn!("Starting the kernel in protected mode, which is experimental.");
+            }
+        }
+
+        info!("Memory: {}", memmap::summary()?);
+
+        if self.config.dry_run {
+            // Boot services stay up so the state can be inspected.
+            info!("Dry run: kernel entry at {:p}", entry.addr());
+            info!("Dry run: {:#x?}", boot_info);
+            halt();
+        }
+
+        info!("Exiting boot services...");
+        // Nothing between here and the jump may allocate: an allocation
+        // would change the map, and the allocator and UEFI console are
+        // unusable once boot services are gone. The map buffer is
+        // LOADER_DATA so it survives the exit and stays valid for the
+        // kernel.
+        let memory_map = unsafe { exit::exit_boot_services(map_buffer)? };
+        // The backing buffer has some slack at the end; only `map_size`
+        // bytes hold descriptors.
+        boot_info.memory_map.buffer = memory_map.buffer().as_ptr();
+        boot_info.memory_map.size = memory_map.meta().map_size;
+        boot_info.memory_map.descriptor_size = memory_map.meta().desc_size;
+        boot_info.memory_map.descriptor_count = memory_map.meta().entry_count();
+        progress::clear_screen(&framebuffer, self.config.background);
+        if let Some(splash) = &splash {
+            progress::draw_bitmap(&framebuffer, splash);
+        }
+        let (mut count, dropped) = memmap::copy_descriptors(&memory_map, descriptors);
+        if dropped > 0 {
+            // Only COM1 and the boot log are left to say so.
+            warn!(
+                "{} memory map entries didn't fit in the kernel's copy",
+                dropped
+            );
+        }
+        // From the map the firmware accepted, so nothing allocated earlier
+        // is counted as free.
+        boot_info.usable_ram_bytes = memory::usable_ram_bytes(&descriptors[..count]);
+        let (free_base, free_size) = memory::largest_free_region(&descriptors[..count]);
+        if free_size > 0 {
+            boot_info.largest_free = MemoryRegion {
+                base: free_base as *const u8,
+                size: free_size as usize,
+            };
+        }
+        if self.config.merge_memmap {
+            count = memory::merge_descriptors(&mut descriptors[..count]);
+        }
+        boot_info.memory_descriptors = MemoryDescriptors {
+            entries: descriptors.as_ptr(),
+            count,
+        };
+
+        // Boot services are gone, so this only goes to COM1 and the boot
+        // log.
+        if self.config.debug {
+            info!("{}", handoff::BootInfoDump(boot_info));
+        }
+        // Nothing is logged after this, so the kernel gets all of it.
+        boot_info.boot_log = logger::boot_log();
+        if let Some(cr3) = cr3 {
+            unsafe { paging::load_cr3(cr3) };
+            paging::enable_write_protect();
+        }
+        #[cfg(feature = "protected-mode")]
+        if self.config.entry_mode == EntryMode::Protected {
+            unsafe { protected::enter(kernel.entry, boot_info, stack_top) }
+        }
+        let on_return = kernel_return_handler(self.config.on_kernel_return);
+        unsafe { stack::call_on_stack(entry, boot_info, stack_top, on_return) }
+    }
 }
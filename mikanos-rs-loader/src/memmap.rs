@@ -0,0 +1,220 @@
+use alloc::format;
+use alloc::vec::Vec;
+use core::ops::Range;
+use core::slice;
+
+use log::{debug, info};
+use mikanos_rs_common::boot_info::MemoryDescriptor;
+use mikanos_rs_loader_core::config::MemMapFormat;
+use mikanos_rs_loader_core::memory::{self, MemoryMapJson, MemorySummary};
+
+use uefi::CStr16;
+use uefi::mem::memory_map::{
+    MemoryAttribute, MemoryDescriptor as UefiDescriptor, MemoryMap, MemoryMapKey, MemoryMapOwned,
+};
+use uefi::prelude::*;
+use uefi::proto::media::file::RegularFile;
+
+use crate::error::LoaderError;
+use crate::exit;
+
+/// A snapshot of the firmware memory map, from [`fetch_memory_map`].
+///
+/// A thin wrapper around the uefi crate's map that exposes only what the
+/// loader uses.
+pub struct OwnedMemoryMap(MemoryMapOwned);
+
+impl OwnedMemoryMap {
+    /// The descriptors, in the order the firmware returned them.
+    pub fn entries(&self) -> impl Iterator<Item = &UefiDescriptor> {
+        self.0.entries()
+    }
+
+    /// How many descriptors the map has.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// The stride between descriptors in [`Self::bytes`], which can be
+    /// larger than `size_of::<UefiDescriptor>()`.
+    pub fn descriptor_size(&self) -> usize {
+        self.0.meta().desc_size
+    }
+
+    /// The key `ExitBootServices` wants. It is only valid as long as
+    /// nothing has been allocated or freed since the map was fetched.
+    pub fn key(&self) -> MemoryMapKey {
+        self.0.meta().map_key
+    }
+
+    /// The raw descriptors as the firmware laid them out.
+    pub fn bytes(&self) -> &[u8] {
+        &self.0.buffer()[..self.0.meta().map_size]
+    }
+}
+
+/// Fetch the current memory map into a LOADER_DATA buffer.
+pub fn fetch_memory_map() -> Result<OwnedMemoryMap, LoaderError> {
+    Ok(OwnedMemoryMap(boot::memory_map(
+        boot::MemoryType::LOADER_DATA,
+    )?))
+}
+
+/// The file the memory map is written to in `format`.
+pub fn file_path(format: MemMapFormat) -> &'static CStr16 {
+    match format {
+        MemMapFormat::Csv => cstr16!("\\memmap"),
+        MemMapFormat::Binary => cstr16!("\\memmap.bin"),
+        MemMapFormat::Json => cstr16!("\\memmap.json"),
+    }
+}
+
+pub fn save_memory_map(file: &mut RegularFile, format: MemMapFormat) -> Result<(), LoaderError> {
+    let memory_map = fetch_memory_map()?;
+    debug!(
+        "Saving memory map: {} entries, key {:?}",
+        memory_map.len(),
+        memory_map.key()
+    );
+    match format {
+        MemMapFormat::Csv => write_csv(file, &memory_map)?,
+        MemMapFormat::Binary => write_binary(file, &memory_map)?,
+        MemMapFormat::Json => write_json(file, &memory_map)?,
+    }
+    Ok(())
+}
+
+/// The physical address ranges the firmware marks as needed by runtime
+/// services.
+///
+/// These stay in use after exit_boot_services, so any page tables handed to
+/// the kernel have to keep them mapped.
+pub fn runtime_ranges() -> Result<Vec<Range<u64>>, LoaderError> {
+    let memory_map = fetch_memory_map()?;
+    Ok(memory_map
+        .entries()
+        .filter(|desc| desc.att.contains(MemoryAttribute::RUNTIME))
+        .map(|desc| desc.phys_start..desc.phys_start + desc.page_count * 0x1000)
+        .collect())
+}
+
+/// Log the entries of the current memory map that overlap `range`.
+///
+/// Does nothing once the loader has tried to exit boot services, since
+/// fetching the map allocates its buffer, which the firmware doesn't allow
+/// any more, and the uefi crate panics without boot services.
+pub fn log_excerpt(range: Range<u64>) {
+    if !exit::boot_services_usable() {
+        return;
+    }
+    let Ok(memory_map) = fetch_memory_map() else {
+        return;
+    };
+    info!("Memory map at {:#x}..{:#x}:", range.start, range.end);
+    for desc in memory_map.entries() {
+        let end = desc.phys_start + desc.page_count * 0x1000;
+        if desc.phys_start < range.end && range.start < end {
+            info!("  {:#x}..{:#x}  {:?}", desc.phys_start, end, desc.ty);
+        }
+    }
+}
+
+/// Summarize the current memory map, to check how much is left after
+/// everything has been loaded.
+pub fn summary() -> Result<MemorySummary, LoaderError> {
+    let memory_map = fetch_memory_map()?;
+    Ok(memory::summarize_memory(
+        memory_map.entries().map(convert_descriptor),
+    ))
+}
+
+/// The current memory map as the loader's descriptors, for the checks in
+/// [`memory`].
+pub fn descriptors() -> Result<Vec<MemoryDescriptor>, LoaderError> {
+    let memory_map = fetch_memory_map()?;
+    Ok(memory_map.entries().map(convert_descriptor).collect())
+}
+
+/// The loader's copy of a firmware descriptor.
+fn convert_descriptor(desc: &UefiDescriptor) -> MemoryDescriptor {
+    MemoryDescriptor {
+        ty: desc.ty.0,
+        phys_start: desc.phys_start,
+        page_count: desc.page_count,
+        attribute: desc.att.bits(),
+    }
+}
+
+fn write_csv(file: &mut RegularFile, memory_map: &OwnedMemoryMap) -> uefi::Result {
+    file.write(memory::MEMMAP_CSV_HEADER.as_bytes())
+        .discard_errdata()?;
+    for (i, desc) in memory_map.entries().enumerate() {
+        let line = memory::format_memmap_line(i, &convert_descriptor(desc));
+        file.write(line.as_bytes()).discard_errdata()?;
+    }
+    Ok(())
+}
+
+fn write_binary(file: &mut RegularFile, memory_map: &OwnedMemoryMap) -> uefi::Result {
+    file.write(&(memory_map.len() as u64).to_le_bytes())
+        .discard_errdata()?;
+    file.write(&(memory_map.descriptor_size() as u64).to_le_bytes())
+        .discard_errdata()?;
+    file.write(memory_map.bytes()).discard_errdata()?;
+    Ok(())
+}
+
+fn write_json(file: &mut RegularFile, memory_map: &OwnedMemoryMap) -> uefi::Result {
+    let descriptors: Vec<MemoryDescriptor> = memory_map.entries().map(convert_descriptor).collect();
+    file.write(format!("{}", MemoryMapJson(&descriptors)).as_bytes())
+        .discard_errdata()
+}
+
+/// Allocate LOADER_DATA pages for the kernel-owned copy of the memory map
+/// that the firmware writes into the `map_buffer_len` bytes of the map
+/// buffer.
+///
+/// This has to happen before exiting boot services, so the array gets one
+/// entry for every descriptor that fits in the map buffer, however much
+/// the map grows before the exit.
+pub fn allocate_descriptors(
+    map_buffer_len: usize,
+) -> Result<&'static mut [MemoryDescriptor], LoaderError> {
+    let len = map_buffer_len / fetch_memory_map()?.descriptor_size();
+    let page_size = 0x1000;
+    let page_cnt = (len * size_of::<MemoryDescriptor>()).div_ceil(page_size);
+    let ptr = boot::allocate_pages(
+        boot::AllocateType::AnyPages,
+        boot::MemoryType::LOADER_DATA,
+        page_cnt,
+    )?
+    .cast::<MemoryDescriptor>();
+    unsafe {
+        for i in 0..len {
+            ptr.add(i).write(MemoryDescriptor {
+                ty: 0,
+                phys_start: 0,
+                page_count: 0,
+                attribute: 0,
+            });
+        }
+        Ok(slice::from_raw_parts_mut(ptr.as_ptr(), len))
+    }
+}
+
+/// Copy the entries of `memory_map` into `dest` and return how many were
+/// written and how many didn't fit and were dropped.
+///
+/// This doesn't allocate, so it is safe to call after exiting boot
+/// services.
+pub fn copy_descriptors(
+    memory_map: &impl MemoryMap,
+    dest: &mut [MemoryDescriptor],
+) -> (usize, usize) {
+    let mut count = 0;
+    for (desc, entry) in memory_map.entries().zip(dest.iter_mut()) {
+        *entry = convert_descriptor(desc);
+        count += 1;
+    }
+    (count, memory_map.len() - count)
+}
@@ -0,0 +1,43 @@
+use core::time::Duration;
+
+use mikanos_rs_loader_core::menu::{self, KeySource, MenuKey};
+use uefi::println;
+use uefi::proto::console::text::{Key, ScanCode};
+
+use crate::config::BootEntry;
+use crate::input;
+
+/// Let the user pick one of `entries` on the UEFI console, as described
+/// for [`menu::select_entry`].
+///
+/// `entries` must not be empty.
+pub fn select_entry(entries: &[BootEntry], timeout_secs: u64) -> &BootEntry {
+    menu::select_entry(entries, timeout_secs, &mut ConsoleKeys, draw)
+}
+
+fn draw(entries: &[BootEntry], selected: usize) {
+    println!();
+    for (i, entry) in entries.iter().enumerate() {
+        let marker = if i == selected { '>' } else { ' ' };
+        println!("{} {}. {} ({})", marker, i + 1, entry.label, entry.path);
+    }
+}
+
+/// Key presses from the UEFI console input.
+struct ConsoleKeys;
+
+impl KeySource for ConsoleKeys {
+    fn next_key(&mut self, timeout_secs: Option<u64>) -> Option<MenuKey> {
+        let key = input::read_key(timeout_secs.map(Duration::from_secs))?;
+        Some(match key {
+            Key::Special(ScanCode::UP) => MenuKey::Up,
+            Key::Special(ScanCode::DOWN) => MenuKey::Down,
+            Key::Printable(c) if char::from(c) == '\r' => MenuKey::Enter,
+            Key::Printable(c) => match char::from(c).to_digit(10) {
+                Some(n) => MenuKey::Digit(n),
+                None => MenuKey::Other,
+            },
+            Key::Special(_) => MenuKey::Other,
+        })
+    }
+}
@@ -0,0 +1,414 @@
+//! Page tables the loader builds for the kernel.
+//!
+//! The kernel starts on a fresh 4-level page table instead of whatever the
+//! firmware left in CR3. It maps the first [`IDENTITY_MAP_SIZE`] bytes of
+//! physical memory with 2 MiB pages at [`PHYS_MEM_OFFSET`], plus any extra
+//! ranges the loader asks for, such as the kernel image or a framebuffer
+//! that lies above that limit. A kernel linked in the higher half is also
+//! mapped at its link address, with 2 MiB pages where it is aligned for
+//! them if the config asks for `huge_pages`. More mappings can be added with
+//! [`PageTables::map_page`] and [`PageTables::map_large_page`] before the
+//! table is loaded.
+//!
+//! Memory attributes set through the firmware (see the `protect` module)
+//! do not carry over to these tables, so the loader applies the segment
+//! permissions again here: pages of kernel segments without `PF_X` are
+//! non-executable when the CPU supports NX, and with the
+//! `segment-permissions` feature pages of segments without `PF_W` are
+//! read-only.
+
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::ops::Range;
+use core::ptr;
+
+use log::{debug, warn};
+use uefi::boot;
+use uefi::prelude::*;
+
+/// Virtual address at which the kernel finds physical memory.
+///
+/// The loader hands over an identity-mapped address space, so this is zero.
+/// Anything that builds page tables for the kernel must map physical
+/// memory at this offset.
+pub const PHYS_MEM_OFFSET: u64 = 0;
+
+/// How much physical memory, starting from address zero, is mapped with
+/// large pages: 64 GiB.
+pub const IDENTITY_MAP_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
+const PAGE_SIZE: u64 = 0x1000;
+pub const LARGE_PAGE_SIZE: u64 = 0x20_0000;
+
+const PRESENT: u64 = 1 << 0;
+const WRITABLE: u64 = 1 << 1;
+const HUGE_PAGE: u64 = 1 << 7;
+const NO_EXECUTE: u64 = 1 << 63;
+/// Bits 12..52 of an entry hold the physical address it points to.
+const ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// CR4 bit that selects 5-level paging.
+const CR4_LA57: u64 = 1 << 12;
+
+/// The extended feature enable register.
+const IA32_EFER: u32 = 0xc000_0080;
+/// EFER bit that makes bit 63 of page-table entries mean no-execute.
+const EFER_NXE: u64 = 1 << 11;
+/// CPUID leaf with the extended feature bits.
+const CPUID_EXTENDED_FEATURES: u32 = 0x8000_0001;
+/// CPUID `edx` bit of [`CPUID_EXTENDED_FEATURES`] for the NX bit.
+const CPUID_EDX_NX: u32 = 1 << 20;
+
+#[repr(C, align(4096))]
+struct PageTable([u64; 512]);
+
+/// A 4-level page table hierarchy in LOADER_DATA pages.
+///
+/// Table frames are allocated with boot services, so everything has to be
+/// mapped before exiting them.
+pub struct PageTables {
+    pml4: *mut PageTable,
+}
+
+impl PageTables {
+    pub fn new() -> uefi::Result<Self> {
+        Ok(Self {
+            pml4: alloc_table()?,
+        })
+    }
+
+    /// The value to load into CR3 to switch to these tables.
+    pub fn cr3(&self) -> u64 {
+        self.pml4 as u64
+    }
+
+    /// Map the 2 MiB page at `virt` to `phys`.
+    pub fn map_large_page(&mut self, virt: u64, phys: u64) -> uefi::Result {
+        let pd = self.page_directory(virt)?;
+        let entry = unsafe { &mut (*pd).0[index(virt, 1)] };
+        *entry = phys & ADDR_MASK | PRESENT | WRITABLE | HUGE_PAGE;
+        Ok(())
+    }
+
+    /// Map the 4 KiB page at `virt` to `phys`.
+    ///
+    /// If `virt` is already covered by a large page that maps it to `phys`,
+    /// nothing changes. Any other overlap with a large page is an error.
+    pub fn map_page(&mut self, virt: u64, phys: u64) -> uefi::Result {
+        let pd = self.page_directory(virt)?;
+        let pde = unsafe { &mut (*pd).0[index(virt, 1)] };
+        if *pde & HUGE_PAGE != 0 {
+            let mapped = (*pde & ADDR_MASK) + (virt & (LARGE_PAGE_SIZE - 1));
+            if mapped == phys & !(PAGE_SIZE - 1) {
+                return Ok(());
+            }
+            return Err(Status::INVALID_PARAMETER.into());
+        }
+        let pt = next_table(pde)?;
+        let entry = unsafe { &mut (*pt).0[index(virt, 0)] };
+        *entry = phys & ADDR_MASK | PRESENT | WRITABLE;
+        Ok(())
+    }
+
+    /// Map every 4 KiB page overlapping `range` of physical memory at
+    /// [`PHYS_MEM_OFFSET`].
+    pub fn map_physical(&mut self, range: Range<u64>) -> uefi::Result {
+        let mut phys = range.start & !(PAGE_SIZE - 1);
+        while phys < range.end {
+            self.map_page(PHYS_MEM_OFFSET + phys, phys)?;
+            phys += PAGE_SIZE;
+        }
+        Ok(())
+    }
+
+    /// Map the 4 KiB pages overlapping the virtual `range` to consecutive
+    /// physical pages, where `phys` is the physical address of
+    /// `range.start`.
+    ///
+    /// With `large`, every 2 MiB page that lies entirely inside the range
+    /// and starts on a 2 MiB boundary both virtually and physically is
+    /// mapped with [`PageTables::map_large_page`] instead. That needs
+    /// `range.start` and `phys` to agree modulo 2 MiB.
+    pub fn map_range(&mut self, range: Range<u64>, phys: u64, large: bool) -> uefi::Result {
+        let offset = range.start & (PAGE_SIZE - 1);
+        let mut virt = range.start - offset;
+        let mut phys = phys - offset;
+        let end = range.end.next_multiple_of(PAGE_SIZE);
+        while virt < end {
+            let step = if large
+                && virt.is_multiple_of(LARGE_PAGE_SIZE)
+                && phys.is_multiple_of(LARGE_PAGE_SIZE)
+                && end - virt >= LARGE_PAGE_SIZE
+            {
+                self.map_large_page(virt, phys)?;
+                LARGE_PAGE_SIZE
+            } else {
+                self.map_page(virt, phys)?;
+                PAGE_SIZE
+            };
+            virt += step;
+            phys += step;
+        }
+        Ok(())
+    }
+
+    /// Clear the writable bit of every 4 KiB page overlapping the virtual
+    /// `range`, splitting large pages where needed. Pages that aren't
+    /// mapped stay unmapped.
+    ///
+    /// Anything in `range` the loader still has to write, such as
+    /// relocations, must be written before the tables are loaded. Ring 0
+    /// only faults on writes to these pages while `CR0.WP` is set, which
+    /// [`enable_write_protect`] takes care of.
+    pub fn write_protect(&mut self, range: Range<u64>) -> uefi::Result {
+        self.update_pages(range, |pte| *pte &= !WRITABLE)
+    }
+
+    /// Set the no-execute bit of every 4 KiB page overlapping the virtual
+    /// `range`, splitting large pages where needed. Pages that aren't
+    /// mapped stay unmapped.
+    ///
+    /// The bit is reserved unless [`enable_nx`] succeeded; setting it
+    /// without NXE makes every access to the page fault.
+    pub fn forbid_execute(&mut self, range: Range<u64>) -> uefi::Result {
+        self.update_pages(range, |pte| *pte |= NO_EXECUTE)
+    }
+
+    /// Apply `update` to the entry of every mapped 4 KiB page overlapping
+    /// the virtual `range`, splitting large pages first.
+    fn update_pages(&mut self, range: Range<u64>, update: impl Fn(&mut u64)) -> uefi::Result {
+        let mut virt = range.start & !(PAGE_SIZE - 1);
+        while virt < range.end {
+            let pd = self.page_directory(virt)?;
+            let pde = unsafe { &mut (*pd).0[index(virt, 1)] };
+            if *pde & PRESENT != 0 {
+                if *pde & HUGE_PAGE != 0 {
+                    split_large_page(pde)?;
+                }
+                let pt = (*pde & ADDR_MASK) as *mut PageTable;
+                update(unsafe { &mut (*pt).0[index(virt, 0)] });
+            }
+            virt += PAGE_SIZE;
+        }
+        Ok(())
+    }
+
+    /// The page directory covering `virt`, allocating it and its PDPT as
+    /// needed.
+    fn page_directory(&mut self, virt: u64) -> uefi::Result<*mut PageTable> {
+        let pml4e = unsafe { &mut (*self.pml4).0[index(virt, 3)] };
+        let pdpt = next_table(pml4e)?;
+        let pdpte = unsafe { &mut (*pdpt).0[index(virt, 2)] };
+        next_table(pdpte)
+    }
+}
+
+/// Index into the table at `level` (0 = PT, ..., 3 = PML4) for `virt`.
+fn index(virt: u64, level: usize) -> usize {
+    ((virt >> (12 + 9 * level)) & 0x1ff) as usize
+}
+
+/// The table `entry` points to, allocating it if the entry is empty.
+fn next_table(entry: &mut u64) -> uefi::Result<*mut PageTable> {
+    if *entry & PRESENT == 0 {
+        let table = alloc_table()?;
+        *entry = table as u64 | PRESENT | WRITABLE;
+    }
+    Ok((*entry & ADDR_MASK) as *mut PageTable)
+}
+
+/// Replace the large page `pde` maps with a page table of 4 KiB pages that
+/// map the same memory with the same flags.
+fn split_large_page(pde: &mut u64) -> uefi::Result {
+    let table = alloc_table()?;
+    let phys = *pde & ADDR_MASK;
+    let flags = *pde & !ADDR_MASK & !HUGE_PAGE;
+    for (i, entry) in unsafe { (*table).0.iter_mut() }.enumerate() {
+        *entry = (phys + i as u64 * PAGE_SIZE) | flags;
+    }
+    *pde = table as u64 | PRESENT | WRITABLE;
+    Ok(())
+}
+
+/// Allocate a zeroed page-table frame.
+///
+/// The firmware identity maps memory, so the pointer is also the frame's
+/// physical address.
+fn alloc_table() -> uefi::Result<*mut PageTable> {
+    let ptr = boot::allocate_pages(
+        boot::AllocateType::AnyPages,
+        boot::MemoryType::LOADER_DATA,
+        1,
+    )?
+    .cast::<PageTable>()
+    .as_ptr();
+    unsafe { ptr::write_bytes(ptr, 0, 1) };
+    Ok(ptr)
+}
+
+/// Build the kernel's page tables and return the CR3 value for them.
+///
+/// The first [`IDENTITY_MAP_SIZE`] bytes of physical memory are mapped with
+/// large pages, which covers the loader, the kernel stack and everything
+/// else allocated as LOADER_DATA on typical machines. Each of `extra` is
+/// mapped on top of that, for memory that may lie above the limit. Each
+/// `(range, phys)` in `remapped` maps the virtual `range` to the physical
+/// memory starting at `phys`, for an image linked in the higher half, with
+/// 2 MiB pages where possible if `huge_pages` is set. Finally, every
+/// virtual range in `read_only` is write-protected with
+/// [`PageTables::write_protect`], and if `nx` says [`enable_nx`] succeeded,
+/// every one in `no_execute` is made non-executable with
+/// [`PageTables::forbid_execute`].
+///
+/// Returns `Ok(None)` if the firmware runs with 5-level paging, which
+/// these tables don't support; the firmware's tables stay in place then.
+pub fn build_page_tables(
+    extra: &[Range<u64>],
+    remapped: &[(Range<u64>, u64)],
+    read_only: &[Range<u64>],
+    no_execute: &[Range<u64>],
+    huge_pages: bool,
+    nx: bool,
+) -> uefi::Result<Option<u64>> {
+    let cr4: u64;
+    unsafe { asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack)) };
+    if cr4 & CR4_LA57 != 0 {
+        return Ok(None);
+    }
+
+    let mut tables = PageTables::new()?;
+    let mut phys = 0;
+    while phys < IDENTITY_MAP_SIZE {
+        tables.map_large_page(PHYS_MEM_OFFSET + phys, phys)?;
+        phys += LARGE_PAGE_SIZE;
+    }
+    for range in extra {
+        tables.map_physical(range.clone())?;
+    }
+    for (range, phys) in remapped {
+        tables.map_range(range.clone(), *phys, huge_pages)?;
+    }
+    for range in read_only {
+        tables.write_protect(range.clone())?;
+        debug!("Mapped {:#x}..{:#x} read-only", range.start, range.end);
+    }
+    if nx {
+        for range in no_execute {
+            tables.forbid_execute(range.clone())?;
+            debug!("Mapped {:#x}..{:#x} non-executable", range.start, range.end);
+        }
+    }
+    debug!("Built kernel page tables at {:#x}", tables.cr3());
+    Ok(Some(tables.cr3()))
+}
+
+/// Whether the CPU supports no-execute pages.
+pub fn nx_supported() -> bool {
+    let cpuid = __cpuid(CPUID_EXTENDED_FEATURES);
+    cpuid.edx & CPUID_EDX_NX != 0
+}
+
+/// Set the NXE bit in EFER so page-table entries can mark pages
+/// non-executable. Without it, bit 63 of an entry is reserved and setting
+/// it faults. Returns `false`, and leaves EFER alone, if the CPU has no NX
+/// support.
+pub fn enable_nx() -> bool {
+    if !nx_supported() {
+        warn!("The CPU doesn't support NX, kernel data pages stay executable.");
+        return false;
+    }
+    unsafe {
+        let efer = rdmsr(IA32_EFER);
+        if efer & EFER_NXE == 0 {
+            wrmsr(IA32_EFER, efer | EFER_NXE);
+            debug!("Enabled NX in EFER");
+        }
+    }
+    true
+}
+
+/// Read the model-specific register `msr`.
+unsafe fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack));
+    }
+    (high as u64) << 32 | low as u64
+}
+
+/// Write `value` to the model-specific register `msr`.
+unsafe fn wrmsr(msr: u32, value: u64) {
+    let (low, high) = (value as u32, (value >> 32) as u32);
+    unsafe {
+        asm!("wrmsr", in("ecx") msr, in("eax") low, in("edx") high, options(nostack));
+    }
+}
+
+/// Where a virtual address leads in a set of page tables, with the access
+/// every level of the walk allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    pub phys: u64,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+/// Walk the page tables at `cr3` for `virt`. Returns `None` if it isn't
+/// mapped.
+///
+/// # Safety
+///
+/// `cr3` must come from [`build_page_tables`], whose tables are reachable
+/// through the firmware's identity map.
+pub unsafe fn translate(cr3: u64, virt: u64) -> Option<Translation> {
+    let mut table = (cr3 & ADDR_MASK) as *const PageTable;
+    let mut writable = true;
+    let mut executable = true;
+    for level in (0..4).rev() {
+        let entry = unsafe { (*table).0[index(virt, level)] };
+        if entry & PRESENT == 0 {
+            return None;
+        }
+        writable &= entry & WRITABLE != 0;
+        executable &= entry & NO_EXECUTE == 0;
+        if level == 0 || entry & HUGE_PAGE != 0 {
+            let page_size = PAGE_SIZE << (9 * level);
+            return Some(Translation {
+                phys: (entry & ADDR_MASK & !(page_size - 1)) + (virt & (page_size - 1)),
+                writable,
+                executable,
+            });
+        }
+        table = (entry & ADDR_MASK) as *const PageTable;
+    }
+    unreachable!()
+}
+
+/// `CR0.WP`: make ring 0 honor read-only pages.
+const CR0_WP: u64 = 1 << 16;
+
+/// Set `CR0.WP`, so that the kernel faults on writes to the pages
+/// [`PageTables::write_protect`] made read-only. Firmware isn't required
+/// to leave it set.
+pub fn enable_write_protect() {
+    unsafe {
+        asm!(
+            "mov {tmp}, cr0",
+            "or {tmp}, {wp}",
+            "mov cr0, {tmp}",
+            tmp = out(reg) _,
+            wp = const CR0_WP,
+            options(nomem, nostack),
+        )
+    };
+}
+
+/// Switch to the page tables at `cr3`.
+///
+/// # Safety
+///
+/// The tables must map the code that is running, its stack and everything
+/// it touches afterwards.
+pub unsafe fn load_cr3(cr3: u64) {
+    unsafe { asm!("mov cr3, {}", in(reg) cr3, options(nostack)) };
+}
@@ -0,0 +1,26 @@
+use core::arch::asm;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+
+use uefi::system;
+
+use crate::logger;
+use mikanos_rs_common::serial::SerialPort;
+
+/// Print the panic to COM1 and, while it is still usable, the UEFI console,
+/// then halt with interrupts disabled.
+///
+/// Nothing here allocates, so this also works after exit_boot_services.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let _ = writeln!(SerialPort::com1(), "[PANIC]: {}", info);
+    // The console is switched off before exiting boot services.
+    if logger::console_enabled() {
+        system::with_stdout(|stdout| {
+            let _ = writeln!(stdout, "[PANIC]: {}", info);
+        });
+    }
+    loop {
+        unsafe { asm!("cli", "hlt", options(nomem, nostack)) };
+    }
+}
@@ -0,0 +1,97 @@
+use mikanos_rs_common::boot_info::FrameBufferInfo;
+use mikanos_rs_common::framebuffer;
+use mikanos_rs_loader_core::bmp::Bitmap;
+
+const BAR_HEIGHT: u32 = 16;
+const BAR_BORDER: u32 = 0x808080;
+const BAR_FILL: u32 = 0xffffff;
+
+/// Write one `0xRRGGBB` pixel. Out-of-range coordinates are ignored.
+fn write_pixel(fb: &FrameBufferInfo, x: u32, y: u32, color: u32) {
+    // The framebuffer GOP reported, which the loader may write to until it
+    // hands it over.
+    unsafe { framebuffer::put_pixel(fb, x, y, color) };
+}
+
+/// Fill a `w` x `h` rectangle whose top-left corner is at (`x`, `y`).
+pub fn draw_rect(fb: &FrameBufferInfo, x: u32, y: u32, w: u32, h: u32, color: u32) {
+    for dy in 0..h {
+        for dx in 0..w {
+            write_pixel(fb, x + dx, y + dy, color);
+        }
+    }
+}
+
+/// Fill the whole framebuffer with `color`.
+///
+/// Pixels are addressed through `pixels_per_scan_line`, so padding doesn't
+/// skew the result.
+pub fn clear_screen(fb: &FrameBufferInfo, color: u32) {
+    draw_rect(
+        fb,
+        0,
+        0,
+        fb.horizontal_resolution,
+        fb.vertical_resolution,
+        color,
+    );
+}
+
+/// Draw `image` centered on the screen, cutting off whatever doesn't fit.
+pub fn draw_bitmap(fb: &FrameBufferInfo, image: &Bitmap) {
+    let w = u32::min(image.width, fb.horizontal_resolution);
+    let h = u32::min(image.height, fb.vertical_resolution);
+    let (src_x, src_y) = ((image.width - w) / 2, (image.height - h) / 2);
+    let (dst_x, dst_y) = (
+        (fb.horizontal_resolution - w) / 2,
+        (fb.vertical_resolution - h) / 2,
+    );
+    for dy in 0..h {
+        for dx in 0..w {
+            let color = image.pixel(src_x + dx, src_y + dy);
+            write_pixel(fb, dst_x + dx, dst_y + dy, color);
+        }
+    }
+}
+
+/// A progress bar drawn across the lower part of the screen.
+///
+/// Does nothing if it was created without a framebuffer, so callers can
+/// advance it unconditionally.
+pub struct ProgressBar {
+    fb: Option<FrameBufferInfo>,
+    steps: u32,
+    done: u32,
+}
+
+impl ProgressBar {
+    pub fn new(fb: Option<FrameBufferInfo>, steps: u32) -> Self {
+        let bar = Self { fb, steps, done: 0 };
+        if let Some(fb) = &bar.fb {
+            let (x, y, w) = bar.geometry(fb);
+            draw_rect(fb, x - 1, y - 1, w + 2, BAR_HEIGHT + 2, BAR_BORDER);
+            draw_rect(fb, x, y, w, BAR_HEIGHT, 0);
+        }
+        bar
+    }
+
+    /// Mark one more step as done.
+    pub fn advance(&mut self) {
+        self.done = u32::min(self.done + 1, self.steps);
+        if let Some(fb) = &self.fb {
+            let (x, y, w) = self.geometry(fb);
+            let filled = w * self.done / self.steps;
+            draw_rect(fb, x, y, filled, BAR_HEIGHT, BAR_FILL);
+        }
+    }
+
+    /// Left edge, top edge and width of the bar's interior.
+    fn geometry(&self, fb: &FrameBufferInfo) -> (u32, u32, u32) {
+        let w = fb.horizontal_resolution / 2;
+        (
+            fb.horizontal_resolution / 4,
+            fb.vertical_resolution * 3 / 4,
+            w,
+        )
+    }
+}
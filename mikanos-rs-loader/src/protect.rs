@@ -0,0 +1,72 @@
+use goblin::elf::program_header::{PF_W, PF_X};
+use log::{debug, warn};
+use mikanos_rs_loader_core::elf::Segment;
+use uefi::boot;
+use uefi::mem::memory_map::MemoryAttribute;
+use uefi::proto::security::MemoryProtection;
+
+use crate::error::LoaderError;
+use crate::paging;
+
+/// Make every loaded segment in `segments` only as accessible as its `p_flags`
+/// ask for: segments without `PF_X` become non-executable and segments
+/// without `PF_W` read-only.
+///
+/// The firmware owns the page tables at this point, so this goes through
+/// `EFI_MEMORY_ATTRIBUTE_PROTOCOL`. Firmware without the protocol leaves
+/// the image as it is, with a warning. A page that is shared by two
+/// segments keeps the more permissive of their flags. On a CPU without NX
+/// support, only the read-only marking is done.
+///
+/// Must run after the segments have been copied and relocated, since it may
+/// make them read-only.
+pub fn protect_segments(segments: &[Segment], bias: u64) -> Result<(), LoaderError> {
+    let handle = match boot::get_handle_for_protocol::<MemoryProtection>() {
+        Ok(handle) => handle,
+        Err(_) => {
+            warn!("No memory attribute protocol, kernel segments stay RWX.");
+            return Ok(());
+        }
+    };
+    let protection = boot::open_protocol_exclusive::<MemoryProtection>(handle)?;
+    let nx = paging::nx_supported();
+
+    let page_size = 0x1000;
+    for segment in segments {
+        // Only pages that belong to this segment alone.
+        let start = segment.vaddr.wrapping_add(bias).next_multiple_of(page_size);
+        let end = segment.vaddr.wrapping_add(bias) + segment.mem_size as u64;
+        let end = end & !(page_size - 1);
+        if start >= end {
+            continue;
+        }
+
+        let mut restrict = MemoryAttribute::empty();
+        let mut allow = MemoryAttribute::empty();
+        if nx {
+            if segment.flags & PF_X == 0 {
+                restrict |= MemoryAttribute::EXECUTE_PROTECT;
+            } else {
+                allow |= MemoryAttribute::EXECUTE_PROTECT;
+            }
+        }
+        if segment.flags & PF_W == 0 {
+            restrict |= MemoryAttribute::READ_ONLY;
+        } else {
+            allow |= MemoryAttribute::READ_ONLY;
+        }
+        // Freshly allocated pages may already be non-executable, so clear
+        // what the segment needs as well as setting what it doesn't.
+        if !allow.is_empty() {
+            protection.clear_memory_attributes(start..end, allow)?;
+        }
+        if !restrict.is_empty() {
+            protection.set_memory_attributes(start..end, restrict)?;
+        }
+        debug!(
+            "Protected {:#x}..{:#x}: set {:?}, cleared {:?}",
+            start, end, restrict, allow
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,229 @@
+//! The experimental `entry_mode=protected` handoff: leave long mode and
+//! call the kernel in 32-bit protected mode with paging off, for kernels
+//! that start with a 32-bit stub and set up long mode themselves.
+//!
+//! The kernel is still loaded from an ELF64 file as usual; only the CPU
+//! mode at the jump changes. The stub is called like a cdecl
+//! `void entry(BootInfo *)`: on entry `[esp]` is a return address and
+//! `[esp + 4]` the pointer to `BootInfo`, which keeps its 64-bit layout.
+//! `abi` and `on_kernel_return` don't apply; if the stub returns, the CPU
+//! stops.
+//!
+//! The state the stub starts in:
+//!
+//! - `cs` is a flat 32-bit code segment and every other segment register a
+//!   flat data segment, both from a GDT in the loader's image. The stub
+//!   should load its own GDT before it reuses the loader's memory.
+//! - `CR0.PG`, `CR4.PAE` and `EFER.LME` are clear, so addresses are
+//!   physical. `EFER.NXE` is left as the loader set it.
+//! - Interrupts are disabled and the IDT is the firmware's, which can't be
+//!   used from 32-bit code. NMIs stay enabled and will triple fault.
+//!
+//! Leaving long mode follows the AMD64 manual, volume 2, section 14.7:
+//! switch to a compatibility-mode code segment, clear `CR0.PG` there, which
+//! turns long mode off, then clear `EFER.LME`. Paging goes away under the
+//! code that does this, so it has to run from identity-mapped memory. With
+//! no paging nothing above 4 GiB is reachable, which is why [`check`]
+//! wants the loader itself, its GDT, the kernel and everything the stub is
+//! handed below 4 GiB.
+
+use alloc::format;
+use alloc::string::ToString;
+use alloc::vec;
+use core::arch::asm;
+use core::ops::Range;
+use core::ptr;
+
+use mikanos_rs_common::boot_info::{BootInfo, ConfigTable};
+
+use crate::error::LoaderError;
+use crate::logger;
+
+/// Everything protected mode can address.
+const LIMIT: u64 = 1 << 32;
+
+/// Selector of the 32-bit code segment in [`GDT`].
+const CODE_SELECTOR: u16 = 0x08;
+/// Selector of the data segment in [`GDT`].
+const DATA_SELECTOR: u16 = 0x10;
+
+/// A null descriptor, then flat 4 GiB segments with base 0 and 4 KiB
+/// granularity: ring-0 code that is 32-bit (`D=1`, `L=0`) and ring-0
+/// writable data.
+#[repr(C, align(8))]
+struct Gdt([u64; 3]);
+
+static GDT: Gdt = Gdt([0, 0x00cf_9a00_0000_ffff, 0x00cf_9200_0000_ffff]);
+
+/// The operand of `lgdt` in 64-bit mode. Only the low 32 bits of `base`
+/// survive the switch, which [`check`] makes sure is enough.
+#[repr(C, packed)]
+struct GdtPointer {
+    limit: u16,
+    base: u64,
+}
+
+/// `CR0.PG`, `CR4.PAE` and `EFER.LME` as masks that clear them.
+const CLEAR_PG: u32 = !(1 << 31);
+const CLEAR_PAE: u32 = !(1 << 5);
+const CLEAR_LME: u32 = !(1 << 8);
+/// The `EFER` MSR.
+const IA32_EFER: u32 = 0xc000_0080;
+
+/// Check, while boot services can still report it, that [`enter`] can
+/// reach everything with paging off. `identity` says whether the kernel
+/// runs at the physical addresses it was loaded to.
+///
+/// Besides the `kernel` image and the `loader`, this covers the named
+/// `handoff` allocations, which `boot_info` doesn't point to yet, and
+/// every region `boot_info` already points to.
+pub fn check(
+    entry: u64,
+    identity: bool,
+    kernel: Range<u64>,
+    handoff: &[(&'static str, Range<u64>)],
+    boot_info: &BootInfo,
+    loader: Range<u64>,
+) -> Result<(), LoaderError> {
+    if !identity {
+        return Err(LoaderError::ProtectedMode(
+            "the kernel is mapped away from where it was loaded".to_string(),
+        ));
+    }
+    if entry >= LIMIT {
+        return Err(LoaderError::ProtectedMode(
+            "its entry point is above 4 GiB".to_string(),
+        ));
+    }
+    let region = |base: u64, size: u64| base..base + size;
+    let boot_log = logger::boot_log();
+    let tables = &boot_info.config_tables;
+    let mut regions = vec![
+        ("kernel image", kernel),
+        ("loader itself", loader),
+        (
+            "initrd",
+            region(boot_info.initrd.base as u64, boot_info.initrd.size as u64),
+        ),
+        (
+            "font",
+            region(boot_info.font.base as u64, boot_info.font.size as u64),
+        ),
+        // With the NUL after it.
+        (
+            "command line",
+            region(
+                boot_info.cmdline.base as u64,
+                boot_info.cmdline.size as u64 + 1,
+            ),
+        ),
+        ("app", region(boot_info.app.base, boot_info.app.size)),
+        (
+            "back buffer",
+            region(
+                boot_info.back_buffer.base as u64,
+                boot_info.back_buffer.size as u64,
+            ),
+        ),
+        (
+            "boot archive",
+            region(boot_info.archive.base as u64, boot_info.archive.size as u64),
+        ),
+        (
+            "boot log",
+            region(boot_log.base as u64, boot_log.capacity as u64),
+        ),
+        (
+            "copy of the configuration table",
+            region(
+                tables.entries as u64,
+                (tables.count * size_of::<ConfigTable>()) as u64,
+            ),
+        ),
+    ];
+    regions.extend_from_slice(handoff);
+    regions.extend(
+        boot_info.modules[..boot_info.module_count]
+            .iter()
+            .map(|module| ("module", region(module.base, module.size))),
+    );
+    match regions.iter().find(|(_, range)| range.end > LIMIT) {
+        Some((name, _)) => Err(LoaderError::ProtectedMode(format!(
+            "the {} would be out of reach above 4 GiB",
+            name
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Leave long mode and call `entry` in 32-bit protected mode on the stack
+/// ending at `stack_top`, with a pointer to `boot_info` as its argument.
+///
+/// # Safety
+///
+/// Boot services must be gone and [`check`] must have passed for the same
+/// addresses. The page tables in use must identity-map the loader's image.
+/// Nothing on the loader stack is reachable afterwards.
+pub unsafe fn enter(entry: u64, boot_info: &BootInfo, stack_top: u64) -> ! {
+    // The argument goes where `esp` points before the `call`, which the
+    // i386 System V ABI wants 16-byte aligned.
+    let args = (stack_top - 16) & !0xf;
+    unsafe { ptr::write(args as *mut u32, ptr::from_ref(boot_info) as u32) };
+    let gdtr = GdtPointer {
+        limit: size_of::<Gdt>() as u16 - 1,
+        base: ptr::from_ref(&GDT) as u64,
+    };
+    unsafe {
+        asm!(
+            "cli",
+            "lgdt [{gdtr}]",
+            // Still in 64-bit mode, on the loader's code segment. `esp`
+            // only keeps the low half of `rsp`, so switch now.
+            "mov rsp, rsi",
+            // Far return to the 32-bit segment: it pops `rip`, then `cs`.
+            "push {code}",
+            "lea rax, [rip + 2f]",
+            "push rax",
+            "retfq",
+            ".code32",
+            "2:",
+            // Compatibility mode. Turning paging off leaves long mode.
+            "mov eax, cr0",
+            "and eax, {clear_pg}",
+            "mov cr0, eax",
+            "jmp 3f",
+            "3:",
+            // Legacy protected mode now. Clear the bits that would bring
+            // long mode back if the kernel re-enabled paging.
+            "mov ecx, {efer}",
+            "rdmsr",
+            "and eax, {clear_lme}",
+            "wrmsr",
+            "mov eax, cr4",
+            "and eax, {clear_pae}",
+            "mov cr4, eax",
+            "mov ax, {data}",
+            "mov ds, ax",
+            "mov es, ax",
+            "mov fs, ax",
+            "mov gs, ax",
+            "mov ss, ax",
+            "call edi",
+            "4:",
+            "cli",
+            "hlt",
+            "jmp 4b",
+            ".code64",
+            gdtr = in(reg) &gdtr,
+            code = const CODE_SELECTOR,
+            data = const DATA_SELECTOR,
+            clear_pg = const CLEAR_PG,
+            clear_pae = const CLEAR_PAE,
+            clear_lme = const CLEAR_LME,
+            efer = const IA32_EFER,
+            in("rsi") args,
+            in("rdi") entry,
+            options(noreturn),
+        )
+    }
+}
@@ -0,0 +1,88 @@
+use core::arch::asm;
+use core::ptr;
+
+use log::debug;
+use mikanos_rs_common::boot_info::{BootInfo, MemoryRegion};
+use mikanos_rs_common::memory_type;
+use uefi::boot;
+
+use crate::KernelEntry;
+
+/// Allocate a kernel stack of `size` bytes, a multiple of the page size.
+/// Its end is page-aligned, so it can be used as the stack top as is.
+///
+/// The stack has type [`memory_type::KERNEL_STACK`], so it stays reserved
+/// after exit_boot_services unlike the firmware stack the loader runs on.
+pub fn allocate_kernel_stack(size: usize) -> uefi::Result<MemoryRegion> {
+    let page_size = 0x1000;
+    let base = boot::allocate_pages(
+        boot::AllocateType::AnyPages,
+        boot::MemoryType::custom(memory_type::KERNEL_STACK),
+        size.div_ceil(page_size),
+    )?;
+    debug!("Allocated a {} KiB kernel stack at {:p}", size / 1024, base);
+    Ok(MemoryRegion {
+        base: base.as_ptr(),
+        size,
+    })
+}
+
+/// Switch `rsp` to `stack_top` and call `entry` with `boot_info` passed
+/// the way its ABI expects:
+///
+/// - [`KernelEntry::Pointer`]: `rdi` points to `boot_info`.
+/// - [`KernelEntry::Value`]: `boot_info` is copied to the top of the kernel
+///   stack and `rsp` points to the copy, so the kernel finds it at
+///   `rsp + 8` after the `call`.
+/// - [`KernelEntry::Split`]: `rdi` points to `boot_info.framebuffer` and
+///   `rsi` to `boot_info.memory_map`.
+///
+/// The sysv64 ABI requires `rsp` to be a multiple of 16 right before the
+/// `call`, so that it is `8 mod 16` at the callee's first instruction. A
+/// kernel prologue that spills SSE registers with `movaps` faults
+/// otherwise. `stack_top` is rounded down to make sure.
+///
+/// If the kernel returns, `on_return` is called on the kernel stack. It is
+/// kept in `r12`, which sysv64 makes the kernel preserve.
+///
+/// # Safety
+///
+/// `stack_top` must be the top of writable memory that nothing else uses,
+/// and `entry` must be the kernel entry point. Nothing on the loader stack
+/// is reachable afterwards.
+pub unsafe fn call_on_stack(
+    entry: KernelEntry,
+    boot_info: &BootInfo,
+    stack_top: u64,
+    on_return: extern "sysv64" fn() -> !,
+) -> ! {
+    let (stack_top, rdi, rsi) = match entry {
+        KernelEntry::Pointer(_) => (stack_top, ptr::from_ref(boot_info) as u64, 0),
+        KernelEntry::Value(_) => {
+            // The copy is the argument area, which has to start 16-byte
+            // aligned where `rsp` points before the call.
+            let args = (stack_top - size_of::<BootInfo>() as u64) & !0xf;
+            unsafe { ptr::write(args as *mut BootInfo, *boot_info) };
+            (args, 0, 0)
+        }
+        KernelEntry::Split(_) => (
+            stack_top,
+            ptr::from_ref(&boot_info.framebuffer) as u64,
+            ptr::from_ref(&boot_info.memory_map) as u64,
+        ),
+    };
+    unsafe {
+        asm!(
+            "mov rsp, {stack_top}",
+            "and rsp, -16",
+            "call {entry}",
+            "call r12",
+            stack_top = in(reg) stack_top,
+            entry = in(reg) entry.addr(),
+            in("rdi") rdi,
+            in("rsi") rsi,
+            in("r12") on_return,
+            options(noreturn),
+        )
+    }
+}
@@ -0,0 +1,87 @@
+//! Wall-clock timing from the time stamp counter.
+//!
+//! The TSC is calibrated once against `boot::stall`, so [`calibrate`] has to
+//! run while boot services are active. The result is cached, which keeps
+//! [`tsc_delay`] usable after exit_boot_services. On CPUs with an invariant
+//! TSC it runs at a constant rate regardless of power states, which makes
+//! it usable as a monotonic clock. On older CPUs the rate can change with
+//! the clock speed, so delays and measurements are only approximate.
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use log::{debug, warn};
+use uefi::boot;
+
+/// How long the calibration waits, in microseconds.
+const CALIBRATION_US: usize = 10_000;
+
+/// TSC ticks per second, or 0 before [`calibrate`] has run.
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Whether the CPU reports an invariant TSC (CPUID 0x80000007, EDX bit 8).
+fn invariant_tsc() -> bool {
+    let max_extended = __cpuid(0x8000_0000).eax;
+    max_extended >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+/// Measure the TSC frequency in Hz, or return the cached result of an
+/// earlier call.
+///
+/// The first call stalls for [`CALIBRATION_US`] microseconds and must
+/// happen before exiting boot services.
+pub fn calibrate() -> u64 {
+    let cached = TSC_FREQUENCY.load(Ordering::Relaxed);
+    if cached != 0 {
+        return cached;
+    }
+    let before = unsafe { _rdtsc() };
+    boot::stall(CALIBRATION_US);
+    let after = unsafe { _rdtsc() };
+    let frequency = u64::max((after - before) * (1_000_000 / CALIBRATION_US as u64), 1);
+    TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+    debug!("TSC runs at {} kHz", frequency / 1000);
+    if !invariant_tsc() {
+        warn!("The TSC is not invariant, so timing may be off.");
+    }
+    frequency
+}
+
+/// Busy-wait for `micros` microseconds using the TSC.
+///
+/// Unlike `boot::stall`, this works after exit_boot_services, but only if
+/// [`calibrate`] ran before. Without a calibration it returns right away.
+pub fn tsc_delay(micros: u64) {
+    let frequency = TSC_FREQUENCY.load(Ordering::Relaxed);
+    if frequency == 0 {
+        return;
+    }
+    let ticks = (micros as u128 * frequency as u128 / 1_000_000) as u64;
+    let start = unsafe { _rdtsc() };
+    while unsafe { _rdtsc() }.wrapping_sub(start) < ticks {
+        core::hint::spin_loop();
+    }
+}
+
+/// A started measurement.
+pub struct Stopwatch {
+    start: u64,
+    ticks_per_ms: u64,
+}
+
+impl Stopwatch {
+    /// Start measuring, calibrating the TSC first if that hasn't happened
+    /// yet.
+    pub fn start() -> Self {
+        let ticks_per_ms = u64::max(calibrate() / 1000, 1);
+        Self {
+            start: unsafe { _rdtsc() },
+            ticks_per_ms,
+        }
+    }
+
+    /// Milliseconds since [`Stopwatch::start`] returned.
+    pub fn elapsed_ms(&self) -> u64 {
+        (unsafe { _rdtsc() } - self.start) / self.ticks_per_ms
+    }
+}
@@ -0,0 +1,121 @@
+//! The `verify=true` check of the kernel's page tables before the jump.
+//!
+//! Only the kernel image is checked: every page of each segment has to map
+//! to the memory it was copied to, read-only and non-executable exactly
+//! where the loader made it so, and still hold the first byte it was
+//! loaded with. Those problems are logged and the boot goes on, since the
+//! kernel may never touch the page in question. An entry point the CPU
+//! couldn't execute fails the boot instead.
+
+use core::ops::Range;
+
+use log::{info, warn};
+
+use crate::LoadedElf;
+use crate::error::LoaderError;
+use crate::paging;
+
+const PAGE_SIZE: u64 = 0x1000;
+
+/// Check `kernel` in the page tables at `cr3`, which write-protect the
+/// virtual ranges in `read_only` and, if `nx` says NX is enabled, forbid
+/// executing the kernel's `no_execute` pages.
+pub fn verify_kernel(
+    cr3: u64,
+    kernel: &LoadedElf,
+    read_only: &[Range<u64>],
+    nx: bool,
+) -> Result<(), LoaderError> {
+    let no_execute: &[Range<u64>] = if nx { &kernel.no_execute } else { &[] };
+    // The physical address `virt` was copied to.
+    let expected = |virt: u64| {
+        virt.wrapping_sub(kernel.image.start)
+            .wrapping_add(kernel.phys_start)
+    };
+    let mut problems = 0;
+    for segment in &kernel.segments {
+        let mut virt = segment.range.start & !(PAGE_SIZE - 1);
+        while virt < segment.range.end {
+            if !check_page(cr3, virt, expected(virt), read_only, no_execute) {
+                problems += 1;
+            }
+            virt += PAGE_SIZE;
+        }
+        let Some(first_byte) = segment.first_byte else {
+            continue;
+        };
+        let start = segment.range.start;
+        let phys = expected(start);
+        if unsafe { paging::translate(cr3, start) }.is_some_and(|page| page.phys == phys) {
+            let byte = unsafe { (phys as *const u8).read_volatile() };
+            if byte != first_byte {
+                warn!(
+                    "Verify: {:#x} holds {:#04x}, but was loaded with {:#04x}",
+                    start, byte, first_byte
+                );
+                problems += 1;
+            }
+        }
+    }
+
+    // Without NXE the CPU ignores the no-execute bit.
+    match unsafe { paging::translate(cr3, kernel.entry) } {
+        Some(page) if (page.executable || !nx) && page.phys == expected(kernel.entry) => {}
+        _ => return Err(LoaderError::EntryNotExecutable(kernel.entry)),
+    }
+    if problems == 0 {
+        info!("Verified the kernel's mappings");
+    } else {
+        warn!("Verify: {} problems in the kernel's mappings", problems);
+    }
+    Ok(())
+}
+
+/// Check the page at `virt` and log what is wrong with it. Returns whether
+/// it maps to `phys` with the expected access.
+fn check_page(
+    cr3: u64,
+    virt: u64,
+    phys: u64,
+    read_only: &[Range<u64>],
+    no_execute: &[Range<u64>],
+) -> bool {
+    let Some(page) = (unsafe { paging::translate(cr3, virt) }) else {
+        warn!("Verify: {:#x} is not mapped", virt);
+        return false;
+    };
+    if page.phys != phys {
+        warn!(
+            "Verify: {:#x} maps to {:#x} instead of {:#x}",
+            virt, page.phys, phys
+        );
+        return false;
+    }
+    let protected = read_only.iter().any(|range| range.contains(&virt));
+    if page.writable == protected {
+        warn!(
+            "Verify: {:#x} is {}",
+            virt,
+            if page.writable {
+                "writable, but should be read-only"
+            } else {
+                "read-only, but should be writable"
+            }
+        );
+        return false;
+    }
+    let data = no_execute.iter().any(|range| range.contains(&virt));
+    if page.executable == data {
+        warn!(
+            "Verify: {:#x} is {}",
+            virt,
+            if page.executable {
+                "executable, but should be non-executable"
+            } else {
+                "non-executable, but should be executable"
+            }
+        );
+        return false;
+    }
+    true
+}
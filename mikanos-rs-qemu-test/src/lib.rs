@@ -0,0 +1,137 @@
+//! Boots the loader and the kernel in QEMU and collects what they print on
+//! COM1.
+//!
+//! The kernel doubles as the stub: it prints a banner over serial as soon
+//! as it runs, which is all a boot test needs to see. Everything is built
+//! into its own target directory, so that the nested cargo runs don't wait
+//! on the lock of the `cargo test` that started them.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Printed by the loader right before it exits boot services.
+pub const LOADER_MARKER: &str = "Successfully loaded kernel!";
+
+/// Printed by the kernel once it has set up COM1.
+pub const KERNEL_MARKER: &str = "mikanos-rs-kernel: serial console on COM1";
+
+/// Printed by the kernel from the `BootInfo` it was handed, with the
+/// resolution [`build_esp`] configures.
+pub const RESOLUTION_MARKER: &str = "Framebuffer: 800x600";
+
+/// How long [`boot`] waits for the markers by default. Override with
+/// `MIKANOS_QEMU_TIMEOUT`, in seconds.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The workspace root, where `assets/` and the crates live.
+pub fn workspace_root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .unwrap()
+        .to_path_buf()
+}
+
+/// The target directory of the test builds.
+fn target_dir() -> PathBuf {
+    workspace_root().join("target").join("qemu-test")
+}
+
+/// Run `cargo build` with `args` in the crate directory `dir`.
+fn cargo_build(dir: &str, args: &[&str]) {
+    let status = Command::new("cargo")
+        .arg("build")
+        .args(args)
+        .arg("--target-dir")
+        .arg(target_dir())
+        .current_dir(workspace_root().join(dir))
+        .status()
+        .expect("failed to run cargo");
+    assert!(status.success(), "building {} failed", dir);
+}
+
+/// Build the loader and the kernel and lay them out in an EFI system
+/// partition directory like `run.sh` does, with a `loader.conf` that picks
+/// an 800x600 mode. Returns the directory.
+pub fn build_esp() -> PathBuf {
+    cargo_build("mikanos-rs-loader", &[]);
+    cargo_build(
+        "mikanos-rs-kernel",
+        &["--target", "./x86_64-mikanos_rs.json"],
+    );
+
+    let target = target_dir();
+    let esp = target.join("esp");
+    fs::create_dir_all(esp.join("efi").join("boot")).unwrap();
+    fs::copy(
+        target.join("x86_64-unknown-uefi/debug/mikanos-rs-loader.efi"),
+        esp.join("efi/boot/bootx64.efi"),
+    )
+    .unwrap();
+    fs::copy(
+        target.join("x86_64-mikanos_rs/debug/mikanos-rs-kernel"),
+        esp.join("kernel.elf"),
+    )
+    .unwrap();
+    // A mode OVMF always offers, so that the kernel's report is known.
+    fs::write(esp.join("loader.conf"), "resolution=800x600\n").unwrap();
+    esp
+}
+
+/// Kills QEMU when the test is done with it, including when it panics.
+struct Qemu(Child);
+
+impl Drop for Qemu {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Boot `esp` in QEMU with OVMF and return the serial log once every one
+/// of `markers` shows up in it, or once the timeout runs out or QEMU exits.
+///
+/// The caller checks the log; this only decides when to stop waiting.
+pub fn boot(esp: &Path, markers: &[&str]) -> String {
+    let assets = workspace_root().join("assets");
+    let log_path = esp.with_file_name("serial.log");
+    let _ = fs::remove_file(&log_path);
+    let pflash = |name: &str| {
+        format!(
+            "if=pflash,format=raw,readonly=on,file={}",
+            assets.join(name).display()
+        )
+    };
+    let mut qemu = Qemu(
+        Command::new("qemu-system-x86_64")
+            .args(["-display", "none", "-monitor", "none", "-no-reboot"])
+            .arg("-serial")
+            .arg(format!("file:{}", log_path.display()))
+            .arg("-drive")
+            .arg(pflash("OVMF_CODE.fd"))
+            .arg("-drive")
+            .arg(pflash("OVMF_VARS.fd"))
+            .arg("-drive")
+            .arg(format!("format=raw,file=fat:rw:{}", esp.display()))
+            .stdin(Stdio::null())
+            .spawn()
+            .expect("failed to start qemu-system-x86_64"),
+    );
+
+    let timeout = std::env::var("MIKANOS_QEMU_TIMEOUT")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map_or(DEFAULT_TIMEOUT, Duration::from_secs);
+    let deadline = Instant::now() + timeout;
+    loop {
+        let log = fs::read_to_string(&log_path).unwrap_or_default();
+        let done = markers.iter().all(|marker| log.contains(marker));
+        let exited = matches!(qemu.0.try_wait(), Ok(Some(_)));
+        if done || exited || Instant::now() >= deadline {
+            return log;
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,30 @@
+//! End-to-end boot tests. Only built with the `qemu` feature:
+//!
+//! ```shell
+//! $ cargo test -p mikanos-rs-qemu-test --features qemu
+//! ```
+
+#![cfg(feature = "qemu")]
+
+use mikanos_rs_qemu_test::{KERNEL_MARKER, LOADER_MARKER, RESOLUTION_MARKER, boot, build_esp};
+
+#[test]
+fn loader_starts_kernel() {
+    let esp = build_esp();
+    let log = boot(&esp, &[LOADER_MARKER, KERNEL_MARKER, RESOLUTION_MARKER]);
+    assert!(
+        log.contains(LOADER_MARKER),
+        "the loader didn't finish loading the kernel; serial log:\n{}",
+        log
+    );
+    assert!(
+        log.contains(KERNEL_MARKER),
+        "the kernel didn't start; serial log:\n{}",
+        log
+    );
+    assert!(
+        log.contains(RESOLUTION_MARKER),
+        "the kernel didn't get the framebuffer from BootInfo; serial log:\n{}",
+        log
+    );
+}
@@ -6,14 +6,18 @@ set -ex
 pushd mikanos-rs-loader && cargo build
 popd
 
-# Build kernel
-pushd mikanos-rs-kernel && cargo build
+# Build kernel. With HIGHER_HALF set, it is linked at 0xffffffff80000000.
+KERNEL_TARGET=x86_64-mikanos_rs
+if [ -n "$HIGHER_HALF" ]; then
+  KERNEL_TARGET=x86_64-mikanos_rs-higher-half
+fi
+pushd mikanos-rs-kernel && cargo build --target ./$KERNEL_TARGET.json
 popd
 
 # Make EFI system partition
 mkdir -p esp/efi/boot
 cp target/x86_64-unknown-uefi/debug/mikanos-rs-loader.efi esp/efi/boot/bootx64.efi
-cp target/x86_64-mikanos_rs/debug/mikanos-rs-kernel esp/kernel.elf
+cp target/$KERNEL_TARGET/debug/mikanos-rs-kernel esp/kernel.elf
 
 # Launch VM
 qemu-system-x86_64 \